@@ -2,8 +2,13 @@ extern crate criterion;
 
 use self::criterion::*;
 use ans_flex::compress;
+use ans_flex::compress::{fse_compress, fse_compress_4_states, fse_compress_4_streams};
 use ans_flex::decompress;
+use ans_flex::decompress::{fse_decompress, fse_decompress_4_states, fse_decompress_4_streams};
+use ans_flex::table::{build_compression_table, build_decompression_table};
 use ans_flex::FSE_DEFAULT_TABLELOG;
+use ans_flex::{compress_frame, decompress_frame, CompressionContext, DecompressionContext};
+use ans_flex::{compress_parallel, decompress_parallel};
 use common::count_simple;
 use common::fse_optimal_table_log;
 use common::get_max_symbol_value;
@@ -64,7 +69,7 @@ fn compression(c: &mut Criterion) {
         let input_bytes = input.len() as u64;
         group.throughput(Throughput::Bytes(input_bytes));
         group.bench_with_input(BenchmarkId::new("ans_flex", input_bytes), &input, |b, i| {
-            b.iter(|| compress(i));
+            b.iter(|| compress(i).unwrap());
         });
         group.bench_with_input(
             BenchmarkId::new("build_tree_fast huffl", input_bytes),
@@ -83,7 +88,7 @@ fn compression(c: &mut Criterion) {
 fn decompression(c: &mut Criterion) {
     let mut group = c.benchmark_group("decompression");
     for input in ALL.iter() {
-        let out = compress(input);
+        let out = compress(input).unwrap();
         let input_bytes = input.len() as u64;
         group.throughput(Throughput::Bytes(input_bytes));
         group.bench_with_input(
@@ -96,20 +101,146 @@ fn decompression(c: &mut Criterion) {
                     let table_log =
                         fse_optimal_table_log(FSE_DEFAULT_TABLELOG, input.len(), max_symbol_value);
                     let norm_counts =
-                        get_normalized_counts(&counts, table_log, input.len(), max_symbol_value);
-                    decompress(i, &norm_counts, table_log, input.len(), max_symbol_value)
+                        get_normalized_counts(&counts, table_log, input.len(), max_symbol_value)
+                            .unwrap();
+                    decompress(i, &norm_counts, table_log, input.len(), max_symbol_value).unwrap()
                 });
             },
         );
         let counts = count_simple(input);
         let max_symbol_value = get_max_symbol_value(&counts);
         let table_log = fse_optimal_table_log(FSE_DEFAULT_TABLELOG, input.len(), max_symbol_value);
-        let norm_counts = get_normalized_counts(&counts, table_log, input.len(), max_symbol_value);
+        let norm_counts =
+            get_normalized_counts(&counts, table_log, input.len(), max_symbol_value).unwrap();
         group.bench_with_input(
             BenchmarkId::new("ans_flex_reuse", input_bytes),
             &out.get_compressed_data(),
             |b, i| {
-                b.iter(|| decompress(i, &norm_counts, table_log, input.len(), max_symbol_value));
+                b.iter(|| {
+                    decompress(i, &norm_counts, table_log, input.len(), max_symbol_value).unwrap()
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Many small inputs, where the allocation of the tables dominates
+fn context(c: &mut Criterion) {
+    let mut group = c.benchmark_group("context");
+    let input_bytes = COMPRESSION1K.len() as u64;
+    group.throughput(Throughput::Bytes(input_bytes));
+    group.bench_function(BenchmarkId::new("compress_frame", input_bytes), |b| {
+        b.iter(|| compress_frame(COMPRESSION1K).unwrap());
+    });
+    let mut comp_ctx = CompressionContext::new();
+    group.bench_function(
+        BenchmarkId::new("compress_frame_context", input_bytes),
+        |b| {
+            b.iter(|| comp_ctx.compress_frame(COMPRESSION1K).unwrap().len());
+        },
+    );
+
+    let frame = compress_frame(COMPRESSION1K).unwrap();
+    group.bench_function(BenchmarkId::new("decompress_frame", input_bytes), |b| {
+        b.iter(|| decompress_frame(&frame).unwrap());
+    });
+    let mut decomp_ctx = DecompressionContext::new();
+    group.bench_function(
+        BenchmarkId::new("decompress_frame_context", input_bytes),
+        |b| {
+            b.iter(|| decomp_ctx.decompress_frame(&frame).unwrap().len());
+        },
+    );
+    group.finish();
+}
+
+/// The bitstream with 2 interleaved states compared to 4 interleaved states and 4 streams
+fn interleave(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interleave");
+    for input in ALL.iter() {
+        let input_bytes = input.len() as u64;
+        group.throughput(Throughput::Bytes(input_bytes));
+        let counts = count_simple(input);
+        let max_symbol_value = get_max_symbol_value(&counts);
+        let table_log = fse_optimal_table_log(FSE_DEFAULT_TABLELOG, input.len(), max_symbol_value);
+        let norm_counts =
+            get_normalized_counts(&counts, table_log, input.len(), max_symbol_value).unwrap();
+        let comp_table = build_compression_table(&norm_counts, table_log, max_symbol_value);
+        let decomp_table = build_decompression_table(&norm_counts, table_log, max_symbol_value);
+
+        group.bench_with_input(
+            BenchmarkId::new("compress_2_states", input_bytes),
+            &input,
+            |b, i| {
+                b.iter(|| fse_compress(i, &comp_table, table_log));
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("compress_4_states", input_bytes),
+            &input,
+            |b, i| {
+                b.iter(|| fse_compress_4_states(i, &comp_table, table_log));
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("compress_4_streams", input_bytes),
+            &input,
+            |b, i| {
+                b.iter(|| fse_compress_4_streams(i, &comp_table, table_log));
+            },
+        );
+
+        let two_states = fse_compress(input, &comp_table, table_log);
+        let mut output = vec![0; input.len()];
+        group.bench_with_input(
+            BenchmarkId::new("decompress_2_states", input_bytes),
+            &two_states.get_compressed_data(),
+            |b, i| {
+                b.iter(|| fse_decompress(&mut output, i, &decomp_table, table_log).unwrap());
+            },
+        );
+        let four_states = fse_compress_4_states(input, &comp_table, table_log);
+        group.bench_with_input(
+            BenchmarkId::new("decompress_4_states", input_bytes),
+            &four_states.get_compressed_data(),
+            |b, i| {
+                b.iter(|| {
+                    fse_decompress_4_states(&mut output, i, &decomp_table, table_log).unwrap()
+                });
+            },
+        );
+        let four_streams = fse_compress_4_streams(input, &comp_table, table_log);
+        group.bench_with_input(
+            BenchmarkId::new("decompress_4_streams", input_bytes),
+            &four_streams,
+            |b, i| {
+                b.iter(|| {
+                    fse_decompress_4_streams(&mut output, i, &decomp_table, table_log).unwrap()
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Block-parallel compression of the largest input, with a block size of 16KB
+fn parallel(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parallel");
+    let input_bytes = COMPRESSION93K.len() as u64;
+    group.throughput(Throughput::Bytes(input_bytes));
+    for threads in [1, 4] {
+        group.bench_function(
+            BenchmarkId::new(format!("compress_{}_threads", threads), input_bytes),
+            |b| {
+                b.iter(|| compress_parallel(COMPRESSION93K, 16 * 1024, threads));
+            },
+        );
+        let compressed = compress_parallel(COMPRESSION93K, 16 * 1024, threads);
+        group.bench_function(
+            BenchmarkId::new(format!("decompress_{}_threads", threads), input_bytes),
+            |b| {
+                b.iter(|| decompress_parallel(&compressed, threads).unwrap());
             },
         );
     }
@@ -117,5 +248,12 @@ fn decompression(c: &mut Criterion) {
 }
 
 // criterion_group!(benches, count, compression);
-criterion_group!(benches, compression, decompression);
+criterion_group!(
+    benches,
+    compression,
+    decompression,
+    context,
+    interleave,
+    parallel
+);
 criterion_main!(benches);
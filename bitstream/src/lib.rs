@@ -10,12 +10,21 @@ number of bits  >> 3 == number of bytes
 
 */
 
+use std::convert::TryInto;
+use thiserror::Error;
+
 pub type BitContainer = usize;
 pub const BIT_CONTAINER_BYTES: usize = core::mem::size_of::<BitContainer>();
 pub const NUM_BITS_IN_BIT_CONTAINER: u32 = BIT_CONTAINER_BYTES as u32 * 8;
 
 const REG_MASK: u32 = NUM_BITS_IN_BIT_CONTAINER - 1;
 
+/// Bit container size of 32 bit targets.
+///
+/// `BitDStreamReverse<BIT_CONTAINER_BYTES_32>` reads with the same cadence as on a 32 bit target, which
+/// allows to test the 32 bit code paths on a 64 bit host.
+pub const BIT_CONTAINER_BYTES_32: usize = 4;
+
 /// returns the position of the highest bit
 ///
 /// see test_highbit_pos
@@ -79,15 +88,26 @@ pub enum BitDstreamStatus {
     Overflow,
 }
 
+#[derive(Error, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BitstreamError {
+    #[error("input is empty")]
+    EmptyInput,
+    #[error("corruption detected - end mark not present")]
+    EndMarkMissing,
+}
+
 /// Reads bitstream in reverse order
+///
+/// `CONTAINER_BYTES` is the number of bytes loaded into the bit container on a reload. It defaults
+/// to the size of usize and can't be larger.
 #[derive(Debug)]
-pub struct BitDStreamReverse {
-    /// BitContainer is usize
+pub struct BitDStreamReverse<const CONTAINER_BYTES: usize = BIT_CONTAINER_BYTES> {
+    /// BitContainer is usize, only the lower CONTAINER_BYTES are used
     pub(crate) bit_container: BitContainer,
 
     /// Current number of bits consumed
     ///
-    /// Should be smaller than NUM_BITS_IN_BIT_CONTAINER
+    /// Should be smaller than CONTAINER_BYTES * 8
     pub(crate) bits_consumed: u32,
 
     /// slow read threshold for the last bytes
@@ -99,81 +119,84 @@ pub struct BitDStreamReverse {
 }
 
 impl BitDStreamReverse {
-    pub fn new(input: &[u8]) -> Self {
-        let limit_pos = BIT_CONTAINER_BYTES;
-        let (input_pos, bit_container) = if input.len() >= BIT_CONTAINER_BYTES {
-            let input_pos = input.len() - BIT_CONTAINER_BYTES;
-            let bit_container = read_usize(input, input_pos);
-            (input_pos, bit_container)
-        } else {
-            let input_pos = 0;
+    pub fn new(input: &[u8]) -> Result<Self, BitstreamError> {
+        Self::with_container_bytes(input)
+    }
+}
 
-            let mut bytes = [0, 0, 0, 0, 0, 0, 0, 0];
-            bytes[..input.len()].copy_from_slice(input);
-            let bit_container = usize::from_le_bytes(bytes);
-            (input_pos, bit_container)
-        };
+impl<const CONTAINER_BYTES: usize> BitDStreamReverse<CONTAINER_BYTES> {
+    const NUM_BITS: u32 = CONTAINER_BYTES as u32 * 8;
 
-        let last_byte = input[input.len() - 1];
+    /// Like `new`, but with a bit container of `CONTAINER_BYTES`, e.g. `BIT_CONTAINER_BYTES_32`.
+    pub fn with_container_bytes(input: &[u8]) -> Result<Self, BitstreamError> {
+        assert!(CONTAINER_BYTES <= BIT_CONTAINER_BYTES && CONTAINER_BYTES >= 4);
+        let last_byte = *input.last().ok_or(BitstreamError::EmptyInput)?;
         if last_byte == 0 {
-            panic!("CORRUPTION DETECTED - END MARK NOT PRESENT"); // TODO proper error maybe
+            return Err(BitstreamError::EndMarkMissing);
         }
 
-        let mut bits_consumed = if last_byte == 0 {
-            0
+        let limit_pos = CONTAINER_BYTES;
+        let (input_pos, bit_container) = if input.len() >= CONTAINER_BYTES {
+            let input_pos = input.len() - CONTAINER_BYTES;
+            let bit_container = read_container::<CONTAINER_BYTES>(input, input_pos);
+            (input_pos, bit_container)
         } else {
-            8 - highbit_pos(last_byte as u32)
+            let input_pos = 0;
+            let bit_container = read_container::<CONTAINER_BYTES>(input, input_pos);
+            (input_pos, bit_container)
         };
 
-        if input.len() < BIT_CONTAINER_BYTES {
-            bits_consumed += (BIT_CONTAINER_BYTES as u32 - input.len() as u32) * 8;
+        let mut bits_consumed = 8 - highbit_pos(last_byte as u32);
+
+        if input.len() < CONTAINER_BYTES {
+            bits_consumed += (CONTAINER_BYTES as u32 - input.len() as u32) * 8;
         }
 
-        BitDStreamReverse {
+        Ok(BitDStreamReverse {
             bit_container,
             bits_consumed,
             limit_pos,
             input_pos,
-        }
+        })
     }
 
     #[inline]
     pub fn reload_stream_fast(&mut self, input: &[u8]) -> BitDstreamStatus {
         // if (UNLIKELY(bitD->ptr < bitD->limitPtr))
         // return BIT_DStream_overflow;
-        debug_assert!(self.bits_consumed <= NUM_BITS_IN_BIT_CONTAINER);
+        debug_assert!(self.bits_consumed <= Self::NUM_BITS);
         // move the pos by the bytes consumed
         self.input_pos -= self.bits_consumed as usize >> 3;
         // clear bits_consumed, but keep residue of last byte
         self.bits_consumed &= 7;
-        self.bit_container = read_usize(input, self.input_pos);
+        self.bit_container = read_container::<CONTAINER_BYTES>(input, self.input_pos);
         BitDstreamStatus::Unfinished
     }
     #[inline]
     pub fn reload_stream(&mut self, input: &[u8]) -> BitDstreamStatus {
-        if self.bits_consumed > NUM_BITS_IN_BIT_CONTAINER {
+        if self.bits_consumed > Self::NUM_BITS {
             return BitDstreamStatus::Overflow;
         }
         if self.input_pos >= self.limit_pos {
             return self.reload_stream_fast(input);
         }
         if self.input_pos == 0 {
-            if self.bits_consumed < NUM_BITS_IN_BIT_CONTAINER {
+            if self.bits_consumed < Self::NUM_BITS {
                 return BitDstreamStatus::EndOfBuffer;
             }
             return BitDstreamStatus::Completed;
         }
-        // last 7 bytes
+        // last CONTAINER_BYTES - 1 bytes
         let nb_bytes = self.bits_consumed >> 3;
         if nb_bytes > self.input_pos as u32 {
             self.bits_consumed -= self.input_pos as u32 * 8;
             self.input_pos = 0;
-            self.bit_container = read_usize(input, self.input_pos);
+            self.bit_container = read_container::<CONTAINER_BYTES>(input, self.input_pos);
             BitDstreamStatus::EndOfBuffer
         } else {
             self.input_pos -= nb_bytes as usize;
             self.bits_consumed -= nb_bytes * 8;
-            self.bit_container = read_usize(input, self.input_pos);
+            self.bit_container = read_container::<CONTAINER_BYTES>(input, self.input_pos);
             BitDstreamStatus::Unfinished
         }
     }
@@ -182,17 +205,20 @@ impl BitDStreamReverse {
     /// On 64-bits, maxNbBits==56.
     #[inline]
     fn look_bits(&self, nb_bits: u32) -> usize {
-        debug_assert!(nb_bits >= 1);
         // debug_assert!(nb_bits <= 56);
         // dbg!(self.bits_consumed);
         // dbg!(nb_bits);
-        // let start = NUM_BITS_IN_BIT_CONTAINER.wrapping_sub(self.bits_consumed).wrapping_sub(nb_bits);
-        let start = NUM_BITS_IN_BIT_CONTAINER - self.bits_consumed - nb_bits;
+        // wrapping, since the stream may be read past its end, which is detected as overflow on reload
+        let start = Self::NUM_BITS
+            .wrapping_sub(self.bits_consumed)
+            .wrapping_sub(nb_bits);
         get_middle_bits(self.bit_container, start, nb_bits)
     }
 
     /// On 32-bits, maxNbBits==24.
     /// On 64-bits, maxNbBits==56.
+    ///
+    /// In contrast to `read_bits_fast`, nb_bits may be 0.
     #[inline]
     pub fn read_bits(&mut self, nb_bits: u32) -> usize {
         // debug_assert!(nb_bits <= 56);
@@ -206,13 +232,40 @@ impl BitDStreamReverse {
     #[inline]
     pub fn read_bits_fast(&mut self, nb_bits: u32) -> usize {
         // dbg!(nb_bits);
+        let value = self.look_bits_fast(nb_bits);
+        self.bits_consumed += nb_bits;
+        value
+    }
+
+    /// Returns the next nb_bits without consuming them, only works when nb_bits > 1.
+    #[inline]
+    pub fn look_bits_fast(&self, nb_bits: u32) -> usize {
         debug_assert!(nb_bits >= 1);
 
-        // shifting left to shift out already consumed bits
-        let value = (self.bit_container << (self.bits_consumed & REG_MASK))
-            >> ((NUM_BITS_IN_BIT_CONTAINER - nb_bits) & REG_MASK);
+        // shifting left to shift out already consumed bits, and the unused upper bits of the
+        // container if CONTAINER_BYTES is smaller than usize
+        let unused_bits = NUM_BITS_IN_BIT_CONTAINER - Self::NUM_BITS;
+        (self.bit_container << ((self.bits_consumed + unused_bits) & REG_MASK))
+            >> ((NUM_BITS_IN_BIT_CONTAINER - nb_bits) & REG_MASK)
+    }
+
+    /// Consumes nb_bits, e.g. after `look_bits_fast`
+    #[inline]
+    pub fn skip_bits(&mut self, nb_bits: u32) {
         self.bits_consumed += nb_bits;
-        value
+    }
+
+    /// Number of bits consumed of the bit container
+    #[inline]
+    pub fn bits_consumed(&self) -> u32 {
+        self.bits_consumed
+    }
+
+    /// Returns true if all bits of the stream have been consumed, reading stopped exactly at
+    /// the end mark
+    #[inline]
+    pub fn is_end_of_stream(&self) -> bool {
+        self.input_pos == 0 && self.bits_consumed == Self::NUM_BITS
     }
 }
 
@@ -311,23 +364,28 @@ impl BitCstream {
         self.bit_pos += nb_bits;
     }
 
-    // /// assumption : bit_container has not overflowed
-    // /// unsafe version; does not check buffer overflow */
-    // #[inline]
-    // pub fn flush_bits(&mut self) {
-    //     let nb_bytes = self.bit_pos >> 3;
+    /// assumption : bit_container has not overflowed
+    ///
+    /// Like `flush_bits_fast`, but never writes past the end of data. Bytes which don't fit are
+    /// dropped, in that case `get_compressed_size` is larger than data.
+    #[inline]
+    pub fn flush_bits(&mut self, data: &mut [u8]) {
+        let nb_bytes = self.bit_pos >> 3;
 
-    //     debug_assert!(self.bit_pos < NUM_BITS_IN_BIT_CONTAINER);
-    //     debug_assert!(self.data.len() > self.data_pos);
+        debug_assert!(self.bit_pos < NUM_BITS_IN_BIT_CONTAINER);
 
-    //     // TODO check overflow for last bytes
-    //     push_usize(&mut self.data, self.data_pos, self.bit_container);
+        if self.data_pos + BIT_CONTAINER_BYTES <= data.len() {
+            push_usize(data, self.data_pos, self.bit_container);
+        } else if self.data_pos < data.len() {
+            // close to the end, write only the bytes which fit
+            let num_bytes = data.len() - self.data_pos;
+            data[self.data_pos..].copy_from_slice(&self.bit_container.to_le_bytes()[..num_bytes]);
+        }
 
-    //     self.data_pos += nb_bytes as usize;
-    //     self.data_pos = self.data_pos.min(self.data.len());
-    //     self.bit_pos &= 7;
-    //     self.bit_container >>= nb_bytes * 8;
-    // }
+        self.data_pos += nb_bytes as usize;
+        self.bit_pos &= 7;
+        self.bit_container >>= nb_bytes * 8;
+    }
 
     /// assumption : bit_container has not overflowed
     /// unsafe version; does not check buffer overflow */
@@ -361,6 +419,16 @@ impl BitCstream {
         //dbg!(padding);
         self.flush_bits_fast(data);
     }
+
+    /// Like `finish_stream`, but never writes past the end of data, see `flush_bits`.
+    ///
+    /// Returns false, if the stream did not fit into data.
+    #[inline]
+    pub fn finish_stream_checked(&mut self, data: &mut [u8]) -> bool {
+        self.add_bits_fast(1, 1);
+        self.flush_bits(data);
+        self.get_compressed_size() <= data.len()
+    }
 }
 
 #[inline]
@@ -375,17 +443,22 @@ fn push_usize(output: &mut [u8], pos: usize, el: usize) {
     }
 }
 
+/// reads CONTAINER_BYTES at pos into a usize, missing bytes past the end of the input are read as 0
 #[inline]
-fn read_usize(input: &[u8], pos: usize) -> usize {
-    let mut num: usize = 0;
-    unsafe {
-        core::ptr::copy_nonoverlapping(
-            input.as_ptr().add(pos),
-            &mut num as *mut usize as *mut u8,
-            core::mem::size_of::<usize>(),
-        );
+fn read_container<const CONTAINER_BYTES: usize>(input: &[u8], pos: usize) -> usize {
+    if CONTAINER_BYTES == BIT_CONTAINER_BYTES {
+        if let Some(bytes) = input.get(pos..pos + BIT_CONTAINER_BYTES) {
+            return usize::from_le_bytes(bytes.try_into().unwrap());
+        }
+    }
+    let mut bytes = [0_u8; BIT_CONTAINER_BYTES];
+    if let Some(container) = input.get(pos..pos + CONTAINER_BYTES) {
+        bytes[..CONTAINER_BYTES].copy_from_slice(container);
+    } else {
+        let rest = input.get(pos..).unwrap_or_default();
+        bytes[..rest.len()].copy_from_slice(rest);
     }
-    num
+    usize::from_le_bytes(bytes)
 }
 
 #[cfg(test)]
@@ -402,7 +475,7 @@ mod tests {
 
         let out = c_stream.get_compressed_data(&data);
 
-        let mut d_stream = BitDStreamReverse::new(out);
+        let mut d_stream = BitDStreamReverse::new(out).unwrap();
         let val = d_stream.read_bits_fast(3);
         assert_eq!(val, 0b101);
     }
@@ -417,7 +490,7 @@ mod tests {
 
         let out = c_stream.get_compressed_data(&data);
 
-        let mut d_stream = BitDStreamReverse::new(out);
+        let mut d_stream = BitDStreamReverse::new(out).unwrap();
         let val1 = d_stream.read_bits_fast(30);
         let val2 = d_stream.read_bits_fast(30);
         assert_eq!(val1, 0b101);
@@ -439,7 +512,7 @@ mod tests {
 
         let out = c_stream.get_compressed_data(&data);
 
-        let mut d_stream = BitDStreamReverse::new(out);
+        let mut d_stream = BitDStreamReverse::new(out).unwrap();
         assert_eq!(d_stream.read_bits_fast(5), 0b1111);
         assert_eq!(d_stream.read_bits_fast(5), 0b0111);
         d_stream.reload_stream(out);
@@ -449,6 +522,33 @@ mod tests {
         assert_eq!(d_stream.read_bits_fast(30), 0b101);
         assert_eq!(d_stream.read_bits_fast(30), 0b111);
     }
+    #[test]
+    fn test_stream_invalid() {
+        assert_eq!(
+            BitDStreamReverse::new(&[]).unwrap_err(),
+            BitstreamError::EmptyInput
+        );
+        assert_eq!(
+            BitDStreamReverse::new(&[1, 0]).unwrap_err(),
+            BitstreamError::EndMarkMissing
+        );
+    }
+
+    #[test]
+    fn test_read_usize_out_of_bounds() {
+        let input = [1, 2, 3];
+        assert_eq!(read_container::<BIT_CONTAINER_BYTES>(&input, 0), 0x030201);
+        assert_eq!(read_container::<BIT_CONTAINER_BYTES>(&input, 2), 0x03);
+        assert_eq!(read_container::<BIT_CONTAINER_BYTES>(&input, 5), 0);
+
+        let input = [1, 2, 3, 4, 5];
+        assert_eq!(
+            read_container::<BIT_CONTAINER_BYTES_32>(&input, 0),
+            0x04030201
+        );
+        assert_eq!(read_container::<BIT_CONTAINER_BYTES_32>(&input, 3), 0x0504);
+    }
+
     #[test]
     fn test_stream_many_reload() {
         let mut data: Vec<u8> = vec![0; 50];
@@ -465,7 +565,7 @@ mod tests {
 
         let out = c_stream.get_compressed_data(&data);
 
-        let mut d_stream = BitDStreamReverse::new(out);
+        let mut d_stream = BitDStreamReverse::new(out).unwrap();
         assert_eq!(d_stream.read_bits_fast(5), 0b1111);
         d_stream.reload_stream(out);
         assert_eq!(d_stream.read_bits_fast(5), 0b0111);
@@ -478,4 +578,67 @@ mod tests {
         d_stream.reload_stream(out);
         assert_eq!(d_stream.read_bits_fast(30), 0b111);
     }
+
+    #[test]
+    fn test_stream_many_reload_32() {
+        let mut data: Vec<u8> = vec![0; 100];
+        let mut c_stream = BitCstream::new();
+        for val in 0..20 {
+            c_stream.add_bits_fast(val, 5);
+            c_stream.add_bits_fast(val << 10 | 1, 20);
+            c_stream.flush_bits_fast(&mut data);
+        }
+        c_stream.finish_stream(&mut data);
+
+        let out = c_stream.get_compressed_data(&data);
+
+        let mut d_stream =
+            BitDStreamReverse::<BIT_CONTAINER_BYTES_32>::with_container_bytes(out).unwrap();
+        for val in (0..20).rev() {
+            assert_eq!(d_stream.read_bits_fast(20), val << 10 | 1);
+            d_stream.reload_stream(out);
+            assert_eq!(d_stream.read_bits(5), val);
+            d_stream.reload_stream(out);
+        }
+        assert_eq!(d_stream.reload_stream(out), BitDstreamStatus::Completed);
+        assert!(d_stream.is_end_of_stream());
+    }
+
+    #[test]
+    fn test_stream_checked() {
+        let write = |data: &mut [u8]| {
+            let mut c_stream = BitCstream::new();
+            for val in 0..20 {
+                c_stream.add_bits_fast(val, 5);
+                c_stream.add_bits_fast(val << 10 | 1, 20);
+                c_stream.flush_bits(data);
+            }
+            let fits = c_stream.finish_stream_checked(data);
+            (fits, c_stream.get_compressed_size())
+        };
+
+        let mut data: Vec<u8> = vec![0; 100];
+        let (fits, size) = write(&mut data);
+        assert!(fits);
+        assert_eq!(size, 63);
+        let mut expected = vec![0; 100];
+        let mut c_stream = BitCstream::new();
+        for val in 0..20 {
+            c_stream.add_bits_fast(val, 5);
+            c_stream.add_bits_fast(val << 10 | 1, 20);
+            c_stream.flush_bits_fast(&mut expected);
+        }
+        c_stream.finish_stream(&mut expected);
+        assert_eq!(data[..size], expected[..size]);
+
+        // exact size
+        let mut data: Vec<u8> = vec![0; size];
+        assert_eq!(write(&mut data), (true, size));
+        assert_eq!(data[..], expected[..size]);
+
+        for len in 0..size {
+            let mut data: Vec<u8> = vec![0; len];
+            assert!(!write(&mut data).0);
+        }
+    }
 }
@@ -4,6 +4,7 @@ use self::criterion::*;
 use common::count_blocked_unsafe;
 use common::count_multi;
 use common::count_multi2;
+use common::count_simd;
 use common::count_simple;
 
 const COMPRESSION1K: &[u8] = include_bytes!("../../test_data/compression_1k.txt");
@@ -12,7 +13,7 @@ const COMPRESSION65K: &[u8] = include_bytes!("../../test_data/compression_65k.tx
 const COMPRESSION66K: &[u8] = include_bytes!("../../test_data/compression_66k_JSON.txt");
 const COMPRESSION19K: &[u8] = include_bytes!("../../test_data/v4_uuids_19k.txt");
 const COMPRESSION93K: &[u8] = include_bytes!("../../test_data/v4_uuids_93k.txt");
-// const COMPRESSION95K_VERY_GOOD_LOGO: &'static [u8] = include_bytes!("logo.jpg");
+const LOGO: &[u8] = include_bytes!("../../test_data/logo.jpg");
 
 const ALL: &[&[u8]] = &[
     COMPRESSION1K as &[u8],
@@ -21,7 +22,7 @@ const ALL: &[&[u8]] = &[
     COMPRESSION66K as &[u8],
     COMPRESSION19K as &[u8],
     COMPRESSION93K as &[u8],
-    // COMPRESSION95K_VERY_GOOD_LOGO as &[u8],
+    LOGO,
 ];
 
 fn count(c: &mut Criterion) {
@@ -36,6 +37,18 @@ fn count(c: &mut Criterion) {
                 b.iter(|| count_simple(i));
             },
         );
+        group.bench_with_input(BenchmarkId::new("count", input_bytes), &input, |b, i| {
+            b.iter(|| common::count(i));
+        });
+        if count_simd(input).is_some() {
+            group.bench_with_input(
+                BenchmarkId::new("count_simd", input_bytes),
+                &input,
+                |b, i| {
+                    b.iter(|| count_simd(i));
+                },
+            );
+        }
         group.bench_with_input(
             BenchmarkId::new("count_multi", input_bytes),
             &input,
@@ -61,5 +74,26 @@ fn count(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, count);
+/// `count_multi` compared to `count_simd` on runs of a single symbol, where `count_simd` counts a
+/// whole vector at once
+fn count_runs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("count_runs");
+    let mut input = vec![];
+    for (i, symbol) in COMPRESSION66K.iter().enumerate().take(1000) {
+        input.resize(input.len() + 1 + i % 128, *symbol);
+    }
+    let input_bytes = input.len() as u64;
+    group.throughput(Throughput::Bytes(input_bytes));
+    group.bench_function(BenchmarkId::new("count_multi", input_bytes), |b| {
+        b.iter(|| count_multi(&input));
+    });
+    if count_simd(&input).is_some() {
+        group.bench_function(BenchmarkId::new("count_simd", input_bytes), |b| {
+            b.iter(|| count_simd(&input));
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, count, count_runs);
 criterion_main!(benches);
@@ -0,0 +1,237 @@
+use crate::{count_multi, count_simple, CountsTable};
+use std::convert::TryInto;
+
+/// Inputs smaller than this are counted with `count_simple`, merging the four tables of the other
+/// variants costs more than it saves.
+const COUNT_SIMPLE_MAX_LEN: usize = 1024;
+
+/// creates a table with the counts of each symbol, with the fastest variant for the input size
+///
+/// Larger inputs are counted with `count_multi`. There is no runtime dispatch to `count_simd`:
+/// AVX2, SSE4.2 and NEON have no scatter or conflict detection, so the increments of a byte
+/// histogram can't be vectorized with them and the SIMD variants only add a run check on top of
+/// the scalar increments. On the count benchmark `count_multi` is faster on all test data, e.g.
+/// 1.9 GiB/s vs 1.4 GiB/s on logo.jpg, `count_simd` only wins on long runs, see count_runs.
+#[inline]
+pub fn count(input: &[u8]) -> CountsTable {
+    if input.len() < COUNT_SIMPLE_MAX_LEN {
+        return count_simple(input);
+    }
+    count_multi(input)
+}
+
+/// creates a table with the counts of each symbol with the SIMD variant of the CPU, selected at
+/// runtime, AVX2 or SSE4.2 on x86_64 and NEON on aarch64
+///
+/// Returns `None` if the CPU supports none of them. The SIMD variants count runs of a single
+/// symbol in a vector at once and scatter the other bytes into four tables like `count_multi`,
+/// they are not a vectorized histogram. Only worth it for inputs, which are mostly long runs.
+pub fn count_simd(input: &[u8]) -> Option<CountsTable> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx2") {
+            // Safety: the cpu supports avx2
+            return Some(unsafe { x86::count_avx2(input) });
+        }
+        if is_x86_feature_detected!("sse4.2") {
+            // Safety: the cpu supports sse4.2, which implies sse4.1
+            return Some(unsafe { x86::count_sse42(input) });
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            // Safety: the cpu supports neon
+            return Some(unsafe { neon::count_neon(input) });
+        }
+    }
+    // unused on other architectures
+    let _ = input;
+    None
+}
+
+/// Four tables, which are merged at the end, so that consecutive equal bytes don't wait on the
+/// increment of the same counter.
+struct SubHistograms {
+    counts: [[u32; 256]; 4],
+}
+
+impl SubHistograms {
+    fn new() -> Self {
+        SubHistograms {
+            counts: [[0; 256]; 4],
+        }
+    }
+
+    #[inline]
+    fn add_run(&mut self, symbol: u8, len: u32) {
+        self.counts[0][symbol as usize] += len;
+    }
+
+    #[inline]
+    fn add_word(&mut self, word: u64) {
+        self.counts[0][(word & 0xFF) as usize] += 1;
+        self.counts[1][(word >> 8 & 0xFF) as usize] += 1;
+        self.counts[2][(word >> 16 & 0xFF) as usize] += 1;
+        self.counts[3][(word >> 24 & 0xFF) as usize] += 1;
+        self.counts[0][(word >> 32 & 0xFF) as usize] += 1;
+        self.counts[1][(word >> 40 & 0xFF) as usize] += 1;
+        self.counts[2][(word >> 48 & 0xFF) as usize] += 1;
+        self.counts[3][(word >> 56) as usize] += 1;
+    }
+
+    #[inline]
+    fn add_bytes(&mut self, bytes: &[u8]) {
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.add_word(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+        for byte in words.remainder() {
+            self.counts[0][*byte as usize] += 1;
+        }
+    }
+
+    fn merge(&self) -> CountsTable {
+        let mut counts = self.counts[0];
+        for other in &self.counts[1..] {
+            for (count, other) in counts.iter_mut().zip(other.iter()) {
+                *count += *other;
+            }
+        }
+        counts
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use super::SubHistograms;
+    use crate::CountsTable;
+    use std::arch::x86_64::*;
+
+    /// Safety: the cpu needs to support avx2
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn count_avx2(input: &[u8]) -> CountsTable {
+        let mut hist = SubHistograms::new();
+        let mut chunks = input.chunks_exact(32);
+        for chunk in &mut chunks {
+            let vector = _mm256_loadu_si256(chunk.as_ptr() as *const __m256i);
+            let equal = _mm256_cmpeq_epi8(vector, _mm256_set1_epi8(chunk[0] as i8));
+            if _mm256_movemask_epi8(equal) == -1 {
+                hist.add_run(chunk[0], 32);
+            } else {
+                hist.add_bytes(chunk);
+            }
+        }
+        hist.add_bytes(chunks.remainder());
+        hist.merge()
+    }
+
+    /// Safety: the cpu needs to support sse4.2
+    #[target_feature(enable = "sse4.2")]
+    pub(super) unsafe fn count_sse42(input: &[u8]) -> CountsTable {
+        let mut hist = SubHistograms::new();
+        let mut chunks = input.chunks_exact(16);
+        for chunk in &mut chunks {
+            let vector = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            let equal = _mm_cmpeq_epi8(vector, _mm_set1_epi8(chunk[0] as i8));
+            if _mm_test_all_ones(equal) == 1 {
+                hist.add_run(chunk[0], 16);
+            } else {
+                hist.add_bytes(chunk);
+            }
+        }
+        hist.add_bytes(chunks.remainder());
+        hist.merge()
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use super::SubHistograms;
+    use crate::CountsTable;
+    use std::arch::aarch64::*;
+
+    /// Safety: the cpu needs to support neon
+    #[target_feature(enable = "neon")]
+    pub(super) unsafe fn count_neon(input: &[u8]) -> CountsTable {
+        let mut hist = SubHistograms::new();
+        let mut chunks = input.chunks_exact(16);
+        for chunk in &mut chunks {
+            let vector = vld1q_u8(chunk.as_ptr());
+            let equal = vceqq_u8(vector, vdupq_n_u8(chunk[0]));
+            if vminvq_u8(equal) == u8::MAX {
+                hist.add_run(chunk[0], 16);
+            } else {
+                hist.add_bytes(chunk);
+            }
+        }
+        hist.add_bytes(chunks.remainder());
+        hist.merge()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Inputs with runs of a single symbol, which are counted at once, and random bytes
+    fn test_inputs() -> Vec<Vec<u8>> {
+        let mut seed = 7_u32;
+        let mut random = |modulo: u32| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            ((seed >> 16) % modulo) as u8
+        };
+        let mut inputs = vec![
+            include_bytes!("../../test_data/compression_66k_JSON.txt").to_vec(),
+            include_bytes!("../../test_data/compression_1k.txt").to_vec(),
+            include_bytes!("../../test_data/logo.jpg").to_vec(),
+            vec![0; 5000],
+            vec![u8::MAX; 5000],
+        ];
+        for len in [0, 1, 15, 16, 17, 31, 32, 33, 1023, 1024, 1025, 4099] {
+            inputs.push((0..len).map(|_| random(256)).collect());
+            inputs.push((0..len).map(|_| random(3)).collect());
+            // runs of random length
+            let mut runs = vec![];
+            while runs.len() < len {
+                let symbol = random(256);
+                let run = random(100) as usize;
+                runs.resize(runs.len() + run, symbol);
+            }
+            runs.truncate(len);
+            inputs.push(runs);
+        }
+        inputs
+    }
+
+    #[test]
+    fn test_count_matches_count_simple() {
+        for input in test_inputs() {
+            // unaligned starts
+            for start in 0..4.min(input.len() + 1) {
+                let input = &input[start..];
+                let expected = count_simple(input);
+                assert_eq!(count(input), expected);
+                if let Some(counts) = count_simd(input) {
+                    assert_eq!(counts, expected);
+                }
+
+                #[cfg(target_arch = "x86_64")]
+                {
+                    if is_x86_feature_detected!("avx2") {
+                        assert_eq!(unsafe { x86::count_avx2(input) }, expected);
+                    }
+                    if is_x86_feature_detected!("sse4.2") {
+                        assert_eq!(unsafe { x86::count_sse42(input) }, expected);
+                    }
+                }
+                #[cfg(target_arch = "aarch64")]
+                {
+                    if std::arch::is_aarch64_feature_detected!("neon") {
+                        assert_eq!(unsafe { neon::count_neon(input) }, expected);
+                    }
+                }
+            }
+        }
+    }
+}
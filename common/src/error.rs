@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, PartialEq, Eq)]
 pub enum HistError {
     #[error("output is too small")]
     OutputTooSmall,
@@ -12,8 +12,12 @@ pub enum HistError {
     TableLogTooSmall,
     #[error("max symbol value")]
     MaxSymbolValueTooSmall,
+    #[error("max symbol value too large")]
+    MaxSymbolValueTooLarge,
     #[error("corruption detected: `{0}`")]
     CorruptionDetected(String),
     #[error("Incorrect normalized distribution")]
     IncorrectNormalizedDistribution,
+    #[error("normalization rounded a symbol with count `{0}` to zero")]
+    SymbolRoundedToZero(u32),
 }
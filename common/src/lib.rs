@@ -1,11 +1,15 @@
+mod count;
 mod error;
+mod symbol;
 mod table;
 use std::convert::TryInto;
 
-use error::HistError;
+pub use count::{count, count_simd};
+pub use error::HistError;
 use log::log_enabled;
 use log::Level::Trace;
 use log::*;
+pub use symbol::{Symbol, MAX_SYMBOL_VALUE_U16};
 pub use table::fse_optimal_table_log;
 
 // use crate::table::fse_min_table_log;
@@ -15,8 +19,8 @@ pub const MAX_SYMBOL_VALUE: u32 = u8::MAX as u32;
 pub type CountsTable = [u32; MAX_SYMBOL_VALUE as usize + 1];
 pub type NormCountsTable = [i16; MAX_SYMBOL_VALUE as usize + 1];
 
-pub fn get_max_symbol_value(counts: &CountsTable) -> u32 {
-    let mut max_symbol_value = MAX_SYMBOL_VALUE;
+pub fn get_max_symbol_value(counts: &[u32]) -> u32 {
+    let mut max_symbol_value = counts.len() as u32 - 1;
 
     while counts[max_symbol_value as usize] == 0 {
         max_symbol_value -= 1;
@@ -25,26 +29,172 @@ pub fn get_max_symbol_value(counts: &CountsTable) -> u32 {
     max_symbol_value
 }
 
+/// Secondary normalization method, used when the rounding in `get_normalized_counts` leaves too
+/// much to redistribute to the largest symbol.
+///
+/// This happens when there are many low probability symbols, which all get rounded up.
+/// Port of FSE_normalizeM2, which like the original fails if a symbol would be rounded to zero.
+fn normalize_m2(
+    norm_counts: &mut [i16],
+    table_log: u32,
+    counts: &[u32],
+    mut total: u64,
+    max_symbol_value: u32,
+) -> Result<(), HistError> {
+    const NOT_YET_ASSIGNED: i16 = -2;
+    let mut distributed: u32 = 0;
+
+    // Init
+    let low_threshold = (total >> table_log) as u32;
+    let mut low_one = ((total * 3) >> (table_log + 1)) as u32;
+
+    for symbol in 0..=max_symbol_value as usize {
+        let symbol_count = counts[symbol];
+        if symbol_count == 0 {
+            norm_counts[symbol] = 0;
+            continue;
+        }
+        if symbol_count <= low_threshold {
+            norm_counts[symbol] = -1;
+            distributed += 1;
+            total -= symbol_count as u64;
+            continue;
+        }
+        if symbol_count <= low_one {
+            norm_counts[symbol] = 1;
+            distributed += 1;
+            total -= symbol_count as u64;
+            continue;
+        }
+        norm_counts[symbol] = NOT_YET_ASSIGNED;
+    }
+    let mut to_distribute = (1 << table_log) - distributed;
+
+    if to_distribute == 0 {
+        return Ok(());
+    }
+
+    if total / to_distribute as u64 > low_one as u64 {
+        // risk of rounding to zero
+        low_one = ((total * 3) / (to_distribute as u64 * 2)) as u32;
+        for symbol in 0..=max_symbol_value as usize {
+            if norm_counts[symbol] == NOT_YET_ASSIGNED && counts[symbol] <= low_one {
+                norm_counts[symbol] = 1;
+                distributed += 1;
+                total -= counts[symbol] as u64;
+            }
+        }
+        to_distribute = (1 << table_log) - distributed;
+    }
+
+    if distributed == max_symbol_value + 1 {
+        // all values are pretty poor, probably incompressible data (should have already been detected).
+        // find max, then give all remaining points to max
+        let mut max_symbol = 0;
+        for symbol in 0..=max_symbol_value as usize {
+            if counts[symbol] > counts[max_symbol] {
+                max_symbol = symbol;
+            }
+        }
+        norm_counts[max_symbol] = norm_counts[max_symbol].abs() + to_distribute as i16;
+        return Ok(());
+    }
+
+    if total == 0 {
+        // all of the symbols were low enough for low_one or low_threshold
+        let mut symbol = 0;
+        while to_distribute > 0 {
+            if norm_counts[symbol] > 0 {
+                to_distribute -= 1;
+                norm_counts[symbol] += 1;
+            }
+            symbol = (symbol + 1) % (max_symbol_value as usize + 1);
+        }
+        return Ok(());
+    }
+
+    let v_step_log: u64 = 62 - table_log as u64;
+    let mid: u64 = (1 << (v_step_log - 1)) - 1;
+    // scale on remaining
+    let r_step: u64 = (((1_u64 << v_step_log) * to_distribute as u64) + mid) / total;
+    let mut tmp_total = mid;
+    for symbol in 0..=max_symbol_value as usize {
+        if norm_counts[symbol] == NOT_YET_ASSIGNED {
+            let end = tmp_total + counts[symbol] as u64 * r_step;
+            let s_start = (tmp_total >> v_step_log) as u32;
+            let s_end = (end >> v_step_log) as u32;
+            let weight = s_end - s_start;
+            if weight < 1 {
+                return Err(HistError::SymbolRoundedToZero(counts[symbol]));
+            }
+            norm_counts[symbol] = weight as i16;
+            tmp_total = end;
+        }
+    }
+    Ok(())
+}
+
 /// Normalize the frequencies.
 ///
 /// get_normalized_counts() will ensure that sum of frequencies is == 2 ^ tableLog.
+///
+/// Returns an error if the secondary normalization method can't give every symbol of the input a
+/// probability, which zstd also reports instead of producing an invalid table.
 #[inline]
 pub fn get_normalized_counts(
     counts: &CountsTable,
     table_log: u32,
     src_size: usize,
     max_symbol_value: u32,
-) -> NormCountsTable {
+) -> Result<NormCountsTable, HistError> {
+    // Variable length arrays are not yet supported in Rust, [0_i16;max_symbol_value] would be enough for the counts;
+    // https://doc.rust-lang.org/beta/unstable-book/language-features/unsized-locals.html
+    // This should also remove bounds checks for the loop below.
+    let mut norm_counts = [0_i16; 256];
+    normalize_counts_into(
+        counts,
+        &mut norm_counts,
+        table_log,
+        src_size,
+        max_symbol_value,
+    )?;
+    Ok(norm_counts)
+}
+
+/// Normalize the frequencies of an alphabet with any number of symbols, see
+/// `get_normalized_counts`.
+///
+/// Returns `max_symbol_value + 1` normalized counts.
+pub fn get_normalized_counts_generic(
+    counts: &[u32],
+    table_log: u32,
+    src_size: usize,
+    max_symbol_value: u32,
+) -> Result<Vec<i16>, HistError> {
+    let mut norm_counts = vec![0_i16; max_symbol_value as usize + 1];
+    normalize_counts_into(
+        counts,
+        &mut norm_counts,
+        table_log,
+        src_size,
+        max_symbol_value,
+    )?;
+    Ok(norm_counts)
+}
+
+fn normalize_counts_into(
+    counts: &[u32],
+    norm_counts: &mut [i16],
+    table_log: u32,
+    src_size: usize,
+    max_symbol_value: u32,
+) -> Result<(), HistError> {
     debug!("table_log: {:?}", table_log);
     // if table_log < fse_min_table_log(src_size, max_symbol_value) {
     //     panic!("Too small tableLog, compression potentially impossible table_log {:?} fse_min_table_log {:?} ", table_log, fse_min_table_log(src_size, max_symbol_value));
     // };
 
     let total = src_size as u64;
-    // Variable length arrays are not yet supported in Rust, [0_i16;max_symbol_value] would be enough for the counts;
-    // https://doc.rust-lang.org/beta/unstable-book/language-features/unsized-locals.html
-    // This should also remove bounds checks for the loop below.
-    let mut norm_counts = [0_i16; 256];
 
     /// rest to beat table
     const RTB_TABLE: [u32; 8] = [0, 473195, 504333, 520860, 550000, 700000, 750000, 830000];
@@ -92,10 +242,8 @@ pub fn get_normalized_counts(
 
     debug!("still_to_distribute: {}", still_to_distribute);
     if -still_to_distribute >= (norm_counts[largest as usize] as i32 >> 1) {
-        panic!("count normalization corner case not yet implemented");
-        /* corner case, need another normalization method */
-        // size_t const errorCode = FSE_normalizeM2(norm_counts, tableLog, count, total, maxSymbolValue);
-        // if (FSE_isError(errorCode)) return errorCode;
+        // corner case, need another normalization method
+        normalize_m2(norm_counts, table_log, counts, total, max_symbol_value)?;
     } else {
         // assign rest to match total norm counts = 1 << table_log
         norm_counts[largest as usize] += still_to_distribute as i16;
@@ -115,8 +263,7 @@ pub fn get_normalized_counts(
             error!("Warning Total {} != table_log {}", n_total, 1 << table_log);
         }
     }
-
-    norm_counts
+    Ok(())
 }
 
 /// creates a table with the counts of each symbol
@@ -130,13 +277,31 @@ pub fn count_simple(input: &[u8]) -> CountsTable {
     counts
 }
 
-pub fn get_normalized_counts_from_data(data: &[u8]) -> (NormCountsTable, u32, u32) {
+/// creates a table with the counts of each symbol, for alphabets larger than `u8`
+///
+/// Returns `S::MAX_SYMBOL_VALUE + 1` counts, or an error if a symbol is larger than
+/// `S::MAX_SYMBOL_VALUE`.
+pub fn count_symbols<S: Symbol>(input: &[S]) -> Result<Vec<u32>, HistError> {
+    let mut counts = vec![0_u32; S::MAX_SYMBOL_VALUE as usize + 1];
+
+    for symbol in input {
+        let count = counts
+            .get_mut(symbol.to_usize())
+            .ok_or(HistError::MaxSymbolValueTooLarge)?;
+        *count = count.saturating_add(1);
+    }
+    Ok(counts)
+}
+
+pub fn get_normalized_counts_from_data(
+    data: &[u8],
+) -> Result<(NormCountsTable, u32, u32), HistError> {
     let counts = count_simple(data);
     let max_symbol_value = get_max_symbol_value(&counts);
     let table_log = fse_optimal_table_log(FSE_DEFAULT_TABLELOG, data.len(), max_symbol_value);
 
-    let norm_counts = get_normalized_counts(&counts, table_log, data.len(), max_symbol_value);
-    (norm_counts, max_symbol_value, table_log)
+    let norm_counts = get_normalized_counts(&counts, table_log, data.len(), max_symbol_value)?;
+    Ok((norm_counts, max_symbol_value, table_log))
 }
 
 /// creates a table with the counts of each symbol
@@ -329,17 +494,21 @@ pub fn fse_ncount_write_bound(max_symbol_value: u32, table_log: u32) -> u32 {
 /// write count metadata into header which is used by FSE and hufmann
 pub fn fse_write_n_count(
     out: &mut [u8],
-    norm_counts: &NormCountsTable,
+    norm_counts: &[i16],
     max_symbol_value: u32,
     table_log: u32,
 ) -> Result<usize, HistError> {
-    if table_log > FSE_MAX_TABLELOG {
+    // up to the `Symbol::MAX_TABLE_LOG` of u16, like `fse_read_n_count`
+    if table_log > FSE_TABLELOG_ABSOLUTE_MAX {
         return Err(HistError::TableLogTooLarge);
     }
 
     if table_log < FSE_MIN_TABLELOG {
         return Err(HistError::TableLogTooSmall);
     }
+    if max_symbol_value as usize >= norm_counts.len() {
+        return Err(HistError::MaxSymbolValueTooLarge);
+    }
     if out.len() < fse_ncount_write_bound(max_symbol_value, table_log) as usize {
         fse_write_n_count_generic(out, norm_counts, max_symbol_value, table_log, false)
     } else {
@@ -350,7 +519,7 @@ pub fn fse_write_n_count(
 /// write count metadata into header which is used by FSE and hufmann
 pub fn fse_write_n_count_generic(
     mut out: &mut [u8],
-    norm_counts: &NormCountsTable,
+    norm_counts: &[i16],
     max_symbol_value: u32,
     table_log: u32,
     write_is_safe: bool,
@@ -454,17 +623,30 @@ pub fn fse_write_n_count_generic(
 }
 
 /// write count metadata into header which is used by FSE and hufmann
+///
+/// `max_symbol_value` is the largest symbol value accepted, it is limited by the size of
+/// `norm_counts`. It is set to the max symbol value of the header.
 pub fn fse_read_n_count(
     mut data: &[u8],
-    norm_counts: &mut NormCountsTable,
+    norm_counts: &mut [i16],
     max_symbol_value: &mut u32,
     table_log: &mut u32,
 ) -> Result<usize, HistError> {
+    if norm_counts.is_empty() {
+        return Err(HistError::MaxSymbolValueTooSmall);
+    }
+    *max_symbol_value = (*max_symbol_value).min(norm_counts.len() as u32 - 1);
     let data_len = data.len();
     if data.len() < 4 {
         let mut buffer = [0, 0, 0, 0];
         buffer[..data.len()].copy_from_slice(data);
-        return fse_read_n_count(data, norm_counts, max_symbol_value, table_log);
+        let bytes_read = fse_read_n_count(&buffer, norm_counts, max_symbol_value, table_log)?;
+        if bytes_read > data_len {
+            return Err(HistError::CorruptionDetected(
+                "header is larger than the input".to_string(),
+            ));
+        }
+        return Ok(bytes_read);
     }
 
     let mut bit_stream = u32::from_le_bytes(data[..4].try_into().unwrap());
@@ -509,8 +691,12 @@ pub fn fse_read_n_count(
                 norm_counts[charnum as usize] = 0;
                 charnum += 1;
             }
-            if data.len() >= 7 || data.len() - (bit_count as usize >> 3) >= 4 {
-                assert!(bit_count >> 3 <= 3);
+            if data.len() >= 7 || data.len() >= (bit_count as usize >> 3) + 4 {
+                if bit_count >> 3 > 3 {
+                    return Err(HistError::CorruptionDetected(
+                        "too many zero counts".to_string(),
+                    ));
+                }
                 data = &data[bit_count as usize >> 3..];
                 bit_count &= 7;
                 bit_stream = u32::from_le_bytes(data[..4].try_into().unwrap()) >> bit_count;
@@ -539,12 +725,14 @@ pub fn fse_read_n_count(
             nb_bits -= 1;
             threshold >>= 1;
         }
-        if data.len() >= 7 || data.len() - (bit_count as usize >> 3) >= 4 {
+        if data.len() >= 7 || data.len() >= (bit_count as usize >> 3) + 4 {
             data = &data[bit_count as usize >> 3..];
             bit_count &= 7;
         } else {
-            bit_count -= (8 * data.len() - 4) as i32;
-            data = &data[..data.len() - 4]; // could be an issue if data has less than 4 left?
+            // close to the end of the input, read the last 4 bytes and adjust bit_count relative to them
+            bit_count -= (8 * (data.len() - 4)) as i32;
+            bit_count &= 31;
+            data = &data[data.len() - 4..];
         }
         bit_stream = u32::from_le_bytes(data[..4].try_into().unwrap()) >> (bit_count & 31);
     }
@@ -558,8 +746,12 @@ pub fn fse_read_n_count(
     }
     *max_symbol_value = charnum - 1;
 
-    data = &data[(bit_count as usize + 7) >> 3..]; // could be an issue if data has less than 4 left?
-    let bytes_read = data_len - data.len();
+    let bytes_read = data_len - data.len() + ((bit_count as usize + 7) >> 3);
+    if bytes_read > data_len {
+        return Err(HistError::CorruptionDetected(
+            "header is larger than the input".to_string(),
+        ));
+    }
     Ok(bytes_read)
 }
 
@@ -613,7 +805,7 @@ mod tests {
         //.read_to_end(&mut test_data)
         //.unwrap();
         let (norm_counts, mut max_symbol_value, table_log) =
-            get_normalized_counts_from_data(test_data);
+            get_normalized_counts_from_data(test_data).unwrap();
         let mut out = vec![0; fse_ncount_write_bound(max_symbol_value, table_log) as usize];
         let bytes_written = fse_write_n_count(
             out.as_mut_slice(),
@@ -636,6 +828,98 @@ mod tests {
         Ok(())
     }
 
+    /// many low probability symbols, which are rounded up to 1, overshoot the table size
+    fn get_skewed_counts() -> (CountsTable, usize) {
+        let mut counts = [0_u32; 256];
+        for count in counts.iter_mut().take(30) {
+            *count = 300;
+        }
+        for count in counts.iter_mut().skip(30).take(200) {
+            *count = 1;
+        }
+        (counts, 30 * 300 + 200)
+    }
+
+    #[test]
+    fn test_normalize_corner_case() {
+        let (counts, total) = get_skewed_counts();
+        let max_symbol_value = get_max_symbol_value(&counts);
+        for table_log in 9..=FSE_MAX_TABLELOG {
+            let norm_counts =
+                get_normalized_counts(&counts, table_log, total, max_symbol_value).unwrap();
+            let sum: i32 = norm_counts.iter().map(|count| count.abs() as i32).sum();
+            assert_eq!(sum, 1 << table_log);
+            for (count, norm_count) in counts.iter().zip(norm_counts.iter()) {
+                assert_eq!(*count == 0, *norm_count == 0);
+            }
+
+            // the counts are normalized with the secondary method
+            let mut norm_counts_m2 = [0_i16; 256];
+            normalize_m2(
+                &mut norm_counts_m2,
+                table_log,
+                &counts,
+                total as u64,
+                max_symbol_value,
+            )
+            .unwrap();
+            assert_eq!(norm_counts, norm_counts_m2);
+        }
+    }
+
+    #[test]
+    fn test_n_count_large_alphabet() {
+        // u16 symbols, every 3rd symbol up to 4000
+        let input: Vec<u16> = (0..200_000_u64)
+            .map(|i| ((i * i) % 1_000_003 % 4001 / 3 * 3) as u16)
+            .collect();
+        let counts = count_symbols(&input).unwrap();
+        assert_eq!(counts.len(), MAX_SYMBOL_VALUE_U16 as usize + 1);
+        let max_symbol_value = get_max_symbol_value(&counts);
+        assert!(max_symbol_value > 3990);
+
+        let table_log = FSE_MAX_TABLELOG;
+        let norm_counts =
+            get_normalized_counts_generic(&counts, table_log, input.len(), max_symbol_value)
+                .unwrap();
+        let sum: i32 = norm_counts.iter().map(|count| count.abs() as i32).sum();
+        assert_eq!(sum, 1 << table_log);
+
+        let mut out = vec![0; fse_ncount_write_bound(max_symbol_value, table_log) as usize];
+        let bytes_written =
+            fse_write_n_count(&mut out, &norm_counts, max_symbol_value, table_log).unwrap();
+
+        let mut norm_counts_restored = vec![0_i16; MAX_SYMBOL_VALUE_U16 as usize + 1];
+        let mut max_symbol_value_restored = MAX_SYMBOL_VALUE_U16;
+        let mut table_log_restored = 0;
+        let bytes_read = fse_read_n_count(
+            &out[..bytes_written],
+            &mut norm_counts_restored,
+            &mut max_symbol_value_restored,
+            &mut table_log_restored,
+        )
+        .unwrap();
+        assert_eq!(bytes_read, bytes_written);
+        assert_eq!(table_log_restored, table_log);
+        assert_eq!(max_symbol_value_restored, max_symbol_value);
+        assert_eq!(
+            &norm_counts_restored[..=max_symbol_value as usize],
+            &norm_counts[..]
+        );
+
+        // the header does not fit into a u8 table
+        let mut max_symbol_value_u8 = MAX_SYMBOL_VALUE;
+        assert!(fse_read_n_count(
+            &out[..bytes_written],
+            &mut [0_i16; 256],
+            &mut max_symbol_value_u8,
+            &mut table_log_restored,
+        )
+        .is_err());
+
+        assert!(count_symbols(&[1_u16, 4096]).is_err());
+    }
+
     #[test]
     fn test_statistic_fns() {
         let test_data = get_test_data();
@@ -655,7 +939,7 @@ mod tests {
         assert_eq!(counts[B_BYTE as usize], 35);
         assert_eq!(counts[C_BYTE as usize], 20);
 
-        let norm_counts = get_normalized_counts(&counts, 7, test_data.len(), 255);
+        let norm_counts = get_normalized_counts(&counts, 7, test_data.len(), 255).unwrap();
 
         assert_eq!(norm_counts[A_BYTE as usize], 59);
         assert_eq!(norm_counts[B_BYTE as usize], 44);
@@ -669,7 +953,7 @@ mod tests {
             128
         );
 
-        let norm_counts = get_normalized_counts(&counts, 8, test_data.len(), 255);
+        let norm_counts = get_normalized_counts(&counts, 8, test_data.len(), 255).unwrap();
         // make sure sum is power of 2 of table_log
         assert_eq!(
             norm_counts[A_BYTE as usize]
@@ -0,0 +1,52 @@
+use std::fmt::Debug;
+
+/// Max symbol value for `u16` symbols, limited by the number of states of `FSE_MAX_TABLELOG`
+pub const MAX_SYMBOL_VALUE_U16: u32 = (1 << crate::FSE_MAX_TABLELOG) - 1;
+
+/// Type of the symbols in the alphabet, which are encoded with FSE.
+///
+/// Implemented for `u8` (256 symbols) and `u16` (4096 symbols).
+pub trait Symbol: Copy + Default + Debug + Send + Sync + 'static {
+    /// Largest symbol value of the alphabet
+    const MAX_SYMBOL_VALUE: u32;
+
+    /// Largest table_log of a table for the alphabet. The bitstream loops flush and reload the
+    /// bit container for symbols of up to this many bits.
+    const MAX_TABLE_LOG: u32;
+
+    fn to_usize(self) -> usize;
+
+    /// Converts a symbol value to the symbol, value needs to be `<= MAX_SYMBOL_VALUE`
+    fn from_usize(value: usize) -> Self;
+}
+
+impl Symbol for u8 {
+    const MAX_SYMBOL_VALUE: u32 = u8::MAX as u32;
+    const MAX_TABLE_LOG: u32 = crate::FSE_MAX_TABLELOG;
+
+    #[inline]
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+
+    #[inline]
+    fn from_usize(value: usize) -> Self {
+        value as u8
+    }
+}
+
+impl Symbol for u16 {
+    const MAX_SYMBOL_VALUE: u32 = MAX_SYMBOL_VALUE_U16;
+    /// More states than `FSE_MAX_TABLELOG`, since every symbol needs at least one
+    const MAX_TABLE_LOG: u32 = crate::FSE_TABLELOG_ABSOLUTE_MAX;
+
+    #[inline]
+    fn to_usize(self) -> usize {
+        self as usize
+    }
+
+    #[inline]
+    fn from_usize(value: usize) -> Self {
+        value as u16
+    }
+}
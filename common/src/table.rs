@@ -14,7 +14,8 @@ pub fn fse_optimal_table_log_interal(
 ) -> u32 {
     let mut table_log = max_table_log;
 
-    let max_bits_src = highbit_pos(src_size as u32 - 1) - minus;
+    // wraps for small inputs, which then don't limit the table_log
+    let max_bits_src = highbit_pos(src_size as u32 - 1).wrapping_sub(minus);
     let min_bits = fse_min_table_log(src_size, max_symbol_value);
 
     table_log = table_log.min(max_bits_src); // accuracy can be reduced
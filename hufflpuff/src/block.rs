@@ -0,0 +1,357 @@
+/*!
+Blocks, which are compressed with huffman or FSE (`ans_flex`), whichever is estimated to be
+smaller, or huffman if it is within a tolerance, since it decompresses faster.
+
+Layout of a block:
+
+| Field      | Size     | Description                                   |
+|------------|----------|-----------------------------------------------|
+| block_type | 1 byte   | `BlockType` tag, the chosen coder             |
+| orig_size  | 4 bytes  | size of the uncompressed input, little endian |
+| payload    | variable | depends on the block type                     |
+
+The payload of a `BlockType::Huffman` block consists of the weights, written with
+`write_huffman_table`, followed by the bitstream of `compress_1x_rev`. The payload of a
+`BlockType::Fse` block is the output of `ans_flex::compress_symbols`, the normalized counts
+followed by the bitstream. A `BlockType::Rle` block stores the symbol and a `BlockType::Raw` block
+the input as is.
+
+*/
+
+use crate::compress::{compress_1x_rev, huf_compress_1x_bound};
+use crate::decompress::decompress_1x;
+use crate::error::DecodeError;
+use crate::tree::build_tree::tree_to_table;
+use crate::weights::{read_huffman_table, write_huffman_table};
+use crate::{build_tree_fast, count_simple};
+use ans_flex::{compress_symbols, decompress_symbols, estimate_compressed_size, CompressOptions};
+use common::{fse_optimal_table_log, get_max_symbol_value, get_normalized_counts};
+use std::convert::TryInto;
+
+/// Size of the block type tag and the original size at the start of a block
+pub const BLOCK_HEADER_SIZE: usize = 5;
+
+/// The coder of a block, stored as tag in the block header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlockType {
+    /// Uncompressed data
+    Raw = 0,
+    /// A single symbol, repeated for the size of the block
+    Rle = 1,
+    /// Huffman compressed data
+    Huffman = 2,
+    /// FSE compressed data
+    Fse = 3,
+}
+
+impl BlockType {
+    pub fn from_tag(tag: u8) -> Result<Self, DecodeError> {
+        match tag {
+            0 => Ok(BlockType::Raw),
+            1 => Ok(BlockType::Rle),
+            2 => Ok(BlockType::Huffman),
+            3 => Ok(BlockType::Fse),
+            _ => Err(DecodeError::UnknownBlockType(tag)),
+        }
+    }
+}
+
+/// Parameters of `compress_block_with_options`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BlockOptions {
+    /// Options of the FSE compression
+    pub fse: CompressOptions,
+    /// Huffman is chosen, if its estimated size is at most this many percent larger than the
+    /// estimated size of FSE, since huffman decompresses faster. With 0 the smaller one is chosen.
+    pub huffman_tolerance_percent: u32,
+}
+
+/// Estimated sizes of the payload with each coder, see `estimate_block_sizes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSizeEstimate {
+    /// Size of the prefix codes and the bitstream, None if the codes can't be written
+    pub huffman: Option<usize>,
+    /// Size of the normalized counts and the bitstream, None if FSE can't compress the input
+    pub fse: Option<usize>,
+}
+
+/// Estimates the size of the huffman and FSE payload of input, without compressing it.
+pub fn estimate_block_sizes(input: &[u8], options: &BlockOptions) -> BlockSizeEstimate {
+    let counts = count_simple(input);
+    BlockSizeEstimate {
+        huffman: estimate_huffman_size(&counts),
+        fse: estimate_fse_size(&counts, input.len(), &options.fse),
+    }
+}
+
+/// The prefix codes header and the bitstream size of `Tree::estimate_compressed_size`
+fn estimate_huffman_size(counts: &[usize; 256]) -> Option<usize> {
+    let tree = build_tree_fast(counts);
+    let header = write_huffman_table(&tree_to_table(&tree)).ok()?;
+    Some(header.len() + tree.estimate_compressed_size())
+}
+
+/// The normalized counts header and the bitstream size of `ans_flex::estimate_compressed_size`
+fn estimate_fse_size(
+    counts: &[usize; 256],
+    src_size: usize,
+    options: &CompressOptions,
+) -> Option<usize> {
+    let max_count = *counts.iter().max().unwrap();
+    // rle, or each symbol present maximum once
+    if max_count == src_size || max_count <= 1 {
+        return None;
+    }
+    let mut counts_u32 = [0_u32; 256];
+    for (count_u32, count) in counts_u32.iter_mut().zip(counts.iter()) {
+        *count_u32 = *count as u32;
+    }
+    let counts = counts_u32;
+    let max_symbol_value = get_max_symbol_value(&counts);
+    let table_log = fse_optimal_table_log(options.table_log_limit(), src_size, max_symbol_value);
+    let norm_counts = get_normalized_counts(&counts, table_log, src_size, max_symbol_value).ok()?;
+    estimate_compressed_size(&counts, &norm_counts, table_log).ok()
+}
+
+/// Compresses input into a block with huffman or FSE, whichever is estimated to be smaller.
+///
+/// Input which is not compressible is stored as raw or rle block.
+pub fn compress_block(input: &[u8]) -> Vec<u8> {
+    compress_block_with_options(input, &BlockOptions::default())
+}
+
+/// Like `compress_block`, but prefers huffman within `options.huffman_tolerance_percent`.
+pub fn compress_block_with_options(input: &[u8], options: &BlockOptions) -> Vec<u8> {
+    assert!(input.len() <= u32::MAX as usize);
+    let mut out = vec![0; BLOCK_HEADER_SIZE];
+    out[1..BLOCK_HEADER_SIZE].copy_from_slice(&(input.len() as u32).to_le_bytes());
+
+    let counts = count_simple(input);
+    let block_type = if *counts.iter().max().unwrap() == input.len() {
+        out.push(input.first().cloned().unwrap_or_default());
+        BlockType::Rle
+    } else {
+        let huffman = estimate_huffman_size(&counts);
+        let fse = estimate_fse_size(&counts, input.len(), &options.fse);
+        let use_huffman = match (huffman, fse) {
+            (Some(huffman), Some(fse)) => {
+                huffman * 100 <= fse * (100 + options.huffman_tolerance_percent as usize)
+            }
+            (huffman, _) => huffman.is_some(),
+        };
+        let block_type = if use_huffman {
+            write_huffman_payload(input, &counts, &mut out)
+        } else if fse.is_some() {
+            write_fse_payload(input, options, &mut out)
+        } else {
+            None
+        };
+        match block_type {
+            Some(block_type) if out.len() - BLOCK_HEADER_SIZE < input.len() => block_type,
+            _ => {
+                out.truncate(BLOCK_HEADER_SIZE);
+                out.extend_from_slice(input);
+                BlockType::Raw
+            }
+        }
+    };
+    out[0] = block_type as u8;
+    out
+}
+
+fn write_huffman_payload(
+    input: &[u8],
+    counts: &[usize; 256],
+    out: &mut Vec<u8>,
+) -> Option<BlockType> {
+    let tree = build_tree_fast(counts);
+    let codes = tree_to_table(&tree);
+    out.extend_from_slice(&write_huffman_table(&codes).ok()?);
+
+    let start = out.len();
+    out.resize(start + huf_compress_1x_bound(&codes, input.len()), 0);
+    let compressed_size = compress_1x_rev(&codes, input, &mut out[start..]);
+    out.truncate(start + compressed_size);
+    Some(BlockType::Huffman)
+}
+
+fn write_fse_payload(input: &[u8], options: &BlockOptions, out: &mut Vec<u8>) -> Option<BlockType> {
+    // the heuristics of ans_flex may still reject the input
+    out.extend_from_slice(&compress_symbols(input, &options.fse).ok()?);
+    Some(BlockType::Fse)
+}
+
+/// Decompresses a block created by `compress_block`, with the coder of the block header.
+///
+/// The output is allocated with the decompressed size in the block header, which can be up to
+/// 4 GiB. Untrusted input should be decompressed with `decompress_block_with_max_size`.
+pub fn decompress_block(input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    decompress_block_with_max_size(input, u32::MAX as usize)
+}
+
+/// Like `decompress_block`, but accepts blocks with a decompressed size up to `max_block_size`.
+///
+/// Larger blocks are rejected with `DecodeError::BlockTooLarge`, before the output is allocated.
+pub fn decompress_block_with_max_size(
+    input: &[u8],
+    max_block_size: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    if input.len() < BLOCK_HEADER_SIZE {
+        return Err(DecodeError::BlockTooShort);
+    }
+    let block_type = BlockType::from_tag(input[0])?;
+    let orig_size = u32::from_le_bytes(input[1..BLOCK_HEADER_SIZE].try_into().unwrap()) as usize;
+    if orig_size > max_block_size {
+        return Err(DecodeError::BlockTooLarge(orig_size));
+    }
+    let payload = &input[BLOCK_HEADER_SIZE..];
+
+    match block_type {
+        BlockType::Raw => {
+            if payload.len() != orig_size {
+                return Err(DecodeError::InvalidPayloadSize);
+            }
+            Ok(payload.to_vec())
+        }
+        BlockType::Rle => {
+            if payload.len() != 1 {
+                return Err(DecodeError::InvalidPayloadSize);
+            }
+            Ok(vec![payload[0]; orig_size])
+        }
+        BlockType::Huffman => {
+            let (codes, header_size) = read_huffman_table(payload)?;
+            let mut output = vec![0; orig_size];
+            decompress_1x(&codes, &payload[header_size..], &mut output)?;
+            Ok(output)
+        }
+        BlockType::Fse => {
+            decompress_symbols(payload, orig_size).map_err(DecodeError::InvalidFseBlock)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_DATA: &[&[u8]] = &[
+        include_bytes!("../../test_data/compression_66k_JSON.txt"),
+        include_bytes!("../../test_data/compression_65k.txt"),
+        include_bytes!("../../test_data/compression_34k.txt"),
+        include_bytes!("../../test_data/compression_1k.txt"),
+        include_bytes!("../../test_data/v4_uuids_19k.txt"),
+        include_bytes!("../../test_data/v4_uuids_93k.txt"),
+        include_bytes!("../../test_data/logo.jpg"),
+    ];
+
+    fn inverse(test_data: &[u8], options: &BlockOptions) -> BlockType {
+        let block = compress_block_with_options(test_data, options);
+        assert!(block.len() <= test_data.len().max(1) + BLOCK_HEADER_SIZE);
+        assert_eq!(decompress_block(&block).unwrap(), test_data);
+        BlockType::from_tag(block[0]).unwrap()
+    }
+
+    #[test]
+    fn test_block_roundtrip() {
+        for test_data in TEST_DATA {
+            let block_type = inverse(test_data, &BlockOptions::default());
+            let estimate = estimate_block_sizes(test_data, &BlockOptions::default());
+            match block_type {
+                BlockType::Huffman => assert!(estimate.huffman <= estimate.fse),
+                BlockType::Fse => assert!(estimate.fse < estimate.huffman),
+                _ => {}
+            }
+        }
+        assert_eq!(inverse(&[], &BlockOptions::default()), BlockType::Rle);
+        assert_eq!(inverse(&[7; 100], &BlockOptions::default()), BlockType::Rle);
+        assert_eq!(inverse(&[1, 2], &BlockOptions::default()), BlockType::Raw);
+        let all_bytes = (0..=u8::MAX).collect::<Vec<u8>>();
+        assert_eq!(
+            inverse(&all_bytes, &BlockOptions::default()),
+            BlockType::Raw
+        );
+    }
+
+    #[test]
+    fn test_block_choice() {
+        // fractional bits: FSE is smaller for a skewed distribution
+        let mut skewed = vec![0_u8; 9000];
+        skewed.extend_from_slice(&[1; 700]);
+        skewed.extend_from_slice(&[2; 300]);
+        assert_eq!(inverse(&skewed, &BlockOptions::default()), BlockType::Fse);
+
+        // huffman is preferred within the tolerance
+        let options = BlockOptions {
+            huffman_tolerance_percent: 100,
+            ..Default::default()
+        };
+        assert_eq!(inverse(&skewed, &options), BlockType::Huffman);
+        let estimate = estimate_block_sizes(&skewed, &options);
+        assert!(estimate.fse.unwrap() < estimate.huffman.unwrap());
+    }
+
+    #[test]
+    fn test_block_estimates() {
+        for test_data in &TEST_DATA[..6] {
+            let estimate = estimate_block_sizes(test_data, &BlockOptions::default());
+
+            let huffman_options = BlockOptions {
+                huffman_tolerance_percent: 1000,
+                ..Default::default()
+            };
+            let huffman = compress_block_with_options(test_data, &huffman_options);
+            assert_eq!(huffman[0], BlockType::Huffman as u8);
+            assert!(
+                estimate
+                    .huffman
+                    .unwrap()
+                    .abs_diff(huffman.len() - BLOCK_HEADER_SIZE)
+                    <= 1
+            );
+
+            let fse = compress_symbols(test_data, &CompressOptions::default()).unwrap();
+            assert!(estimate.fse.unwrap().abs_diff(fse.len()) <= fse.len() / 50 + 4);
+        }
+    }
+
+    #[test]
+    fn test_block_corrupted() {
+        for test_data in &TEST_DATA[..4] {
+            for huffman_tolerance_percent in [0, 1000] {
+                let options = BlockOptions {
+                    huffman_tolerance_percent,
+                    ..Default::default()
+                };
+                let block = compress_block_with_options(&test_data[..700], &options);
+                for len in 0..block.len() {
+                    assert!(decompress_block(&block[..len]).is_err());
+                }
+                // the max size limits the allocation of corrupted sizes in the header
+                for pos in 0..block.len().min(200) {
+                    let mut corrupted = block.clone();
+                    corrupted[pos] ^= 1 << (pos % 8);
+                    let _ = decompress_block_with_max_size(&corrupted, 1000);
+                }
+            }
+        }
+        assert!(matches!(
+            decompress_block(&[4, 0, 0, 0, 0]),
+            Err(DecodeError::UnknownBlockType(4))
+        ));
+
+        // rle block claiming 1 GiB, rejected before allocating
+        let block = [BlockType::Rle as u8, 0, 0, 0, 0x40, 7];
+        assert!(matches!(
+            decompress_block_with_max_size(&block, 1 << 20),
+            Err(DecodeError::BlockTooLarge(0x4000_0000))
+        ));
+        let block = compress_block(&[7; 1000]);
+        assert!(decompress_block_with_max_size(&block, 999).is_err());
+        assert_eq!(
+            decompress_block_with_max_size(&block, 1000).unwrap(),
+            vec![7; 1000]
+        );
+    }
+}
@@ -1,10 +1,36 @@
 use crate::tree::tree_node::MinNode;
-use crate::HUF_TABLELOG_MAX;
+use crate::{HUF_JUMP_TABLE_SIZE, HUF_TABLELOG_MAX};
 use bitstream::BitCstream;
 use bitstream::NUM_BITS_IN_BIT_CONTAINER;
 
+/// Upper bound of the output of `compress_1x_rev` for `size` bytes of input with the codes of
+/// `table`.
+///
+/// Every symbol costs at most the longest code, and the bit container is written as a whole.
+pub fn huf_compress_1x_bound(table: &[MinNode], size: usize) -> usize {
+    let max_bits = table
+        .iter()
+        .map(|node| node.number_bits as usize)
+        .max()
+        .unwrap_or(0);
+    (size * max_bits).div_ceil(8) + std::mem::size_of::<usize>()
+}
+
+/// Upper bound of the output of `compress_4x` for `size` bytes of input with the codes of
+/// `table`.
+pub fn huf_compress_4x_bound(table: &[MinNode], size: usize) -> usize {
+    HUF_JUMP_TABLE_SIZE + 4 * huf_compress_1x_bound(table, size.div_ceil(4))
+}
+
 /// compress input to dst in reverse order
-pub fn compress_1x_rev(table: &[MinNode], input: &[u8], dst: &mut [u8]) {
+///
+/// Returns the number of bytes written to dst. Panics, if dst is smaller than
+/// `huf_compress_1x_bound`, since the bitstream is written without checks for the end of dst.
+pub fn compress_1x_rev(table: &[MinNode], input: &[u8], dst: &mut [u8]) -> usize {
+    assert!(
+        dst.len() >= huf_compress_1x_bound(table, input.len()),
+        "dst is smaller than huf_compress_1x_bound"
+    );
     let mut bit_c = BitCstream::new();
 
     let mut index = input.len();
@@ -34,6 +60,41 @@ pub fn compress_1x_rev(table: &[MinNode], input: &[u8], dst: &mut [u8]) {
         bit_c.flush_bits_fast(dst);
     }
     bit_c.finish_stream(dst);
+    bit_c.get_compressed_size()
+}
+
+/// compress input to dst as four streams, which can be decoded interleaved
+///
+/// The input is split into four segments of `(input.len() + 3) / 4` bytes, the last one may be
+/// smaller. Each segment is compressed with `compress_1x_rev`. The streams are prefixed with a
+/// jump table of the compressed sizes of the first three streams, 2 bytes each, little endian.
+///
+/// Returns the number of bytes written to dst, or None if the input is too small to be split
+/// (less than 12 bytes), dst is smaller than `huf_compress_4x_bound` or a stream is too large
+/// for the jump table.
+pub fn compress_4x(table: &[MinNode], input: &[u8], dst: &mut [u8]) -> Option<usize> {
+    // no saving possible : too small input
+    if input.len() < 12 {
+        return None;
+    }
+    // the streams are written without checks for the end of dst
+    if dst.len() < huf_compress_4x_bound(table, input.len()) {
+        return None;
+    }
+    let segment_size = input.len().div_ceil(4);
+    let mut op = HUF_JUMP_TABLE_SIZE;
+    for (stream, segment) in input.chunks(segment_size).enumerate() {
+        let compressed_size = compress_1x_rev(table, segment, &mut dst[op..]);
+        if stream < 3 {
+            if compressed_size > u16::MAX as usize {
+                return None;
+            }
+            dst[stream * 2..stream * 2 + 2]
+                .copy_from_slice(&(compressed_size as u16).to_le_bytes());
+        }
+        op += compressed_size;
+    }
+    Some(op)
 }
 
 fn huf_encode_symbol(symbol: u8, bit_c: &mut BitCstream, table: &[MinNode]) {
@@ -61,7 +122,7 @@ fn huf_flush_bits_2(bit_c: &mut BitCstream, dst: &mut [u8]) {
 #[cfg(test)]
 mod tests {
     use crate::build_tree_fast;
-    use crate::compress::compress_1x_rev;
+    use crate::compress::{compress_1x_rev, huf_compress_1x_bound};
     use crate::count_simple;
     use crate::tree::build_tree::tree_to_table;
 
@@ -80,7 +141,7 @@ mod tests {
         );
 
         let table = tree_to_table(&tree);
-        let mut out = vec![0; tree.estimate_compressed_size() + 16];
+        let mut out = vec![0; huf_compress_1x_bound(&table, TEST_DATA.len())];
         compress_1x_rev(&table, TEST_DATA, &mut out);
     }
 
@@ -99,7 +160,7 @@ mod tests {
         );
 
         let table = tree_to_table(&tree);
-        let mut out = vec![0; tree.estimate_compressed_size() + 16];
+        let mut out = vec![0; huf_compress_1x_bound(&table, TEST_DATA.len())];
         compress_1x_rev(&table, TEST_DATA, &mut out);
         println!("{:08b}", out[0]);
         println!("{:08b}", out[1]);
@@ -1,4 +1,711 @@
-#[allow(dead_code)]
-fn build_x2_table(_table_log: u32, _nb_symbols: u8) {
-    unimplemented!()
+use crate::error::DecodeError;
+use crate::tree::tree_node::MinNode;
+use crate::{HUF_JUMP_TABLE_SIZE, HUF_TABLELOG_MAX};
+use bitstream::{BitDStreamReverse, BitDstreamStatus, NUM_BITS_IN_BIT_CONTAINER};
+
+/// Entry of the single symbol decoding table
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HufDEltX1 {
+    pub symbol: u8,
+    pub number_bits: u8,
+}
+
+/// Single symbol decoding table (X1)
+///
+/// The table is indexed by the next `max_bits` bits of the stream. All entries starting with the
+/// prefix code of a symbol decode to that symbol.
+#[derive(Debug)]
+pub struct DecompressionTableX1 {
+    pub table: Vec<HufDEltX1>,
+    pub max_bits: u32,
+}
+
+/// Builds the single symbol decoding table of size `1 << max_bits` from the prefix codes of
+/// `tree_to_table`.
+pub fn build_x1_table(codes: &[MinNode; 256]) -> DecompressionTableX1 {
+    let max_bits = codes
+        .iter()
+        .map(|node| node.number_bits as u32)
+        .max()
+        .unwrap()
+        .max(1);
+    assert!(max_bits <= HUF_TABLELOG_MAX);
+    let mut table = vec![HufDEltX1::default(); 1 << max_bits];
+
+    for (symbol, node) in codes.iter().enumerate() {
+        if node.number_bits == 0 {
+            continue;
+        }
+        // the code is the prefix of all entries in the range
+        let num_entries = 1 << (max_bits - node.number_bits as u32);
+        let start = (node.val as usize) << (max_bits - node.number_bits as u32);
+        for entry in &mut table[start..start + num_entries] {
+            *entry = HufDEltX1 {
+                symbol: symbol as u8,
+                number_bits: node.number_bits,
+            };
+        }
+    }
+
+    DecompressionTableX1 { table, max_bits }
+}
+
+/// Decompresses the output of `compress_1x_rev` with a single symbol decoding table.
+///
+/// output needs to be preallocated to the size of the uncompressed input.
+///
+/// Returns an error if the input is malformed or does not decode to exactly `output.len()` symbols.
+pub fn decompress_1x_x1(
+    table: &DecompressionTableX1,
+    input: &[u8],
+    output: &mut [u8],
+) -> Result<(), DecodeError> {
+    let mut bit_d = BitDStreamReverse::new(input)?;
+    decode_stream_x1(table, &mut bit_d, input, output);
+
+    if !bit_d.is_end_of_stream() {
+        return Err(DecodeError::OutputSizeMismatch);
+    }
+    Ok(())
+}
+
+/// decodes symbols from bit_d, until output is filled
+#[inline]
+fn decode_stream_x1(
+    table: &DecompressionTableX1,
+    bit_d: &mut BitDStreamReverse,
+    input: &[u8],
+    output: &mut [u8],
+) {
+    // up to 4 symbols per reload
+    let mut pos = 0;
+    for out_chunk in output.chunks_exact_mut(4) {
+        if bit_d.reload_stream(input) != BitDstreamStatus::Unfinished {
+            break;
+        }
+        out_chunk[0] = huf_decode_symbol_x1(table, bit_d);
+        huf_reload_2(bit_d, input);
+        out_chunk[1] = huf_decode_symbol_x1(table, bit_d);
+        out_chunk[2] = huf_decode_symbol_x1(table, bit_d);
+        huf_reload_2(bit_d, input);
+        out_chunk[3] = huf_decode_symbol_x1(table, bit_d);
+        pos += 4;
+    }
+
+    // [0-3] symbols remaining
+    while pos < output.len() && bit_d.reload_stream(input) == BitDstreamStatus::Unfinished {
+        output[pos] = huf_decode_symbol_x1(table, bit_d);
+        pos += 1;
+    }
+
+    // no more data to retrieve from bitstream, no need to reload
+    for out in &mut output[pos..] {
+        *out = huf_decode_symbol_x1(table, bit_d);
+    }
+}
+
+#[inline]
+fn huf_decode_symbol_x1(table: &DecompressionTableX1, bit_d: &mut BitDStreamReverse) -> u8 {
+    // look_bits_fast returns at most max_bits, so the index is always in the table
+    let elt = table.table[bit_d.look_bits_fast(table.max_bits)];
+    bit_d.skip_bits(elt.number_bits as u32);
+    elt.symbol
+}
+
+/// reloads, if the bit container can't hold 4 symbols
+#[inline]
+fn huf_reload_2(bit_d: &mut BitDStreamReverse, input: &[u8]) {
+    if NUM_BITS_IN_BIT_CONTAINER < HUF_TABLELOG_MAX * 4 + 7 {
+        bit_d.reload_stream(input);
+    }
+}
+
+/// Entry of the double symbol decoding table
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HufDEltX2 {
+    /// the decoded symbols, only the first `length` are valid
+    pub sequence: [u8; 2],
+    /// number of bits of all symbols in the sequence
+    pub number_bits: u8,
+    /// number of symbols, 1 or 2
+    pub length: u8,
+}
+
+/// Double symbol decoding table (X2)
+///
+/// Like `DecompressionTableX1`, but an entry contains a second symbol, if its prefix code fits into
+/// the remaining bits of the lookup.
+#[derive(Debug)]
+pub struct DecompressionTableX2 {
+    pub table: Vec<HufDEltX2>,
+    pub max_bits: u32,
+}
+
+/// Builds the double symbol decoding table of size `1 << max_bits` from the prefix codes of
+/// `tree_to_table`.
+pub fn build_x2_table(codes: &[MinNode; 256]) -> DecompressionTableX2 {
+    let x1_table = build_x1_table(codes);
+    let max_bits = x1_table.max_bits;
+    let table_mask = (1 << max_bits) - 1;
+
+    let table = x1_table
+        .table
+        .iter()
+        .enumerate()
+        .map(|(index, first)| {
+            // the bits after the first symbol, padded with zeros
+            let second = x1_table.table[(index << first.number_bits) & table_mask];
+            if first.number_bits != 0
+                && second.number_bits != 0
+                && first.number_bits + second.number_bits <= max_bits as u8
+            {
+                HufDEltX2 {
+                    sequence: [first.symbol, second.symbol],
+                    number_bits: first.number_bits + second.number_bits,
+                    length: 2,
+                }
+            } else {
+                HufDEltX2 {
+                    sequence: [first.symbol, 0],
+                    number_bits: first.number_bits,
+                    length: 1,
+                }
+            }
+        })
+        .collect();
+
+    DecompressionTableX2 { table, max_bits }
+}
+
+/// Decompresses the output of `compress_1x_rev` with a double symbol decoding table.
+///
+/// output needs to be preallocated to the size of the uncompressed input.
+///
+/// Returns an error if the input is malformed or does not decode to exactly `output.len()` symbols.
+pub fn decompress_1x_x2(
+    table: &DecompressionTableX2,
+    input: &[u8],
+    output: &mut [u8],
+) -> Result<(), DecodeError> {
+    let mut bit_d = BitDStreamReverse::new(input)?;
+    decode_stream_x2(table, &mut bit_d, input, output);
+
+    if !bit_d.is_end_of_stream() {
+        return Err(DecodeError::OutputSizeMismatch);
+    }
+    Ok(())
+}
+
+/// decodes symbols from bit_d, until output is filled
+#[inline]
+fn decode_stream_x2(
+    table: &DecompressionTableX2,
+    bit_d: &mut BitDStreamReverse,
+    input: &[u8],
+    output: &mut [u8],
+) {
+    let out_len = output.len();
+
+    // up to 8 symbols per reload
+    let mut pos = 0;
+    while (bit_d.reload_stream(input) == BitDstreamStatus::Unfinished) & (pos + 8 <= out_len) {
+        pos += huf_decode_symbol_x2(table, bit_d, &mut output[pos..]);
+        huf_reload_2(bit_d, input);
+        pos += huf_decode_symbol_x2(table, bit_d, &mut output[pos..]);
+        pos += huf_decode_symbol_x2(table, bit_d, &mut output[pos..]);
+        huf_reload_2(bit_d, input);
+        pos += huf_decode_symbol_x2(table, bit_d, &mut output[pos..]);
+    }
+
+    // closer to the end: up to 2 symbols at a time
+    while (bit_d.reload_stream(input) == BitDstreamStatus::Unfinished) & (pos + 2 <= out_len) {
+        pos += huf_decode_symbol_x2(table, bit_d, &mut output[pos..]);
+    }
+    // no more data to retrieve from bitstream, no need to reload
+    while pos + 2 <= out_len {
+        pos += huf_decode_symbol_x2(table, bit_d, &mut output[pos..]);
+    }
+    if pos < out_len {
+        output[pos] = huf_decode_last_symbol_x2(table, bit_d);
+    }
+}
+
+/// writes 2 bytes to output, returns the number of decoded symbols
+#[inline]
+fn huf_decode_symbol_x2(
+    table: &DecompressionTableX2,
+    bit_d: &mut BitDStreamReverse,
+    output: &mut [u8],
+) -> usize {
+    // look_bits_fast returns at most max_bits, so the index is always in the table
+    let elt = table.table[bit_d.look_bits_fast(table.max_bits)];
+    output[..2].copy_from_slice(&elt.sequence);
+    bit_d.skip_bits(elt.number_bits as u32);
+    elt.length as usize
+}
+
+/// decodes the last symbol, which may be the first of a sequence of two
+#[inline]
+fn huf_decode_last_symbol_x2(table: &DecompressionTableX2, bit_d: &mut BitDStreamReverse) -> u8 {
+    let elt = table.table[bit_d.look_bits_fast(table.max_bits)];
+    if elt.length == 1 {
+        bit_d.skip_bits(elt.number_bits as u32);
+    } else if bit_d.bits_consumed() < NUM_BITS_IN_BIT_CONTAINER {
+        // the second symbol was decoded from the zero padding after the end of the stream, so
+        // the first symbol consumes all remaining bits
+        let remaining = NUM_BITS_IN_BIT_CONTAINER - bit_d.bits_consumed();
+        bit_d.skip_bits((elt.number_bits as u32).min(remaining));
+    }
+    elt.sequence[0]
+}
+
+/// Huffman decoding variants
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HufDecoder {
+    /// single symbol per table lookup, smaller table
+    X1,
+    /// up to two symbols per table lookup
+    X2,
+}
+
+/// Decoder timings, table build time and decode time per 256 symbols, for single and double symbol
+/// decoding. Quantized by the compression ratio.
+///
+/// Taken from huf_decompress.c
+const ALGO_TIME: [[(u32, u32); 2]; 16] = [
+    [(0, 0), (1, 1)],           // Q==0 : impossible
+    [(0, 0), (1, 1)],           // Q==1 : impossible
+    [(150, 216), (381, 119)],   // Q == 2 : 12-18%
+    [(170, 205), (514, 112)],   // Q == 3 : 18-25%
+    [(177, 199), (539, 110)],   // Q == 4 : 25-32%
+    [(197, 194), (644, 107)],   // Q == 5 : 32-38%
+    [(221, 192), (735, 107)],   // Q == 6 : 38-44%
+    [(256, 189), (881, 106)],   // Q == 7 : 44-50%
+    [(359, 188), (1167, 109)],  // Q == 8 : 50-56%
+    [(582, 187), (1570, 114)],  // Q == 9 : 56-62%
+    [(688, 187), (1712, 122)],  // Q ==10 : 62-69%
+    [(825, 186), (1965, 136)],  // Q ==11 : 69-75%
+    [(976, 185), (2131, 150)],  // Q ==12 : 75-81%
+    [(1180, 186), (2070, 175)], // Q ==13 : 81-87%
+    [(1377, 185), (1731, 202)], // Q ==14 : 87-93%
+    [(1412, 185), (1695, 202)], // Q ==15 : 93-99%
+];
+
+/// Selects the faster decoder, based on the compression ratio, which reflects the symbol
+/// distribution.
+///
+/// Skewed distributions have short prefix codes, where the X2 decoder can decode two symbols per
+/// lookup. For small inputs, the time to build the larger X2 table dominates.
+pub fn select_decoder(decompressed_size: usize, compressed_size: usize) -> HufDecoder {
+    assert!(decompressed_size > 0);
+    let quantized = if compressed_size >= decompressed_size {
+        15
+    } else {
+        compressed_size * 16 / decompressed_size
+    };
+    let d256 = (decompressed_size >> 8) as u64;
+    let (table_time, decode_256_time) = ALGO_TIME[quantized][0];
+    let time_x1 = table_time as u64 + decode_256_time as u64 * d256;
+    let (table_time, decode_256_time) = ALGO_TIME[quantized][1];
+    let mut time_x2 = table_time as u64 + decode_256_time as u64 * d256;
+    // small advantage to the algorithm using less memory, to reduce cache eviction
+    time_x2 += time_x2 >> 5;
+    if time_x2 < time_x1 {
+        HufDecoder::X2
+    } else {
+        HufDecoder::X1
+    }
+}
+
+/// Decompresses the output of `compress_1x_rev`, with the decoder chosen by `select_decoder`.
+pub fn decompress_1x(
+    codes: &[MinNode; 256],
+    input: &[u8],
+    output: &mut [u8],
+) -> Result<(), DecodeError> {
+    if output.is_empty() {
+        return Ok(());
+    }
+    match select_decoder(output.len(), input.len()) {
+        HufDecoder::X1 => decompress_1x_x1(&build_x1_table(codes), input, output),
+        HufDecoder::X2 => decompress_1x_x2(&build_x2_table(codes), input, output),
+    }
+}
+
+/// splits the input of `compress_4x` into its four streams, using the jump table
+fn split_streams(input: &[u8]) -> Result<[&[u8]; 4], DecodeError> {
+    // strict minimum : jump table + 1 byte per stream
+    if input.len() < HUF_JUMP_TABLE_SIZE + 4 {
+        return Err(DecodeError::InvalidJumpTable);
+    }
+    let length1 = u16::from_le_bytes([input[0], input[1]]) as usize;
+    let length2 = u16::from_le_bytes([input[2], input[3]]) as usize;
+    let length3 = u16::from_le_bytes([input[4], input[5]]) as usize;
+    let (stream1, rest) = input[HUF_JUMP_TABLE_SIZE..]
+        .split_at_checked(length1)
+        .ok_or(DecodeError::InvalidJumpTable)?;
+    let (stream2, rest) = rest
+        .split_at_checked(length2)
+        .ok_or(DecodeError::InvalidJumpTable)?;
+    let (stream3, stream4) = rest
+        .split_at_checked(length3)
+        .ok_or(DecodeError::InvalidJumpTable)?;
+    Ok([stream1, stream2, stream3, stream4])
+}
+
+/// splits the output into the four segments of the streams, the last one may be smaller
+fn split_segments(output: &mut [u8]) -> Result<[&mut [u8]; 4], DecodeError> {
+    let segment_size = output.len().div_ceil(4);
+    if segment_size * 3 > output.len() {
+        return Err(DecodeError::OutputSizeMismatch);
+    }
+    let (segment1, rest) = output.split_at_mut(segment_size);
+    let (segment2, rest) = rest.split_at_mut(segment_size);
+    let (segment3, segment4) = rest.split_at_mut(segment_size);
+    Ok([segment1, segment2, segment3, segment4])
+}
+
+/// Decompresses the output of `compress_4x` with a single symbol decoding table. The four streams
+/// are decoded interleaved.
+///
+/// output needs to be preallocated to the size of the uncompressed input.
+pub fn decompress_4x_x1(
+    table: &DecompressionTableX1,
+    input: &[u8],
+    output: &mut [u8],
+) -> Result<(), DecodeError> {
+    let streams = split_streams(input)?;
+    let mut segments = split_segments(output)?;
+    let mut bit_d = [
+        BitDStreamReverse::new(streams[0])?,
+        BitDStreamReverse::new(streams[1])?,
+        BitDStreamReverse::new(streams[2])?,
+        BitDStreamReverse::new(streams[3])?,
+    ];
+
+    // up to 16 symbols per loop (4 symbols per stream), the last segment is the smallest
+    let mut pos = 0;
+    let mut end_signal = true;
+    while end_signal && pos + 4 <= segments[3].len() {
+        for (segment, bit_d) in segments.iter_mut().zip(bit_d.iter_mut()) {
+            segment[pos] = huf_decode_symbol_x1(table, bit_d);
+        }
+        for (stream, bit_d) in streams.iter().zip(bit_d.iter_mut()) {
+            huf_reload_2(bit_d, stream);
+        }
+        for (segment, bit_d) in segments.iter_mut().zip(bit_d.iter_mut()) {
+            segment[pos + 1] = huf_decode_symbol_x1(table, bit_d);
+        }
+        for (segment, bit_d) in segments.iter_mut().zip(bit_d.iter_mut()) {
+            segment[pos + 2] = huf_decode_symbol_x1(table, bit_d);
+        }
+        for (stream, bit_d) in streams.iter().zip(bit_d.iter_mut()) {
+            huf_reload_2(bit_d, stream);
+        }
+        for (segment, bit_d) in segments.iter_mut().zip(bit_d.iter_mut()) {
+            segment[pos + 3] = huf_decode_symbol_x1(table, bit_d);
+        }
+        pos += 4;
+        for (stream, bit_d) in streams.iter().zip(bit_d.iter_mut()) {
+            end_signal &= bit_d.reload_stream(stream) == BitDstreamStatus::Unfinished;
+        }
+    }
+
+    // finish the streams one by one
+    for ((segment, bit_d), stream) in segments.iter_mut().zip(bit_d.iter_mut()).zip(streams) {
+        decode_stream_x1(table, bit_d, stream, &mut segment[pos..]);
+        if !bit_d.is_end_of_stream() {
+            return Err(DecodeError::OutputSizeMismatch);
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses the output of `compress_4x` with a double symbol decoding table. The four streams
+/// are decoded interleaved.
+///
+/// output needs to be preallocated to the size of the uncompressed input.
+pub fn decompress_4x_x2(
+    table: &DecompressionTableX2,
+    input: &[u8],
+    output: &mut [u8],
+) -> Result<(), DecodeError> {
+    let streams = split_streams(input)?;
+    let mut segments = split_segments(output)?;
+    let mut bit_d = [
+        BitDStreamReverse::new(streams[0])?,
+        BitDStreamReverse::new(streams[1])?,
+        BitDStreamReverse::new(streams[2])?,
+        BitDStreamReverse::new(streams[3])?,
+    ];
+
+    // up to 32 symbols per loop (8 symbols per stream), the number of symbols per stream varies
+    let mut pos = [0; 4];
+    let mut end_signal = true;
+    while end_signal
+        && segments
+            .iter()
+            .zip(pos.iter())
+            .all(|(segment, pos)| pos + 8 <= segment.len())
+    {
+        for ((segment, bit_d), pos) in segments.iter_mut().zip(bit_d.iter_mut()).zip(&mut pos) {
+            *pos += huf_decode_symbol_x2(table, bit_d, &mut segment[*pos..]);
+        }
+        for (stream, bit_d) in streams.iter().zip(bit_d.iter_mut()) {
+            huf_reload_2(bit_d, stream);
+        }
+        for ((segment, bit_d), pos) in segments.iter_mut().zip(bit_d.iter_mut()).zip(&mut pos) {
+            *pos += huf_decode_symbol_x2(table, bit_d, &mut segment[*pos..]);
+            *pos += huf_decode_symbol_x2(table, bit_d, &mut segment[*pos..]);
+        }
+        for (stream, bit_d) in streams.iter().zip(bit_d.iter_mut()) {
+            huf_reload_2(bit_d, stream);
+        }
+        for ((segment, bit_d), pos) in segments.iter_mut().zip(bit_d.iter_mut()).zip(&mut pos) {
+            *pos += huf_decode_symbol_x2(table, bit_d, &mut segment[*pos..]);
+        }
+        for (stream, bit_d) in streams.iter().zip(bit_d.iter_mut()) {
+            end_signal &= bit_d.reload_stream(stream) == BitDstreamStatus::Unfinished;
+        }
+    }
+
+    // finish the streams one by one
+    for (((segment, bit_d), stream), pos) in segments
+        .iter_mut()
+        .zip(bit_d.iter_mut())
+        .zip(streams)
+        .zip(pos)
+    {
+        decode_stream_x2(table, bit_d, stream, &mut segment[pos..]);
+        if !bit_d.is_end_of_stream() {
+            return Err(DecodeError::OutputSizeMismatch);
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses the output of `compress_4x`, with the decoder chosen by `select_decoder`.
+///
+/// Like `decompress_1x`, an empty output is decompressed without reading the input.
+pub fn decompress_4x(
+    codes: &[MinNode; 256],
+    input: &[u8],
+    output: &mut [u8],
+) -> Result<(), DecodeError> {
+    if output.is_empty() {
+        return Ok(());
+    }
+    match select_decoder(output.len(), input.len()) {
+        HufDecoder::X1 => decompress_4x_x1(&build_x1_table(codes), input, output),
+        HufDecoder::X2 => decompress_4x_x2(&build_x2_table(codes), input, output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_tree_fast;
+    use crate::compress::{
+        compress_1x_rev, compress_4x, huf_compress_1x_bound, huf_compress_4x_bound,
+    };
+    use crate::count_simple;
+    use crate::tree::build_tree::tree_to_table;
+
+    fn inverse(test_data: &[u8]) {
+        let counts = count_simple(test_data);
+        let tree = build_tree_fast(&counts);
+        let codes = tree_to_table(&tree);
+        let mut compressed = vec![0; huf_compress_1x_bound(&codes, test_data.len())];
+        let compressed_size = compress_1x_rev(&codes, test_data, &mut compressed);
+
+        let table = build_x1_table(&codes);
+        let mut decompressed = vec![0; test_data.len()];
+        decompress_1x_x1(&table, &compressed[..compressed_size], &mut decompressed).unwrap();
+        assert_eq!(decompressed, test_data);
+
+        // the stream doesn't contain the decompressed size, it can't be decoded to a larger output
+        let mut decompressed = vec![0; test_data.len() + 1];
+        assert!(
+            decompress_1x_x1(&table, &compressed[..compressed_size], &mut decompressed).is_err()
+        );
+    }
+
+    fn inverse_x2(test_data: &[u8]) {
+        let counts = count_simple(test_data);
+        let tree = build_tree_fast(&counts);
+        let codes = tree_to_table(&tree);
+        let mut compressed = vec![0; huf_compress_1x_bound(&codes, test_data.len())];
+        let compressed_size = compress_1x_rev(&codes, test_data, &mut compressed);
+        let compressed = &compressed[..compressed_size];
+
+        let table = build_x2_table(&codes);
+        let mut decompressed = vec![0; test_data.len()];
+        decompress_1x_x2(&table, compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, test_data);
+
+        let mut decompressed = vec![0; test_data.len()];
+        decompress_1x(&codes, compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, test_data);
+    }
+
+    fn inverse_4x(test_data: &[u8]) -> Vec<u8> {
+        let counts = count_simple(test_data);
+        let tree = build_tree_fast(&counts);
+        let codes = tree_to_table(&tree);
+        let mut compressed = vec![0; huf_compress_4x_bound(&codes, test_data.len())];
+        let compressed_size = compress_4x(&codes, test_data, &mut compressed).unwrap();
+        compressed.truncate(compressed_size);
+
+        let mut decompressed = vec![0; test_data.len()];
+        decompress_4x_x1(&build_x1_table(&codes), &compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, test_data);
+
+        let mut decompressed = vec![0; test_data.len()];
+        decompress_4x_x2(&build_x2_table(&codes), &compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, test_data);
+
+        let mut decompressed = vec![0; test_data.len()];
+        decompress_4x(&codes, &compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, test_data);
+        compressed
+    }
+
+    #[test]
+    fn test_decompress_empty() {
+        let tree = build_tree_fast(&count_simple(b"aaabbc"));
+        let codes = tree_to_table(&tree);
+        assert!(decompress_1x(&codes, &[], &mut []).is_ok());
+        assert!(decompress_4x(&codes, &[], &mut []).is_ok());
+    }
+
+    #[test]
+    fn test_decompress_4x_test_data() {
+        inverse_4x(include_bytes!("../../test_data/compression_66k_JSON.txt"));
+        inverse_4x(include_bytes!("../../test_data/compression_65k.txt"));
+        inverse_4x(include_bytes!("../../test_data/compression_34k.txt"));
+        inverse_4x(include_bytes!("../../test_data/compression_1k.txt"));
+        inverse_4x(include_bytes!("../../test_data/v4_uuids_19k.txt"));
+        inverse_4x(include_bytes!("../../test_data/v4_uuids_93k.txt"));
+        inverse_4x(include_bytes!("../../test_data/logo.jpg"));
+    }
+
+    #[test]
+    fn test_decompress_4x_multi_sizes() {
+        for num_elems in 12..200 {
+            let test_data: Vec<u8> = (0..num_elems).map(|i| b"aaaabbbccd"[i % 10]).collect();
+            inverse_4x(&test_data);
+        }
+        let counts = count_simple(&[1, 2]);
+        let codes = tree_to_table(&build_tree_fast(&counts));
+        assert_eq!(compress_4x(&codes, &[1, 2, 1, 2], &mut [0; 64]), None);
+
+        // dst too small for the worst case, even if the streams would fit
+        let test_data = [1, 2].repeat(100);
+        let bound = huf_compress_4x_bound(&codes, test_data.len());
+        assert_eq!(
+            compress_4x(&codes, &test_data, &mut vec![0; bound - 1]),
+            None
+        );
+        assert!(compress_4x(&codes, &test_data, &mut vec![0; bound]).is_some());
+    }
+
+    #[test]
+    fn test_decompress_4x_corrupted_no_panic() {
+        let compressed = inverse_4x(include_bytes!("../../test_data/compression_1k.txt"));
+        let counts = count_simple(include_bytes!("../../test_data/compression_1k.txt"));
+        let codes = tree_to_table(&build_tree_fast(&counts));
+        let table_x1 = build_x1_table(&codes);
+        let table_x2 = build_x2_table(&codes);
+
+        let mut decompressed = vec![0; 1000];
+        for len in 0..compressed.len() {
+            assert!(decompress_4x_x1(&table_x1, &compressed[..len], &mut decompressed).is_err());
+            let _ = decompress_4x_x2(&table_x2, &compressed[..len], &mut decompressed);
+        }
+        for pos in 0..HUF_JUMP_TABLE_SIZE {
+            for bit in 0..8 {
+                let mut corrupted = compressed.clone();
+                corrupted[pos] ^= 1 << bit;
+                assert!(decompress_4x_x1(&table_x1, &corrupted, &mut decompressed).is_err());
+                let _ = decompress_4x_x2(&table_x2, &corrupted, &mut decompressed);
+            }
+        }
+        for out_len in 0..20 {
+            let mut decompressed = vec![0; out_len];
+            assert!(decompress_4x_x1(&table_x1, &compressed, &mut decompressed).is_err());
+            let _ = decompress_4x_x2(&table_x2, &compressed, &mut decompressed);
+        }
+    }
+
+    #[test]
+    fn test_decompress_x2_test_data() {
+        inverse_x2(include_bytes!("../../test_data/compression_66k_JSON.txt"));
+        inverse_x2(include_bytes!("../../test_data/compression_65k.txt"));
+        inverse_x2(include_bytes!("../../test_data/compression_34k.txt"));
+        inverse_x2(include_bytes!("../../test_data/compression_1k.txt"));
+        inverse_x2(include_bytes!("../../test_data/v4_uuids_19k.txt"));
+        inverse_x2(include_bytes!("../../test_data/v4_uuids_93k.txt"));
+        inverse_x2(include_bytes!("../../test_data/logo.jpg"));
+    }
+
+    #[test]
+    fn test_decompress_x2_multi_sizes() {
+        for num_elems in 1..100 {
+            let test_data: Vec<u8> = (0..num_elems).map(|i| b"aaaabbbccd"[i % 10]).collect();
+            inverse_x2(&test_data);
+            let test_data: Vec<u8> = (0..num_elems).map(|i| b"abcdefghij"[i % 10]).collect();
+            inverse_x2(&test_data);
+        }
+        inverse_x2(&[7; 100]);
+    }
+
+    #[test]
+    fn test_select_decoder() {
+        // skewed distribution, large input
+        assert_eq!(select_decoder(64 * 1024, 16 * 1024), HufDecoder::X2);
+        // almost incompressible
+        assert_eq!(select_decoder(64 * 1024, 63 * 1024), HufDecoder::X1);
+        // small input, table creation dominates
+        assert_eq!(select_decoder(100, 30), HufDecoder::X1);
+    }
+
+    #[test]
+    fn test_decompress_x1_test_data() {
+        inverse(include_bytes!("../../test_data/compression_66k_JSON.txt"));
+        inverse(include_bytes!("../../test_data/compression_65k.txt"));
+        inverse(include_bytes!("../../test_data/compression_34k.txt"));
+        inverse(include_bytes!("../../test_data/compression_1k.txt"));
+        inverse(include_bytes!("../../test_data/v4_uuids_19k.txt"));
+        inverse(include_bytes!("../../test_data/v4_uuids_93k.txt"));
+        inverse(include_bytes!("../../test_data/logo.jpg"));
+    }
+
+    #[test]
+    fn test_decompress_x1_multi_sizes() {
+        for num_elems in 1..100 {
+            let test_data: Vec<u8> = (0..num_elems).map(|i| b"aaaabbbccd"[i % 10]).collect();
+            inverse(&test_data);
+        }
+    }
+
+    #[test]
+    fn test_decompress_x1_single_symbol() {
+        inverse(&[7; 100]);
+    }
+
+    #[test]
+    fn test_decompress_x1_corrupted_no_panic() {
+        const TEST_DATA: &[u8] = include_bytes!("../../test_data/compression_1k.txt");
+        let counts = count_simple(TEST_DATA);
+        let tree = build_tree_fast(&counts);
+        let codes = tree_to_table(&tree);
+        let mut compressed = vec![0; huf_compress_1x_bound(&codes, TEST_DATA.len())];
+        let compressed_size = compress_1x_rev(&codes, TEST_DATA, &mut compressed);
+        let table = build_x1_table(&codes);
+
+        let mut decompressed = vec![0; TEST_DATA.len()];
+        for len in 0..compressed_size {
+            assert!(decompress_1x_x1(&table, &compressed[..len], &mut decompressed).is_err());
+        }
+    }
 }
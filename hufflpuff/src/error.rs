@@ -0,0 +1,36 @@
+use bitstream::BitstreamError;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("invalid bitstream: {0}")]
+    InvalidBitstream(#[from] BitstreamError),
+    #[error("decoded stream does not match the output size")]
+    OutputSizeMismatch,
+    #[error("jump table of the four streams does not match the input")]
+    InvalidJumpTable,
+    #[error("input is too small to contain the huffman table header")]
+    HeaderTooShort,
+    #[error("invalid fse compressed weights: {0}")]
+    InvalidWeightsStream(#[from] ans_flex::DecodeError),
+    #[error("corrupted huffman weights: {0}")]
+    CorruptedWeights(&'static str),
+    #[error("input is too small to contain a block header")]
+    BlockTooShort,
+    #[error("unknown block type `{0}`")]
+    UnknownBlockType(u8),
+    #[error("payload size does not match the block")]
+    InvalidPayloadSize,
+    #[error("invalid fse block: {0}")]
+    InvalidFseBlock(ans_flex::DecodeError),
+    #[error("decompressed size `{0}` of the block exceeds the max block size")]
+    BlockTooLarge(usize),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CompressError {
+    #[error("prefix codes are incomplete, e.g. only a single symbol is present")]
+    IncompleteCode,
+    #[error("too many symbols for the uncompressed weights header")]
+    TooManySymbols,
+}
@@ -1,10 +1,14 @@
+pub mod block;
 pub mod compress;
 pub mod decompress;
+pub mod error;
 pub mod tree;
+pub mod weights;
 use crate::tree::tree_node::Node;
 use crate::tree::Tree;
 use std::collections::BinaryHeap;
 
+pub use crate::error::{CompressError, DecodeError};
 pub use crate::tree::build_tree_fast;
 
 pub const MAX_SYMBOL_VALUE: u32 = u8::MAX as u32;
@@ -21,6 +25,9 @@ pub const HUF_TABLELOG_MAX: u32 = 12;
 pub const HUF_TABLELOG_ABSOLUTEMAX: u32 = 15;
 pub const HUF_TABLELOG_DEFAULT: u32 = 11;
 
+/// Size of the jump table of the four streams of `compress::compress_4x`
+pub const HUF_JUMP_TABLE_SIZE: usize = 6;
+
 pub fn huf_blockbound(size: usize) -> usize {
     size + (size >> 8) + 8
 }
@@ -131,7 +138,7 @@ pub fn get_max_value(counts: &[usize; 256]) -> usize {
 #[cfg(test)]
 mod tests {
 
-    use crate::compress::compress_1x_rev;
+    use crate::compress::{compress_1x_rev, huf_compress_1x_bound};
     use crate::tree::build_tree::test_prefix_property;
     use crate::tree::build_tree::tree_to_table;
     use crate::tree::build_tree_fast;
@@ -162,7 +169,7 @@ mod tests {
         let tree = build_tree_fast(&counts);
 
         let table = tree_to_table(&tree);
-        let mut out = vec![0; tree.estimate_compressed_size() + 16];
+        let mut out = vec![0; huf_compress_1x_bound(&table, test_data.len())];
         compress_1x_rev(&table, &test_data, &mut out);
 
         // compress_1x_rev(table: &[MinNode], input: &[u8], dst: &mut [u8]) {
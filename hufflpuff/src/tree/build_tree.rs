@@ -131,11 +131,30 @@ pub fn build_tree_fast_1(counts: &[usize; 256]) -> Tree {
 
 /// converts the tree into a table with prefixes for each symbol
 pub fn tree_to_table(tree: &Tree) -> [MinNode; 256] {
+    let mut symbol_lookup_table = [MinNode::default(); u8::MAX as usize + 1];
+    for node in tree.get_symbol_nodes() {
+        if let Some(byte) = node.symbol {
+            symbol_lookup_table[byte as usize].number_bits = node.number_bits;
+        }
+    }
+    assign_prefix_codes(&mut symbol_lookup_table);
+
+    // dbg!(&symbol_lookup_table[..10]);
+
+    symbol_lookup_table
+}
+
+/// assigns the prefix codes, given the number of bits of each symbol
+///
+/// Values within a depth are assigned in symbol order, so the codes can be rebuilt from the number
+/// of bits per symbol alone, see `read_huffman_table`.
+pub(crate) fn assign_prefix_codes(table: &mut [MinNode; 256]) {
     let mut num_nodes_per_depth = [0_u16; 16];
     let mut node_values_per_depth = [0_u16; 16];
-    for node in tree.get_symbol_nodes() {
+    for node in table.iter() {
         num_nodes_per_depth[node.number_bits as usize] += 1;
     }
+    num_nodes_per_depth[0] = 0;
 
     let max_depth = num_nodes_per_depth
         .iter()
@@ -143,7 +162,9 @@ pub fn tree_to_table(tree: &Tree) -> [MinNode; 256] {
         .filter(|(_depth, num_nodes)| **num_nodes != 0)
         .map(|(depth, _num_nodes)| depth)
         .next_back()
-        .unwrap();
+        .unwrap_or_default();
+
+    // dbg!(&num_nodes_per_depth[..10]);
 
     // assign start values, starting at end of tree
     // this assignment is ported from FiniteStateEntropy (huf_compress.c)
@@ -159,20 +180,10 @@ pub fn tree_to_table(tree: &Tree) -> [MinNode; 256] {
         min >>= 1;
     }
 
-    let mut symbol_lookup_table = [MinNode::default(); u8::MAX as usize + 1];
-    for node in tree.get_symbol_nodes() {
-        if let Some(byte) = node.symbol {
-            symbol_lookup_table[byte as usize].val =
-                node_values_per_depth[node.number_bits as usize];
-            symbol_lookup_table[byte as usize].number_bits = node.number_bits;
-            node_values_per_depth[node.number_bits as usize] += 1;
-        }
+    for node in table.iter_mut().filter(|node| node.number_bits != 0) {
+        node.val = node_values_per_depth[node.number_bits as usize];
+        node_values_per_depth[node.number_bits as usize] += 1;
     }
-
-    // dbg!(&num_nodes_per_depth[..10]);
-    // dbg!(&symbol_lookup_table[..10]);
-
-    symbol_lookup_table
 }
 
 /// Limits  depth of the tree
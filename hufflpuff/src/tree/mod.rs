@@ -1,5 +1,6 @@
 pub mod build_tree;
 pub mod render_tree;
+#[allow(clippy::module_inception)]
 mod tree;
 pub(crate) mod tree_node;
 pub use build_tree::build_tree_fast;
@@ -56,7 +56,7 @@ impl<'a> dot::Labeller<'a> for Tree {
 impl<'a> dot::GraphWalk<'a> for Tree {
     type Node = Node;
     type Edge = Edge;
-    fn nodes(&self) -> dot::Nodes<Node> {
+    fn nodes(&self) -> dot::Nodes<'_, Node> {
         self.nodes
             .iter()
             .filter(|el| el.count != 0)
@@ -64,7 +64,7 @@ impl<'a> dot::GraphWalk<'a> for Tree {
             .collect()
     }
 
-    fn edges(&self) -> dot::Edges<Edge> {
+    fn edges(&self) -> dot::Edges<'_, Edge> {
         let mut edges = vec![];
         for (i, node) in self.nodes.iter().enumerate() {
             if let Some(left) = node.left {
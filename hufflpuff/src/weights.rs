@@ -0,0 +1,308 @@
+/*!
+Serialization of the prefix codes, in the format of the zstd huffman tree description.
+
+The codes are stored as weights per symbol, `weight = max_bits + 1 - number_bits`, or 0 if the
+symbol is not present. The weight of the last symbol is not stored, it is implied by the total,
+which has to be a power of 2.
+
+| Header byte | Description                                                                 |
+|-------------|-----------------------------------------------------------------------------|
+| < 128       | size of the following FSE compressed weights                               |
+| >= 128      | `header byte - 127` weights follow uncompressed, 4 bits each, high bits first |
+
+*/
+
+use crate::error::{CompressError, DecodeError};
+use crate::tree::build_tree::assign_prefix_codes;
+use crate::tree::tree_node::MinNode;
+use crate::HUF_TABLELOG_MAX;
+use ans_flex::compress::fse_compress;
+use ans_flex::decompress::fse_decompress_stream;
+use ans_flex::table::{build_compression_table, build_decompression_table, check_norm_counts};
+use bitstream::highbit_pos;
+use common::{
+    count_simple, fse_ncount_write_bound, fse_optimal_table_log, fse_read_n_count,
+    fse_write_n_count, get_max_symbol_value, get_normalized_counts,
+};
+
+/// Max table_log of the FSE compressed weights
+pub const MAX_FSE_TABLELOG_FOR_HUFF_HEADER: u32 = 6;
+
+/// Writes the prefix codes of `tree_to_table` as weights.
+///
+/// The weights are FSE compressed if that's smaller, else they are written with 4 bits each,
+/// which is limited to 128 symbols.
+pub fn write_huffman_table(codes: &[MinNode; 256]) -> Result<Vec<u8>, CompressError> {
+    let max_symbol_value = codes
+        .iter()
+        .rposition(|node| node.number_bits != 0)
+        .ok_or(CompressError::IncompleteCode)?;
+    let max_bits = codes.iter().map(|node| node.number_bits).max().unwrap() as u32;
+
+    // the weight of the last symbol is implied by the total, which requires complete codes
+    let weight_total: u32 = codes
+        .iter()
+        .filter(|node| node.number_bits != 0)
+        .map(|node| 1 << (max_bits - node.number_bits as u32))
+        .sum();
+    if weight_total != 1 << max_bits {
+        return Err(CompressError::IncompleteCode);
+    }
+
+    let weights: Vec<u8> = codes[..max_symbol_value]
+        .iter()
+        .map(|node| {
+            if node.number_bits == 0 {
+                0
+            } else {
+                (max_bits + 1 - node.number_bits as u32) as u8
+            }
+        })
+        .collect();
+
+    if let Some(compressed) = compress_weights(&weights) {
+        if compressed.len() > 1 && compressed.len() < max_symbol_value / 2 {
+            let mut out = Vec::with_capacity(compressed.len() + 1);
+            out.push(compressed.len() as u8);
+            out.extend_from_slice(&compressed);
+            return Ok(out);
+        }
+    }
+
+    // write raw values as 4-bits (max : 15)
+    if max_symbol_value > 128 {
+        return Err(CompressError::TooManySymbols);
+    }
+    let mut out = Vec::with_capacity(max_symbol_value / 2 + 2);
+    out.push((128 + (max_symbol_value - 1)) as u8);
+    for pair in weights.chunks(2) {
+        out.push(pair[0] << 4 | pair.get(1).cloned().unwrap_or_default());
+    }
+    Ok(out)
+}
+
+/// FSE compresses the weights, returns None if they are not compressible.
+fn compress_weights(weights: &[u8]) -> Option<Vec<u8>> {
+    if weights.len() <= 2 {
+        return None;
+    }
+    let counts = count_simple(weights);
+    let max_count = *counts.iter().max().unwrap() as usize;
+    if max_count == weights.len() || max_count == 1 {
+        // rle or each symbol present maximum once
+        return None;
+    }
+    let max_symbol_value = get_max_symbol_value(&counts);
+    let table_log = fse_optimal_table_log(
+        MAX_FSE_TABLELOG_FOR_HUFF_HEADER,
+        weights.len(),
+        max_symbol_value,
+    );
+    let mut norm_counts =
+        get_normalized_counts(&counts, table_log, weights.len(), max_symbol_value).ok()?;
+    // like zstd, the weights are normalized without low probability counts
+    for norm_count in norm_counts.iter_mut().filter(|count| **count == -1) {
+        *norm_count = 1;
+    }
+
+    let mut out = vec![0; fse_ncount_write_bound(max_symbol_value, table_log) as usize];
+    let header_size = fse_write_n_count(&mut out, &norm_counts, max_symbol_value, table_log)
+        .expect("normalized counts should be valid");
+    out.truncate(header_size);
+
+    let comp_table = build_compression_table(&norm_counts, table_log, max_symbol_value);
+    let stream = fse_compress(weights, &comp_table, table_log);
+    out.extend_from_slice(stream.get_compressed_data());
+    Some(out)
+}
+
+/// Reads the weights written by `write_huffman_table` and rebuilds the prefix codes.
+///
+/// Returns the prefix codes and the number of bytes read.
+pub fn read_huffman_table(input: &[u8]) -> Result<([MinNode; 256], usize), DecodeError> {
+    let header = *input.first().ok_or(DecodeError::HeaderTooShort)? as usize;
+    let mut weights = [0_u8; 256];
+
+    let (num_weights, header_size) = if header >= 128 {
+        // raw 4-bit weights
+        let num_weights = header - 127;
+        let size = num_weights.div_ceil(2);
+        let data = input.get(1..1 + size).ok_or(DecodeError::HeaderTooShort)?;
+        for (pair, byte) in weights.chunks_exact_mut(2).zip(data) {
+            pair[0] = byte >> 4;
+            pair[1] = byte & 15;
+        }
+        (num_weights, size + 1)
+    } else {
+        let data = input
+            .get(1..1 + header)
+            .ok_or(DecodeError::HeaderTooShort)?;
+        let num_weights = decompress_weights(data, &mut weights[..255])?;
+        (num_weights, header + 1)
+    };
+
+    // collect weight stats
+    let mut rank_stats = [0_u32; HUF_TABLELOG_MAX as usize + 1];
+    let mut weight_total = 0_u32;
+    for &weight in &weights[..num_weights] {
+        if weight as u32 > HUF_TABLELOG_MAX {
+            return Err(DecodeError::CorruptedWeights("weight too large"));
+        }
+        rank_stats[weight as usize] += 1;
+        weight_total += (1 << weight) >> 1;
+    }
+    if weight_total == 0 {
+        return Err(DecodeError::CorruptedWeights("all weights are zero"));
+    }
+
+    // get last non-null symbol weight (implied, total must be 2^n)
+    let max_bits = highbit_pos(weight_total) + 1;
+    if max_bits > HUF_TABLELOG_MAX {
+        return Err(DecodeError::CorruptedWeights("table_log too large"));
+    }
+    let rest = (1 << max_bits) - weight_total;
+    if 1 << highbit_pos(rest) != rest {
+        // last value must be a clean power of 2
+        return Err(DecodeError::CorruptedWeights(
+            "implied weight is not a power of 2",
+        ));
+    }
+    let last_weight = highbit_pos(rest) + 1;
+    weights[num_weights] = last_weight as u8;
+    rank_stats[last_weight as usize] += 1;
+
+    // by construction : at least 2 elts of rank 1, must be even
+    if rank_stats[1] < 2 || rank_stats[1] & 1 == 1 {
+        return Err(DecodeError::CorruptedWeights(
+            "invalid number of longest codes",
+        ));
+    }
+
+    let mut codes = [MinNode::default(); 256];
+    for (node, &weight) in codes.iter_mut().zip(&weights[..=num_weights]) {
+        if weight != 0 {
+            node.number_bits = (max_bits + 1 - weight as u32) as u8;
+        }
+    }
+    assign_prefix_codes(&mut codes);
+
+    Ok((codes, header_size))
+}
+
+/// Decompresses the FSE compressed weights, returns the number of weights.
+fn decompress_weights(data: &[u8], weights: &mut [u8]) -> Result<usize, DecodeError> {
+    let mut norm_counts = [0_i16; 256];
+    let mut max_symbol_value = HUF_TABLELOG_MAX;
+    let mut table_log = 0;
+    let ncount_size = fse_read_n_count(
+        data,
+        &mut norm_counts,
+        &mut max_symbol_value,
+        &mut table_log,
+    )
+    .map_err(ans_flex::DecodeError::from)?;
+    if table_log > MAX_FSE_TABLELOG_FOR_HUFF_HEADER {
+        return Err(DecodeError::CorruptedWeights("table_log too large"));
+    }
+    check_norm_counts(&norm_counts, table_log, max_symbol_value)
+        .map_err(ans_flex::DecodeError::from)?;
+
+    let table = build_decompression_table(&norm_counts, table_log, max_symbol_value);
+    Ok(fse_decompress_stream(
+        weights,
+        &data[ncount_size..],
+        &table,
+        table_log,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build_tree_fast;
+    use crate::compress::{compress_1x_rev, huf_compress_1x_bound};
+    use crate::count_simple;
+    use crate::decompress::decompress_1x;
+    use crate::tree::build_tree::tree_to_table;
+
+    fn inverse(test_data: &[u8]) -> Vec<u8> {
+        let counts = count_simple(test_data);
+        let tree = build_tree_fast(&counts);
+        let codes = tree_to_table(&tree);
+
+        let header = write_huffman_table(&codes).unwrap();
+        let (read_codes, bytes_read) = read_huffman_table(&header).unwrap();
+        assert_eq!(bytes_read, header.len());
+        for (node, read_node) in codes.iter().zip(read_codes.iter()) {
+            assert_eq!(node.number_bits, read_node.number_bits);
+            if node.number_bits != 0 {
+                assert_eq!(node.val, read_node.val);
+            }
+        }
+
+        // the stream can be decoded with the codes read from the header
+        let mut compressed = vec![0; huf_compress_1x_bound(&codes, test_data.len())];
+        let compressed_size = compress_1x_rev(&codes, test_data, &mut compressed);
+        let mut decompressed = vec![0; test_data.len()];
+        decompress_1x(
+            &read_codes,
+            &compressed[..compressed_size],
+            &mut decompressed,
+        )
+        .unwrap();
+        assert_eq!(decompressed, test_data);
+        header
+    }
+
+    #[test]
+    fn test_weights_zstd_format_example() {
+        // example of the zstd format documentation, with weights 4, 3, 2, 0, 1 and the implied 1
+        let src: Vec<u8> = vec![
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 4, 5,
+        ];
+        let header = inverse(&src);
+        assert_eq!(header, vec![128 + 4, 0x43, 0x20, 0x10]);
+    }
+
+    #[test]
+    fn test_weights_test_data() {
+        let header = inverse(include_bytes!("../../test_data/compression_66k_JSON.txt"));
+        // fse compressed
+        assert!(header[0] < 128);
+        inverse(include_bytes!("../../test_data/compression_65k.txt"));
+        inverse(include_bytes!("../../test_data/compression_34k.txt"));
+        inverse(include_bytes!("../../test_data/compression_1k.txt"));
+        inverse(include_bytes!("../../test_data/v4_uuids_19k.txt"));
+        inverse(include_bytes!("../../test_data/v4_uuids_93k.txt"));
+        inverse(include_bytes!("../../test_data/logo.jpg"));
+    }
+
+    #[test]
+    fn test_weights_incomplete_code() {
+        let counts = count_simple(&[3; 10]);
+        let codes = tree_to_table(&build_tree_fast(&counts));
+        assert_eq!(
+            write_huffman_table(&codes),
+            Err(CompressError::IncompleteCode)
+        );
+    }
+
+    #[test]
+    fn test_weights_corrupted_no_panic() {
+        let counts = count_simple(include_bytes!("../../test_data/compression_66k_JSON.txt"));
+        let codes = tree_to_table(&build_tree_fast(&counts));
+        let header = write_huffman_table(&codes).unwrap();
+        for len in 0..header.len() {
+            assert!(read_huffman_table(&header[..len]).is_err());
+        }
+        for pos in 0..header.len() {
+            for bit in 0..8 {
+                let mut corrupted = header.clone();
+                corrupted[pos] ^= 1 << bit;
+                let _ = read_huffman_table(&corrupted);
+            }
+        }
+        assert!(read_huffman_table(&[]).is_err());
+        assert!(read_huffman_table(&[255]).is_err());
+    }
+}
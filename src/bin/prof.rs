@@ -26,10 +26,11 @@ fn main() {
     dbg!(counts.iter().filter(|cnt| **cnt == 0).count());
     dbg!(counts.iter().filter(|cnt| **cnt != 0).count());
     //dbg!(counts);
-    let out = compress(test_data);
+    let out = compress(test_data).unwrap();
     let max_symbol_value = get_max_symbol_value(&counts);
     let table_log = fse_optimal_table_log(FSE_DEFAULT_TABLELOG, test_data.len(), max_symbol_value);
-    let norm_counts = get_normalized_counts(&counts, table_log, test_data.len(), max_symbol_value);
+    let norm_counts =
+        get_normalized_counts(&counts, table_log, test_data.len(), max_symbol_value).unwrap();
     for _ in 0..10 {
         // dbg!(&out.get_compressed_data());
         // dbg!("out.get_compressed_data().len() {:?}", out.get_compressed_data().len());
@@ -39,7 +40,8 @@ fn main() {
             table_log,
             test_data.len(),
             max_symbol_value,
-        );
+        )
+        .unwrap();
 
         len += decompressed.len();
     }
@@ -0,0 +1,582 @@
+use crate::compress::{
+    fse_compress_4_states_into, fse_compress_4_streams_into, fse_compress_into, fse_compressbound,
+    fse_compressbound_4_streams,
+};
+use crate::decompress::{fse_decompress, fse_decompress_4_states, fse_decompress_4_streams};
+use crate::error::{CompressError, DecodeError};
+use crate::options::CompressOptions;
+use crate::table::{
+    build_compression_table_into, build_decompression_table_into, check_norm_counts,
+    estimate_stream_bits, CompressionTable, CompressionWorkspace, DecompressionTable,
+};
+use crate::{normalize, select_table_log};
+use common::fse_ncount_write_bound;
+use common::fse_read_n_count;
+use common::fse_write_n_count;
+use common::FSE_MAX_SYMBOL_VALUE;
+use common::{count, get_normalized_counts};
+
+/// The type of a block, stored as tag in the frame header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum BlockType {
+    /// Uncompressed data
+    Raw = 0,
+    /// A single symbol, repeated for the size of the block
+    Rle = 1,
+    /// FSE compressed data
+    Fse = 2,
+    /// FSE compressed data, with the table of the previous `BlockType::Fse` block
+    Repeat = 3,
+    /// Like `BlockType::Fse`, with a bitstream of 4 interleaved states, see
+    /// `fse_compress_4_states`
+    Fse4States = 4,
+    /// Like `BlockType::Repeat`, with a bitstream of 4 interleaved states
+    Repeat4States = 5,
+    /// Like `BlockType::Fse`, with 4 independent bitstreams, see `fse_compress_4_streams`
+    Fse4Streams = 6,
+    /// Like `BlockType::Repeat`, with 4 independent bitstreams
+    Repeat4Streams = 7,
+}
+
+impl BlockType {
+    pub fn from_tag(tag: u8) -> Result<Self, DecodeError> {
+        match tag {
+            0 => Ok(BlockType::Raw),
+            1 => Ok(BlockType::Rle),
+            2 => Ok(BlockType::Fse),
+            3 => Ok(BlockType::Repeat),
+            4 => Ok(BlockType::Fse4States),
+            5 => Ok(BlockType::Repeat4States),
+            6 => Ok(BlockType::Fse4Streams),
+            7 => Ok(BlockType::Repeat4Streams),
+            _ => Err(DecodeError::UnknownBlockType(tag)),
+        }
+    }
+}
+
+/// Output of `compress_or_fallback`
+#[derive(Debug, PartialEq, Eq)]
+pub enum Block<'a> {
+    /// The input is stored uncompressed, since it is not compressible
+    Raw(&'a [u8]),
+    /// The input consists of a single symbol, repeated `len` times
+    Rle { symbol: u8, len: usize },
+    /// FSE compressed input of size `len`, the normalized counts header followed by the bitstream
+    Fse { data: Vec<u8>, len: usize },
+    /// Like `Block::Fse`, with a bitstream of 4 interleaved states
+    Fse4States { data: Vec<u8>, len: usize },
+    /// Like `Block::Fse`, with 4 independent bitstreams
+    Fse4Streams { data: Vec<u8>, len: usize },
+}
+
+impl<'a> Block<'a> {
+    pub fn block_type(&self) -> BlockType {
+        match self {
+            Block::Raw(_) => BlockType::Raw,
+            Block::Rle { .. } => BlockType::Rle,
+            Block::Fse { .. } => BlockType::Fse,
+            Block::Fse4States { .. } => BlockType::Fse4States,
+            Block::Fse4Streams { .. } => BlockType::Fse4Streams,
+        }
+    }
+
+    /// Size of the input before compression
+    pub fn decompressed_size(&self) -> usize {
+        match self {
+            Block::Raw(data) => data.len(),
+            Block::Rle { len, .. } => *len,
+            Block::Fse { len, .. }
+            | Block::Fse4States { len, .. }
+            | Block::Fse4Streams { len, .. } => *len,
+        }
+    }
+
+    /// The bytes stored for this block, the rle symbol in case of `Block::Rle`
+    pub fn payload(&self) -> &[u8] {
+        match self {
+            Block::Raw(data) => data,
+            Block::Rle { symbol, .. } => std::slice::from_ref(symbol),
+            Block::Fse { data, .. }
+            | Block::Fse4States { data, .. }
+            | Block::Fse4Streams { data, .. } => data,
+        }
+    }
+
+    pub fn decompress(&self) -> Result<Vec<u8>, DecodeError> {
+        decompress_block(self.block_type(), self.payload(), self.decompressed_size())
+    }
+}
+
+/// Compresses input with FSE, but never fails.
+///
+/// Input which is not suited for FSE is emitted as a raw or rle block instead.
+/// A raw block is also emitted, if the FSE block would be larger than the input.
+pub fn compress_or_fallback(input: &[u8]) -> Block<'_> {
+    compress_or_fallback_with_options(input, &CompressOptions::default())
+}
+
+/// Like `compress_or_fallback`, with the table_log and incompressibility threshold of `options`.
+pub fn compress_or_fallback_with_options<'a>(
+    input: &'a [u8],
+    options: &CompressOptions,
+) -> Block<'a> {
+    match compress_fse_block(input, options) {
+        Ok((BlockType::Fse4States, data)) if data.len() < input.len() => Block::Fse4States {
+            data,
+            len: input.len(),
+        },
+        Ok((BlockType::Fse4Streams, data)) if data.len() < input.len() => Block::Fse4Streams {
+            data,
+            len: input.len(),
+        },
+        Ok((_, data)) if data.len() < input.len() => Block::Fse {
+            data,
+            len: input.len(),
+        },
+        Ok(_) => Block::Raw(input),
+        Err(CompressError::Rle) => Block::Rle {
+            symbol: input.first().cloned().unwrap_or_default(),
+            len: input.len(),
+        },
+        Err(CompressError::NotCompressible)
+        | Err(CompressError::NotCompressibleEnough)
+        | Err(CompressError::SymbolTooLarge)
+        | Err(CompressError::OutputTooSmall)
+        | Err(CompressError::InvalidNormCounts(_))
+        | Err(CompressError::ZeroProbabilitySymbol(_))
+        | Err(CompressError::InputTooLarge(_)) => Block::Raw(input),
+    }
+}
+
+/// Compresses input into the normalized counts header, written with `fse_write_n_count`, followed
+/// by the bitstream.
+///
+/// Returns the block type of the bitstream layout, see `Bitstream`.
+fn compress_fse_block(
+    input: &[u8],
+    options: &CompressOptions,
+) -> Result<(BlockType, Vec<u8>), CompressError> {
+    let mut out = vec![];
+    let block_type = compress_fse_block_into(
+        input,
+        options,
+        &mut CompressionTable::default(),
+        &mut CompressionWorkspace::default(),
+        &mut out,
+    )?;
+    Ok((block_type, out))
+}
+
+/// Like `compress_fse_block`, but appends to `out` and reuses the allocations of the table.
+pub(crate) fn compress_fse_block_into(
+    input: &[u8],
+    options: &CompressOptions,
+    table: &mut CompressionTable,
+    workspace: &mut CompressionWorkspace,
+    out: &mut Vec<u8>,
+) -> Result<BlockType, CompressError> {
+    let (norm_counts, max_symbol_value, table_log) = normalize(input, options)?;
+    build_compression_table_into(table, workspace, &norm_counts, table_log, max_symbol_value);
+
+    write_n_count(&norm_counts, max_symbol_value, table_log, out);
+    let bitstream = Bitstream::select(input, options);
+    write_stream(input, table, table_log, bitstream, out)?;
+    Ok(bitstream.fse_block_type())
+}
+
+/// Layout of the bitstream of a FSE block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Bitstream {
+    /// `fse_compress`
+    TwoStates,
+    /// `fse_compress_4_states`
+    FourStates,
+    /// `fse_compress_4_streams`
+    FourStreams,
+}
+
+impl Bitstream {
+    /// The layout of `options`, 4 streams need at least 8 symbols and 4 states at least 4 symbols,
+    /// smaller inputs fall back to 2 states.
+    fn select(input: &[u8], options: &CompressOptions) -> Self {
+        if options.four_streams && input.len() >= 8 {
+            Bitstream::FourStreams
+        } else if options.four_states && input.len() >= 4 {
+            Bitstream::FourStates
+        } else {
+            Bitstream::TwoStates
+        }
+    }
+
+    fn from_block_type(block_type: BlockType) -> Self {
+        match block_type {
+            BlockType::Fse4States | BlockType::Repeat4States => Bitstream::FourStates,
+            BlockType::Fse4Streams | BlockType::Repeat4Streams => Bitstream::FourStreams,
+            _ => Bitstream::TwoStates,
+        }
+    }
+
+    fn fse_block_type(self) -> BlockType {
+        match self {
+            Bitstream::TwoStates => BlockType::Fse,
+            Bitstream::FourStates => BlockType::Fse4States,
+            Bitstream::FourStreams => BlockType::Fse4Streams,
+        }
+    }
+
+    fn repeat_block_type(self) -> BlockType {
+        match self {
+            Bitstream::TwoStates => BlockType::Repeat,
+            Bitstream::FourStates => BlockType::Repeat4States,
+            Bitstream::FourStreams => BlockType::Repeat4Streams,
+        }
+    }
+}
+
+/// Normalized counts and table of the last `BlockType::Fse` block, which can be reused by
+/// `BlockType::Repeat` blocks.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RepeatTable {
+    table: CompressionTable,
+    norm_counts: Vec<i16>,
+    table_log: u32,
+}
+
+impl RepeatTable {
+    /// Estimated size of the bitstream of input with `counts` encoded with the table, or None if
+    /// there is no table or a symbol of input has a zero probability in the table.
+    fn estimate_stream_size(&self, counts: &[u32], input_len: usize) -> Option<usize> {
+        // the bitstream contains at least the two states
+        if self.norm_counts.is_empty() || input_len < 2 {
+            return None;
+        }
+        let is_encodable = counts.iter().enumerate().all(|(symbol, count)| {
+            *count == 0 || self.norm_counts.get(symbol).is_some_and(|norm| *norm != 0)
+        });
+        if !is_encodable {
+            return None;
+        }
+        Some(estimate_stream_bits(&self.table, counts, self.table_log).div_ceil(8))
+    }
+}
+
+/// Like `compress_fse_block_into`, but reuses the table of the previous `BlockType::Fse` block,
+/// if the estimated size with it is smaller than with a new table and its normalized counts.
+///
+/// Returns the type of the written block, a `BlockType::Fse` block replaces the table in
+/// `repeat`. Returns `CompressError::NotCompressibleEnough`, if the block would not be smaller
+/// than the input.
+pub(crate) fn compress_block_with_repeat_into(
+    input: &[u8],
+    options: &CompressOptions,
+    repeat: &mut RepeatTable,
+    table: &mut CompressionTable,
+    workspace: &mut CompressionWorkspace,
+    out: &mut Vec<u8>,
+) -> Result<BlockType, CompressError> {
+    let counts = count(input);
+    let repeat_size = repeat.estimate_stream_size(&counts, input.len());
+    let start = out.len();
+
+    let (max_symbol_value, table_log) = match select_table_log::<u8>(&counts, input.len(), options)
+    {
+        Ok(selected) => selected,
+        // the previous table may still compress input, which is not worth a new table
+        Err(err) if err != CompressError::Rle && repeat_size.is_some() => {
+            return write_repeat_block(input, options, repeat, start, out)
+        }
+        Err(err) => return Err(err),
+    };
+    let norm_counts = get_normalized_counts(&counts, table_log, input.len(), max_symbol_value)?;
+    build_compression_table_into(table, workspace, &norm_counts, table_log, max_symbol_value);
+
+    write_n_count(&norm_counts, max_symbol_value, table_log, out);
+    let fresh_size =
+        out.len() - start + estimate_stream_bits(table, &counts, table_log).div_ceil(8);
+    if repeat_size.is_some_and(|repeat_size| repeat_size < fresh_size) {
+        out.truncate(start);
+        return write_repeat_block(input, options, repeat, start, out);
+    }
+
+    let bitstream = Bitstream::select(input, options);
+    write_stream(input, table, table_log, bitstream, out)?;
+    if out.len() - start >= input.len() {
+        out.truncate(start);
+        return Err(CompressError::NotCompressibleEnough);
+    }
+    std::mem::swap(&mut repeat.table, table);
+    repeat.norm_counts.clear();
+    repeat
+        .norm_counts
+        .extend_from_slice(&norm_counts[..=max_symbol_value as usize]);
+    repeat.table_log = table_log;
+    Ok(bitstream.fse_block_type())
+}
+
+fn write_repeat_block(
+    input: &[u8],
+    options: &CompressOptions,
+    repeat: &RepeatTable,
+    start: usize,
+    out: &mut Vec<u8>,
+) -> Result<BlockType, CompressError> {
+    let bitstream = Bitstream::select(input, options);
+    write_stream(input, &repeat.table, repeat.table_log, bitstream, out)?;
+    if out.len() - start >= input.len() {
+        out.truncate(start);
+        return Err(CompressError::NotCompressibleEnough);
+    }
+    Ok(bitstream.repeat_block_type())
+}
+
+/// Appends the normalized counts, written with `fse_write_n_count`, to `out`.
+fn write_n_count(norm_counts: &[i16], max_symbol_value: u32, table_log: u32, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.resize(
+        start + fse_ncount_write_bound(max_symbol_value, table_log) as usize,
+        0,
+    );
+    let header_size =
+        fse_write_n_count(&mut out[start..], norm_counts, max_symbol_value, table_log)
+            .expect("normalized counts should be valid");
+    out.truncate(start + header_size);
+}
+
+/// Appends the bitstream of input in the layout of `bitstream` to `out`.
+fn write_stream(
+    input: &[u8],
+    table: &CompressionTable,
+    table_log: u32,
+    bitstream: Bitstream,
+    out: &mut Vec<u8>,
+) -> Result<(), CompressError> {
+    let stream_start = out.len();
+    let bound = match bitstream {
+        Bitstream::TwoStates => fse_compressbound(input.len()),
+        // the 4 state bitstream flushes two additional states
+        Bitstream::FourStates => fse_compressbound(input.len()) + 4,
+        Bitstream::FourStreams => fse_compressbound_4_streams(input.len()),
+    };
+    out.resize(stream_start + bound, 0);
+    let stream = &mut out[stream_start..];
+    let stream_size = match bitstream {
+        Bitstream::TwoStates => fse_compress_into(input, table, table_log, stream)?,
+        Bitstream::FourStates => fse_compress_4_states_into(input, table, table_log, stream)?,
+        Bitstream::FourStreams => fse_compress_4_streams_into(input, table, table_log, stream)?,
+    };
+    out.truncate(stream_start + stream_size);
+    Ok(())
+}
+
+/// Decompresses the payload of a block, dispatching on its block type.
+pub fn decompress_block(
+    block_type: BlockType,
+    payload: &[u8],
+    decompressed_size: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    let mut output = vec![];
+    decompress_block_into(
+        block_type,
+        payload,
+        decompressed_size,
+        &mut DecompressionTable::default(),
+        &mut vec![],
+        &mut output,
+    )?;
+    Ok(output)
+}
+
+/// Like `decompress_block`, but decompresses into `output` and reuses the allocations of the
+/// table.
+///
+/// The `BlockType::Repeat*` blocks are decompressed with `table`, which is the table of the
+/// previous `BlockType::Fse*` block.
+pub(crate) fn decompress_block_into(
+    block_type: BlockType,
+    payload: &[u8],
+    decompressed_size: usize,
+    table: &mut DecompressionTable,
+    next_symbol_table: &mut Vec<u16>,
+    output: &mut Vec<u8>,
+) -> Result<(), DecodeError> {
+    output.clear();
+    match block_type {
+        BlockType::Raw => {
+            if payload.len() != decompressed_size {
+                return Err(DecodeError::InvalidPayloadSize);
+            }
+            output.extend_from_slice(payload);
+        }
+        BlockType::Rle => {
+            if payload.len() != 1 {
+                return Err(DecodeError::InvalidPayloadSize);
+            }
+            output.resize(decompressed_size, payload[0]);
+        }
+        BlockType::Fse | BlockType::Fse4States | BlockType::Fse4Streams => {
+            let mut norm_counts = [0_i16; 256];
+            let mut max_symbol_value = FSE_MAX_SYMBOL_VALUE;
+            let mut table_log = 0;
+            let header_size = fse_read_n_count(
+                payload,
+                &mut norm_counts,
+                &mut max_symbol_value,
+                &mut table_log,
+            )?;
+            check_norm_counts(&norm_counts, table_log, max_symbol_value)?;
+
+            build_decompression_table_into(
+                table,
+                next_symbol_table,
+                &norm_counts,
+                table_log,
+                max_symbol_value,
+            );
+            output.resize(decompressed_size, 0);
+            decompress_stream(
+                block_type,
+                output,
+                &payload[header_size..],
+                table,
+                table_log,
+            )?;
+        }
+        BlockType::Repeat | BlockType::Repeat4States | BlockType::Repeat4Streams => {
+            if table.table.is_empty() {
+                return Err(DecodeError::MissingRepeatTable);
+            }
+            output.resize(decompressed_size, 0);
+            decompress_stream(block_type, output, payload, table, table.table_log())?;
+        }
+    }
+    Ok(())
+}
+
+/// Decompresses the bitstream of a FSE block, in the layout of its block type.
+fn decompress_stream(
+    block_type: BlockType,
+    output: &mut [u8],
+    input: &[u8],
+    table: &DecompressionTable,
+    table_log: u32,
+) -> Result<(), DecodeError> {
+    match Bitstream::from_block_type(block_type) {
+        Bitstream::TwoStates => fse_decompress(output, input, table, table_log),
+        Bitstream::FourStates => fse_decompress_4_states(output, input, table, table_log),
+        Bitstream::FourStreams => fse_decompress_4_streams(output, input, table, table_log),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inverse(test_data: &[u8]) -> Block<'_> {
+        let block = compress_or_fallback(test_data);
+        assert_eq!(block.decompress().unwrap(), test_data);
+        block
+    }
+
+    #[test]
+    fn test_fallback_rle() {
+        assert_eq!(
+            inverse(&[5; 1000]),
+            Block::Rle {
+                symbol: 5,
+                len: 1000
+            }
+        );
+        assert_eq!(inverse(&[5]), Block::Rle { symbol: 5, len: 1 });
+        assert_eq!(inverse(&[]), Block::Rle { symbol: 0, len: 0 });
+    }
+
+    #[test]
+    fn test_fallback_raw() {
+        let all_bytes = (0..=u8::MAX).collect::<Vec<u8>>();
+        assert_eq!(inverse(&all_bytes), Block::Raw(&all_bytes));
+        assert_eq!(inverse(&[1, 2]), Block::Raw(&[1, 2]));
+
+        const LOGO: &[u8] = include_bytes!("../test_data/logo.jpg");
+        assert!(matches!(inverse(LOGO), Block::Raw(_)));
+    }
+
+    #[test]
+    fn test_fallback_fse() {
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_65k.txt");
+        assert_eq!(inverse(TEST_DATA).block_type(), BlockType::Fse);
+    }
+
+    #[test]
+    fn test_block_type_tag() {
+        for block_type in [
+            BlockType::Raw,
+            BlockType::Rle,
+            BlockType::Fse,
+            BlockType::Repeat,
+            BlockType::Fse4States,
+            BlockType::Repeat4States,
+            BlockType::Fse4Streams,
+            BlockType::Repeat4Streams,
+        ]
+        .iter()
+        {
+            assert_eq!(BlockType::from_tag(*block_type as u8).unwrap(), *block_type);
+        }
+        assert!(matches!(
+            BlockType::from_tag(8),
+            Err(DecodeError::UnknownBlockType(8))
+        ));
+    }
+
+    #[test]
+    fn test_four_states_block() {
+        let options = CompressOptions {
+            four_states: true,
+            ..Default::default()
+        };
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_65k.txt");
+        for len in [4, 5, 6, 7, 100, 1000, TEST_DATA.len()] {
+            let block = compress_or_fallback_with_options(&TEST_DATA[..len], &options);
+            assert_eq!(block.decompress().unwrap(), &TEST_DATA[..len]);
+            if let Block::Fse { data, .. } | Block::Fse4States { data, .. } = &block {
+                assert_eq!(block.block_type(), BlockType::Fse4States);
+                // the payload differs only in the bitstream, 2 additional states
+                let two_states = compress_or_fallback(&TEST_DATA[..len]);
+                assert!(data.len() <= two_states.payload().len() + 4);
+            }
+        }
+        // the 2 state bitstream is used for inputs smaller than 4
+        let block = compress_or_fallback_with_options(b"aab", &options);
+        assert_ne!(block.block_type(), BlockType::Fse4States);
+        assert_eq!(block.decompress().unwrap(), b"aab");
+    }
+
+    #[test]
+    fn test_four_streams_block() {
+        let options = CompressOptions {
+            four_streams: true,
+            ..Default::default()
+        };
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_65k.txt");
+        for len in [8, 9, 10, 11, 100, 1000, TEST_DATA.len()] {
+            let block = compress_or_fallback_with_options(&TEST_DATA[..len], &options);
+            assert_eq!(block.decompress().unwrap(), &TEST_DATA[..len]);
+            if let Block::Fse { data, .. } | Block::Fse4Streams { data, .. } = &block {
+                assert_eq!(block.block_type(), BlockType::Fse4Streams);
+                // the jump table and the states of 3 additional streams
+                let one_stream = compress_or_fallback(&TEST_DATA[..len]);
+                assert!(data.len() <= one_stream.payload().len() + 24);
+            }
+        }
+        let block = compress_or_fallback_with_options(TEST_DATA, &options);
+        let payload = block.payload();
+        for len in 0..payload.len().min(100) {
+            assert!(
+                decompress_block(BlockType::Fse4Streams, &payload[..len], TEST_DATA.len()).is_err()
+            );
+        }
+        assert!(decompress_block(BlockType::Fse4Streams, payload, TEST_DATA.len() - 1).is_err());
+        assert!(decompress_block(BlockType::Fse4Streams, payload, TEST_DATA.len() + 1).is_err());
+    }
+}
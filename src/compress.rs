@@ -1,9 +1,10 @@
+use crate::error::CompressError;
 use crate::table::CompressionTable;
-use crate::FSE_MAX_TABLELOG;
 use bitstream::BitCstream;
 use bitstream::BitCstreamOwned;
 use bitstream::BIT_CONTAINER_BYTES;
 use bitstream::NUM_BITS_IN_BIT_CONTAINER;
+use common::Symbol;
 
 #[derive(Debug)]
 struct FseCState {
@@ -11,9 +12,12 @@ struct FseCState {
 }
 
 impl FseCState {
-    fn new(symbol: u8, comp: &CompressionTable) -> Self {
-        let symbol_tt = comp.symbol_tt[symbol as usize];
-        let nb_bits_out: u32 = ((symbol_tt.delta_nb_bits as usize + (1 << 15)) >> 16) as u32;
+    fn new<S: Symbol>(symbol: S, comp: &CompressionTable) -> Self {
+        let symbol_tt = comp.symbol_tt[symbol.to_usize()];
+        // the number of bits of the first state of the symbol. The states are below
+        // 2 * table_size, rounding with 1 << 15 instead is only correct up to table_log 14.
+        let max_state = 2 << comp.table_log();
+        let nb_bits_out: u32 = ((symbol_tt.delta_nb_bits as usize + max_state) >> 16) as u32;
         let value: usize = ((nb_bits_out as usize) << 16) - symbol_tt.delta_nb_bits as usize;
         let value: usize = comp.state_table
             [((value >> nb_bits_out) as isize + symbol_tt.delta_find_state as isize) as usize]
@@ -35,17 +39,240 @@ fn fse_blockbound(size: usize) -> usize {
     size + (size>>7) + 4 /* fse states */ + BIT_CONTAINER_BYTES
 }
 
+/// Upper bound of the compressed size of `size` bytes of input, including the normalized counts.
+///
+/// An output buffer of this size is always large enough for `compress_into`.
 #[inline]
-fn fse_compressbound(size: usize) -> usize {
+pub fn fse_compressbound(size: usize) -> usize {
     FSE_NCOUNTBOUND + fse_blockbound(size)
 }
 
+/// Size of the jump table of the four streams of `fse_compress_4_streams`
+pub const FSE_JUMP_TABLE_SIZE: usize = 12;
+
+/// Upper bound of the compressed size of `size` bytes of input with `fse_compress_4_streams`.
+///
+/// Every stream fits the bound of `fse_compress_into`, which allows to skip the checks for the
+/// end of the output.
+#[inline]
+pub fn fse_compressbound_4_streams(size: usize) -> usize {
+    // the last segment may be up to 3 symbols larger than a quarter
+    FSE_JUMP_TABLE_SIZE + 4 * fse_compressbound(size / 4 + 8)
+}
+
 #[inline]
-pub fn fse_compress(input: &[u8], comp: &CompressionTable, table_log: u32) -> BitCstreamOwned {
-    assert!(input.len() > 2);
-    let max_compressed_size = fse_compressbound(input.len());
+pub fn fse_compress<S: Symbol>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+) -> BitCstreamOwned {
+    // the bound of the input size in bytes, u16 symbols cost up to 15 bits
+    let max_compressed_size = fse_compressbound(std::mem::size_of_val(input));
+
+    let mut data: Vec<u8> = vec![0; max_compressed_size];
+    let (stream_data_pos, stream_bit_pos) =
+        compress_stream::<S, false>(input, comp, table_log, &mut data);
+
+    BitCstreamOwned::new(data, stream_data_pos, stream_bit_pos)
+}
+
+/// Like `fse_compress`, but writes into `data`, which allows to reuse the output buffer.
+///
+/// Returns the compressed size, or `CompressError::OutputTooSmall` if the bitstream does not
+/// fit into `data`. Nothing is written past the end of `data`.
+#[inline]
+pub fn fse_compress_into<S: Symbol>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+    data: &mut [u8],
+) -> Result<usize, CompressError> {
+    // the checks for the end of data are only required, if data is smaller than the bound
+    let (data_pos, bit_pos) = if data.len() >= fse_compressbound(std::mem::size_of_val(input)) {
+        compress_stream::<S, false>(input, comp, table_log, data)
+    } else {
+        compress_stream::<S, true>(input, comp, table_log, data)
+    };
+    let compressed_size = data_pos + if bit_pos > 0 { 1 } else { 0 };
+    if compressed_size > data.len() {
+        return Err(CompressError::OutputTooSmall);
+    }
+    Ok(compressed_size)
+}
+
+/// Like `fse_compress`, but interleaves 4 states instead of 2 in the bitstream, which allows
+/// more instruction level parallelism in `fse_decompress_4_states`.
+///
+/// Symbol `i` is encoded with state `i % 4`. The bitstream is not compatible with the 2 state
+/// `fse_decompress`. input needs at least 4 symbols.
+#[inline]
+pub fn fse_compress_4_states<S: Symbol>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+) -> BitCstreamOwned {
+    // two additional states compared to the 2 state bound
+    let max_compressed_size = fse_compressbound(std::mem::size_of_val(input)) + 4;
 
     let mut data: Vec<u8> = vec![0; max_compressed_size];
+    let (stream_data_pos, stream_bit_pos) =
+        compress_stream_4_states::<S, false>(input, comp, table_log, &mut data);
+
+    BitCstreamOwned::new(data, stream_data_pos, stream_bit_pos)
+}
+
+/// Like `fse_compress_into`, but with the 4 state bitstream of `fse_compress_4_states`.
+#[inline]
+pub fn fse_compress_4_states_into<S: Symbol>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+    data: &mut [u8],
+) -> Result<usize, CompressError> {
+    let (data_pos, bit_pos) = if data.len() >= fse_compressbound(std::mem::size_of_val(input)) + 4 {
+        compress_stream_4_states::<S, false>(input, comp, table_log, data)
+    } else {
+        compress_stream_4_states::<S, true>(input, comp, table_log, data)
+    };
+    let compressed_size = data_pos + if bit_pos > 0 { 1 } else { 0 };
+    if compressed_size > data.len() {
+        return Err(CompressError::OutputTooSmall);
+    }
+    Ok(compressed_size)
+}
+
+/// Like `compress_stream`, with 4 interleaved states.
+#[inline]
+fn compress_stream_4_states<S: Symbol, const CHECKED: bool>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+    data: &mut [u8],
+) -> (usize, u32) {
+    assert!(input.len() >= 4);
+    check_symbols(input, comp, table_log);
+    let mut bit_c = BitCstream::new();
+
+    // the last 4 symbols are the initial states, symbol i belongs to state i % 4
+    let body_len = input.len() - 4;
+    let mut states = [
+        FseCState::new(input[body_len], comp),
+        FseCState::new(input[body_len + 1], comp),
+        FseCState::new(input[body_len + 2], comp),
+        FseCState::new(input[body_len + 3], comp),
+    ];
+    states.rotate_right(body_len % 4);
+    let [mut state0, mut state1, mut state2, mut state3] = states;
+
+    // the symbols after the last multiple of 4
+    let index = body_len - body_len % 4;
+    for index in (index..body_len).rev() {
+        let state = match index % 4 {
+            0 => &mut state0,
+            1 => &mut state1,
+            _ => &mut state2,
+        };
+        fse_encode_symbol(&mut bit_c, state, comp, input[index]);
+        flush_bits::<CHECKED>(&mut bit_c, data);
+    }
+
+    // the flushes depend on the number of bits of a symbol, which is at most the table_log, and
+    // are resolved at compile time
+    for chunk in input[..index].chunks_exact(4).rev() {
+        fse_encode_symbol(&mut bit_c, &mut state3, comp, chunk[3]);
+        if S::MAX_TABLE_LOG * 2 + 7 > NUM_BITS_IN_BIT_CONTAINER {
+            flush_bits::<CHECKED>(&mut bit_c, data);
+        }
+        fse_encode_symbol(&mut bit_c, &mut state2, comp, chunk[2]);
+        if S::MAX_TABLE_LOG * 4 + 7 > NUM_BITS_IN_BIT_CONTAINER {
+            flush_bits::<CHECKED>(&mut bit_c, data);
+        }
+        fse_encode_symbol(&mut bit_c, &mut state1, comp, chunk[1]);
+        if S::MAX_TABLE_LOG * 2 + 7 > NUM_BITS_IN_BIT_CONTAINER {
+            flush_bits::<CHECKED>(&mut bit_c, data);
+        }
+        fse_encode_symbol(&mut bit_c, &mut state0, comp, chunk[0]);
+        flush_bits::<CHECKED>(&mut bit_c, data);
+    }
+
+    fse_flush_cstate::<CHECKED>(&mut bit_c, &mut state3, table_log, data);
+    fse_flush_cstate::<CHECKED>(&mut bit_c, &mut state2, table_log, data);
+    fse_flush_cstate::<CHECKED>(&mut bit_c, &mut state1, table_log, data);
+    fse_flush_cstate::<CHECKED>(&mut bit_c, &mut state0, table_log, data);
+
+    if CHECKED {
+        bit_c.finish_stream_checked(data);
+    } else {
+        bit_c.finish_stream(data);
+    }
+    (bit_c.data_pos, bit_c.bit_pos)
+}
+
+/// Compresses input as four independent bitstreams, which can be decoded interleaved by
+/// `fse_decompress_4_streams`.
+///
+/// The input is split into four segments of `input.len() / 4` symbols, the last one gets the
+/// remaining symbols. Each segment is compressed like `fse_compress`. The streams are prefixed
+/// with a jump table of the compressed sizes of the first three streams, 4 bytes each, little
+/// endian. input needs at least 8 symbols.
+#[inline]
+pub fn fse_compress_4_streams<S: Symbol>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+) -> Vec<u8> {
+    let mut data = vec![0; fse_compressbound_4_streams(std::mem::size_of_val(input))];
+    let compressed_size = fse_compress_4_streams_into(input, comp, table_log, &mut data)
+        .expect("the bound should fit the streams");
+    data.truncate(compressed_size);
+    data
+}
+
+/// Like `fse_compress_4_streams`, but writes into `data`.
+///
+/// Returns the compressed size, or `CompressError::OutputTooSmall` if the streams do not fit
+/// into `data`.
+#[inline]
+pub fn fse_compress_4_streams_into<S: Symbol>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+    data: &mut [u8],
+) -> Result<usize, CompressError> {
+    assert!(input.len() >= 8);
+    if data.len() < FSE_JUMP_TABLE_SIZE {
+        return Err(CompressError::OutputTooSmall);
+    }
+    let segment_size = input.len() / 4;
+    let mut op = FSE_JUMP_TABLE_SIZE;
+    for stream in 0..4 {
+        let segment = if stream < 3 {
+            &input[stream * segment_size..(stream + 1) * segment_size]
+        } else {
+            &input[3 * segment_size..]
+        };
+        let compressed_size = fse_compress_into(segment, comp, table_log, &mut data[op..])?;
+        if stream < 3 {
+            data[stream * 4..stream * 4 + 4]
+                .copy_from_slice(&(compressed_size as u32).to_le_bytes());
+        }
+        op += compressed_size;
+    }
+    Ok(op)
+}
+
+/// Returns the position of the last byte and bit in data
+///
+/// With `CHECKED`, the position may be past the end of data, but nothing is written there.
+#[inline]
+fn compress_stream<S: Symbol, const CHECKED: bool>(
+    input: &[S],
+    comp: &CompressionTable,
+    table_log: u32,
+    data: &mut [u8],
+) -> (usize, u32) {
+    assert!(input.len() >= 2);
+    check_symbols(input, comp, table_log);
     let mut bit_c = BitCstream::new();
 
     // let mut bit_c = BitCstream::new(max_compressed_size);
@@ -60,7 +287,7 @@ pub fn fse_compress(input: &[u8], comp: &CompressionTable, table_log: u32) -> Bi
             let state2 = FseCState::new(input[index], comp);
             index -= 1;
             fse_encode_symbol(&mut bit_c, &mut state1, comp, input[index]);
-            bit_c.flush_bits_fast(&mut data);
+            flush_bits::<CHECKED>(&mut bit_c, data);
             (state1, state2)
         } else {
             index -= 1;
@@ -70,63 +297,86 @@ pub fn fse_compress(input: &[u8], comp: &CompressionTable, table_log: u32) -> Bi
             (state1, state2)
         };
 
-        // join to mod 4
-        if NUM_BITS_IN_BIT_CONTAINER > FSE_MAX_TABLELOG * 4 + 7
-            // test bit 2
-            && ((input.len() - 2) & 2) == 2
-        {
+        // join to mod 4, test bit 2
+        if ((input.len() - 2) & 2) == 2 {
             index -= 1;
             fse_encode_symbol(&mut bit_c, &mut state2, comp, input[index]);
+            if S::MAX_TABLE_LOG * 2 + 7 > NUM_BITS_IN_BIT_CONTAINER {
+                flush_bits::<CHECKED>(&mut bit_c, data);
+            }
             index -= 1;
             fse_encode_symbol(&mut bit_c, &mut state1, comp, input[index]);
-            bit_c.flush_bits_fast(&mut data);
+            flush_bits::<CHECKED>(&mut bit_c, data);
         }
         // println!("START LOOP");
 
-        // these loops are correct for FSE_MAX_TABLELOG = 12
-        #[cfg(target_pointer_width = "64")]
-        {
-            // 64 bit version
-            for chunk in input[..index].rchunks_exact(4) {
-                fse_encode_symbol(&mut bit_c, &mut state2, comp, chunk[3]);
-                fse_encode_symbol(&mut bit_c, &mut state1, comp, chunk[2]);
-                fse_encode_symbol(&mut bit_c, &mut state2, comp, chunk[1]);
-                fse_encode_symbol(&mut bit_c, &mut state1, comp, chunk[0]);
-                bit_c.flush_bits_fast(&mut data);
+        // the flushes depend on the number of bits of a symbol, which is at most the table_log,
+        // and are resolved at compile time
+        for chunk in input[..index].rchunks_exact(4) {
+            fse_encode_symbol(&mut bit_c, &mut state2, comp, chunk[3]);
+            if S::MAX_TABLE_LOG * 2 + 7 > NUM_BITS_IN_BIT_CONTAINER {
+                flush_bits::<CHECKED>(&mut bit_c, data);
             }
-        }
-
-        #[cfg(target_pointer_width = "32")]
-        {
-            // 32 bit version
-            for chunk in input[..index].rchunks_exact(2) {
-                fse_encode_symbol(&mut bit_c, &mut state2, comp, chunk[1]);
-                bit_c.flush_bits_fast(&mut data);
-                fse_encode_symbol(&mut bit_c, &mut state1, comp, chunk[0]);
-                bit_c.flush_bits_fast(&mut data);
+            fse_encode_symbol(&mut bit_c, &mut state1, comp, chunk[2]);
+            if S::MAX_TABLE_LOG * 4 + 7 > NUM_BITS_IN_BIT_CONTAINER {
+                flush_bits::<CHECKED>(&mut bit_c, data);
+            }
+            fse_encode_symbol(&mut bit_c, &mut state2, comp, chunk[1]);
+            if S::MAX_TABLE_LOG * 2 + 7 > NUM_BITS_IN_BIT_CONTAINER {
+                flush_bits::<CHECKED>(&mut bit_c, data);
             }
+            fse_encode_symbol(&mut bit_c, &mut state1, comp, chunk[0]);
+            flush_bits::<CHECKED>(&mut bit_c, data);
         }
 
-        fse_flush_cstate(&mut bit_c, &mut state2, table_log, &mut data);
-        fse_flush_cstate(&mut bit_c, &mut state1, table_log, &mut data);
+        fse_flush_cstate::<CHECKED>(&mut bit_c, &mut state2, table_log, data);
+        fse_flush_cstate::<CHECKED>(&mut bit_c, &mut state1, table_log, data);
 
-        bit_c.finish_stream(&mut data);
+        if CHECKED {
+            bit_c.finish_stream_checked(data);
+        } else {
+            bit_c.finish_stream(data);
+        }
         (bit_c.data_pos, bit_c.bit_pos)
     };
 
-    BitCstreamOwned::new(data, stream_data_pos, stream_bit_pos)
+    (stream_data_pos, stream_bit_pos)
 }
 
+/// Panics, if the input contains a symbol without a transform in the table, or if the table has
+/// more states than the bitstream loops flush for.
+///
+/// Symbols with a zero count in the table only produce a corrupted bitstream, since their
+/// transform moves to the first state, see `build_compression_table_into`.
 #[inline]
-fn fse_encode_symbol(
+fn check_symbols<S: Symbol>(input: &[S], comp: &CompressionTable, table_log: u32) {
+    assert!(
+        table_log <= S::MAX_TABLE_LOG && comp.table_log() <= S::MAX_TABLE_LOG,
+        "table_log is too large for the symbol type"
+    );
+    let num_symbols = comp.symbol_tt.len();
+    if num_symbols <= S::MAX_SYMBOL_VALUE as usize {
+        let max_symbol = input.iter().map(|symbol| symbol.to_usize()).max();
+        assert!(
+            max_symbol.is_none_or(|max_symbol| max_symbol < num_symbols),
+            "input contains symbols which are not in the compression table"
+        );
+    }
+}
+
+#[inline]
+fn fse_encode_symbol<S: Symbol>(
     bit_c: &mut BitCstream,
     c_state: &mut FseCState,
     comp: &CompressionTable,
-    symbol: u8,
+    symbol: S,
 ) {
+    // The symbols are checked by `check_symbols`, and the state stays in
+    // table_size..2 * table_size for every transform of a table, which is validated by
+    // `CompressionTable::from_bytes`, so the accesses are in bounds.
     unsafe {
         // These unchecked access bring aroung 3-14% gain
-        let symbol_tt = comp.symbol_tt.get_unchecked(symbol as usize);
+        let symbol_tt = comp.symbol_tt.get_unchecked(symbol.to_usize());
         // let symbol_tt = comp.symbol_tt[symbol as usize];
 
         let nb_bits_out: u32 = ((c_state.value + symbol_tt.delta_nb_bits as usize) >> 16) as u32;
@@ -141,12 +391,21 @@ fn fse_encode_symbol(
 }
 
 #[inline]
-fn fse_flush_cstate(
+fn fse_flush_cstate<const CHECKED: bool>(
     bit_c: &mut BitCstream,
     c_state: &mut FseCState,
     table_log: u32,
     data: &mut [u8],
 ) {
     bit_c.add_bits(c_state.value, table_log);
-    bit_c.flush_bits_fast(data);
+    flush_bits::<CHECKED>(bit_c, data);
+}
+
+#[inline]
+fn flush_bits<const CHECKED: bool>(bit_c: &mut BitCstream, data: &mut [u8]) {
+    if CHECKED {
+        bit_c.flush_bits(data);
+    } else {
+        bit_c.flush_bits_fast(data);
+    }
 }
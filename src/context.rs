@@ -0,0 +1,369 @@
+/*!
+Contexts, which keep the tables and buffers between calls.
+
+Compressing many small inputs is dominated by allocating the tables. The contexts own those
+allocations and reuse them, the output is returned as slice into the context, which is valid
+until the next call.
+
+*/
+
+use crate::block::{
+    compress_block_with_repeat_into, decompress_block_into, BlockType, RepeatTable,
+};
+use crate::compress::{fse_compress_into, fse_compressbound};
+use crate::decompress::fse_decompress;
+use crate::error::{CompressError, DecodeError};
+use crate::frame::{check_frame_input_size, read_frame_header, FRAME_HEADER_SIZE};
+use crate::normalize;
+use crate::options::CompressOptions;
+use crate::table::{
+    build_compression_table_into, build_decompression_table_into, check_norm_counts,
+    CompressionTable, CompressionWorkspace, DecompressionTable,
+};
+use common::NormCountsTable;
+
+/// Reusable state for compression, see `compress` and `compress_frame`.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionContext {
+    options: CompressOptions,
+    table: CompressionTable,
+    workspace: CompressionWorkspace,
+    repeat: RepeatTable,
+    buffer: Vec<u8>,
+}
+
+impl CompressionContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_options(options: CompressOptions) -> Self {
+        CompressionContext {
+            options,
+            ..Default::default()
+        }
+    }
+
+    /// Like `crate::compress`, returns the bitstream.
+    pub fn compress(&mut self, input: &[u8]) -> Result<&[u8], CompressError> {
+        let (norm_counts, max_symbol_value, table_log) = normalize(input, &self.options)?;
+        build_compression_table_into(
+            &mut self.table,
+            &mut self.workspace,
+            &norm_counts,
+            table_log,
+            max_symbol_value,
+        );
+
+        let bound = fse_compressbound(input.len());
+        if self.buffer.len() < bound {
+            self.buffer.resize(bound, 0);
+        }
+        let compressed_size = fse_compress_into(input, &self.table, table_log, &mut self.buffer)?;
+        Ok(&self.buffer[..compressed_size])
+    }
+
+    /// Like `crate::compress_frame`, but the frame may reuse the table of the previous frame as
+    /// `BlockType::Repeat` block, if that's estimated to be smaller than a new table.
+    ///
+    /// The frames need to be decompressed in the same order by
+    /// `DecompressionContext::decompress_frame`.
+    pub fn compress_frame(&mut self, input: &[u8]) -> Result<&[u8], CompressError> {
+        check_frame_input_size(input.len())?;
+        self.buffer.clear();
+        self.buffer.resize(FRAME_HEADER_SIZE, 0);
+
+        let block_type = match compress_block_with_repeat_into(
+            input,
+            &self.options,
+            &mut self.repeat,
+            &mut self.table,
+            &mut self.workspace,
+            &mut self.buffer,
+        ) {
+            Ok(block_type) => block_type,
+            Err(CompressError::Rle) => {
+                self.buffer.truncate(FRAME_HEADER_SIZE);
+                self.buffer.push(input.first().cloned().unwrap_or_default());
+                BlockType::Rle
+            }
+            _ => {
+                self.buffer.truncate(FRAME_HEADER_SIZE);
+                self.buffer.extend_from_slice(input);
+                BlockType::Raw
+            }
+        };
+        self.buffer[0] = block_type as u8;
+        self.buffer[1..FRAME_HEADER_SIZE].copy_from_slice(&(input.len() as u32).to_le_bytes());
+        Ok(&self.buffer)
+    }
+}
+
+/// Reusable state for decompression, see `decompress` and `decompress_frame`.
+#[derive(Debug)]
+pub struct DecompressionContext {
+    table: DecompressionTable,
+    /// Table of the last `BlockType::Fse*` frame, for `BlockType::Repeat*` frames
+    frame_table: DecompressionTable,
+    next_symbol_table: Vec<u16>,
+    output: Vec<u8>,
+    /// Max decompressed size of a frame
+    max_block_size: usize,
+}
+
+impl Default for DecompressionContext {
+    fn default() -> Self {
+        Self::with_max_block_size(u32::MAX as usize)
+    }
+}
+
+impl DecompressionContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a context, which rejects frames with a decompressed size above `max_block_size`
+    /// with `DecodeError::BlockTooLarge`, see `crate::decompress_frame_with_max_size`.
+    pub fn with_max_block_size(max_block_size: usize) -> Self {
+        DecompressionContext {
+            table: DecompressionTable::default(),
+            frame_table: DecompressionTable::default(),
+            next_symbol_table: vec![],
+            output: vec![],
+            max_block_size,
+        }
+    }
+
+    /// Like `crate::decompress`.
+    pub fn decompress(
+        &mut self,
+        compressed: &[u8],
+        norm_counts: &NormCountsTable,
+        table_log: u32,
+        orig_size: usize,
+        max_symbol_value: u32,
+    ) -> Result<&[u8], DecodeError> {
+        check_norm_counts(norm_counts, table_log, max_symbol_value)?;
+        build_decompression_table_into(
+            &mut self.table,
+            &mut self.next_symbol_table,
+            norm_counts,
+            table_log,
+            max_symbol_value,
+        );
+        self.output.clear();
+        self.output.resize(orig_size, 0);
+        fse_decompress(&mut self.output, compressed, &self.table, table_log)?;
+        Ok(&self.output)
+    }
+
+    /// Output of the last decompression
+    pub(crate) fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    /// Like `crate::decompress_frame`, a `BlockType::Repeat` block is decompressed with the
+    /// table of the last `BlockType::Fse` block decompressed by this context.
+    pub fn decompress_frame(&mut self, input: &[u8]) -> Result<&[u8], DecodeError> {
+        let (block_type, orig_size) = read_frame_header(input, self.max_block_size)?;
+        decompress_block_into(
+            block_type,
+            &input[FRAME_HEADER_SIZE..],
+            orig_size,
+            &mut self.frame_table,
+            &mut self.next_symbol_table,
+            &mut self.output,
+        )?;
+        Ok(&self.output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress, compress_frame, decompress_frame};
+    use common::get_normalized_counts_from_data;
+
+    const TEST_DATA: &[&[u8]] = &[
+        include_bytes!("../test_data/compression_66k_JSON.txt"),
+        include_bytes!("../test_data/compression_1k.txt"),
+        include_bytes!("../test_data/logo.jpg"),
+        include_bytes!("../test_data/v4_uuids_19k.txt"),
+        &[5; 100],
+        b"aaabbc",
+        &[],
+        include_bytes!("../test_data/compression_65k.txt"),
+    ];
+
+    #[test]
+    fn test_context_frame_reuse() {
+        let mut comp_ctx = CompressionContext::new();
+        let mut decomp_ctx = DecompressionContext::new();
+        // twice, so every input is compressed with tables of a previous input
+        for test_data in TEST_DATA.iter().chain(TEST_DATA.iter()) {
+            let frame = comp_ctx.compress_frame(test_data).unwrap();
+            if frame[0] != BlockType::Repeat as u8 {
+                assert_eq!(frame, &compress_frame(test_data).unwrap()[..]);
+                assert_eq!(decompress_frame(frame).unwrap(), *test_data);
+            }
+            assert_eq!(decomp_ctx.decompress_frame(frame).unwrap(), *test_data);
+        }
+    }
+
+    /// Symbols with the same skewed distribution in every block
+    fn skewed_data(len: usize) -> Vec<u8> {
+        let mut seed = 1_u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((seed >> 16) % 64).trailing_zeros() as u8 + b'a'
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_context_repeat_table() {
+        let test_data = skewed_data(20_000);
+        let mut comp_ctx = CompressionContext::new();
+        let mut decomp_ctx = DecompressionContext::new();
+        let mut block_types = vec![];
+        let mut repeat_size = 0;
+        let mut fresh_size = 0;
+        for chunk in test_data.chunks(1000) {
+            let frame = comp_ctx.compress_frame(chunk).unwrap().to_vec();
+            block_types.push(BlockType::from_tag(frame[0]).unwrap());
+            assert_eq!(decomp_ctx.decompress_frame(&frame).unwrap(), chunk);
+            repeat_size += frame.len();
+            fresh_size += compress_frame(chunk).unwrap().len();
+        }
+        // the chunks have similar statistics, the first table is reused
+        assert_eq!(block_types[0], BlockType::Fse);
+        assert!(block_types.contains(&BlockType::Repeat));
+        assert!(repeat_size < fresh_size);
+
+        // a repeat frame can't be decompressed without the previous frames
+        let frame = comp_ctx
+            .compress_frame(&test_data[..1000])
+            .unwrap()
+            .to_vec();
+        assert_eq!(frame[0], BlockType::Repeat as u8);
+        assert!(matches!(
+            decompress_frame(&frame),
+            Err(DecodeError::MissingRepeatTable)
+        ));
+        assert!(matches!(
+            DecompressionContext::new().decompress_frame(&frame),
+            Err(DecodeError::MissingRepeatTable)
+        ));
+
+        // symbols which are not in the previous table need a new table
+        let other = include_bytes!("../test_data/logo.jpg");
+        let frame = comp_ctx.compress_frame(&other[..]).unwrap().to_vec();
+        assert_ne!(frame[0], BlockType::Repeat as u8);
+    }
+
+    #[test]
+    fn test_context_bitstream_layouts() {
+        let four_states = CompressOptions {
+            four_states: true,
+            ..Default::default()
+        };
+        let four_streams = CompressOptions {
+            four_streams: true,
+            ..Default::default()
+        };
+        let test_data = skewed_data(20_000);
+        for (options, fse, repeat) in [
+            (four_states, BlockType::Fse4States, BlockType::Repeat4States),
+            (
+                four_streams,
+                BlockType::Fse4Streams,
+                BlockType::Repeat4Streams,
+            ),
+        ] {
+            let mut comp_ctx = CompressionContext::with_options(options);
+            let mut decomp_ctx = DecompressionContext::new();
+            let mut block_types = vec![];
+            for chunk in test_data.chunks(1000) {
+                let frame = comp_ctx.compress_frame(chunk).unwrap().to_vec();
+                block_types.push(BlockType::from_tag(frame[0]).unwrap());
+                assert_eq!(decomp_ctx.decompress_frame(&frame).unwrap(), chunk);
+            }
+            assert_eq!(block_types[0], fse);
+            assert!(block_types.contains(&repeat));
+            assert!(block_types
+                .iter()
+                .all(|block_type| *block_type == fse || *block_type == repeat));
+        }
+    }
+
+    #[test]
+    fn test_context_compress_reuse() {
+        let mut comp_ctx = CompressionContext::new();
+        let mut decomp_ctx = DecompressionContext::new();
+        for test_data in TEST_DATA.iter().chain(TEST_DATA.iter()) {
+            let compressed = match comp_ctx.compress(test_data) {
+                Ok(compressed) => compressed,
+                Err(err) => {
+                    assert_eq!(compress(test_data).unwrap_err(), err);
+                    continue;
+                }
+            };
+            assert_eq!(
+                compressed,
+                compress(test_data).unwrap().get_compressed_data()
+            );
+
+            let (norm_counts, max_symbol_value, table_log) =
+                get_normalized_counts_from_data(test_data).unwrap();
+            let decompressed = decomp_ctx
+                .decompress(
+                    compressed,
+                    &norm_counts,
+                    table_log,
+                    test_data.len(),
+                    max_symbol_value,
+                )
+                .unwrap();
+            assert_eq!(decompressed, *test_data);
+        }
+    }
+
+    #[test]
+    fn test_context_max_block_size() {
+        let test_data = skewed_data(5000);
+        let mut comp_ctx = CompressionContext::new();
+        let frame = comp_ctx.compress_frame(&test_data).unwrap().to_vec();
+        let mut decomp_ctx = DecompressionContext::with_max_block_size(4999);
+        assert!(matches!(
+            decomp_ctx.decompress_frame(&frame),
+            Err(DecodeError::BlockTooLarge(5000))
+        ));
+        let mut decomp_ctx = DecompressionContext::with_max_block_size(5000);
+        assert_eq!(decomp_ctx.decompress_frame(&frame).unwrap(), &test_data[..]);
+
+        let frame = [BlockType::Rle as u8, 0, 0, 0, 0x40, 7];
+        assert!(matches!(
+            DecompressionContext::with_max_block_size(1 << 20).decompress_frame(&frame),
+            Err(DecodeError::BlockTooLarge(0x4000_0000))
+        ));
+    }
+
+    #[test]
+    fn test_context_corrupted_frame() {
+        let mut comp_ctx = CompressionContext::new();
+        let mut decomp_ctx = DecompressionContext::new();
+        let frame = comp_ctx
+            .compress_frame(include_bytes!("../test_data/compression_1k.txt"))
+            .unwrap()
+            .to_vec();
+        for len in 0..frame.len() {
+            let _ = decomp_ctx.decompress_frame(&frame[..len]);
+        }
+        // the context is still usable after errors
+        assert_eq!(
+            decomp_ctx.decompress_frame(&frame).unwrap(),
+            &include_bytes!("../test_data/compression_1k.txt")[..]
+        );
+    }
+}
@@ -1,5 +1,9 @@
+use crate::compress::FSE_JUMP_TABLE_SIZE;
+use crate::error::DecodeError;
 use crate::table::DecompressionTable;
-use bitstream::{BitDStreamReverse, BitDstreamStatus};
+use bitstream::{BitDStreamReverse, BitDstreamStatus, BIT_CONTAINER_BYTES};
+use common::Symbol;
+use std::convert::TryInto;
 
 /// Decomprssion State context. Multiple ones are possible
 #[derive(Debug)]
@@ -8,7 +12,11 @@ struct FseDState {
 }
 
 impl FseDState {
-    fn new(bit_stream: &mut BitDStreamReverse, table_log: u32, input: &[u8]) -> Self {
+    fn new<const CONTAINER_BYTES: usize>(
+        bit_stream: &mut BitDStreamReverse<CONTAINER_BYTES>,
+        table_log: u32,
+        input: &[u8],
+    ) -> Self {
         let state = bit_stream.read_bits(table_log);
         bit_stream.reload_stream(input);
         // DStatePtr->table = dt + 1;  TODO?
@@ -20,15 +28,72 @@ impl FseDState {
 ///
 /// output needs to be preallocated to fit the uncompressed output
 ///
+/// Returns an error if the input is malformed or does not decode to exactly `output.len()` symbols.
+#[inline]
+pub fn fse_decompress<S: Symbol>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<(), DecodeError> {
+    fse_decompress_with_container::<S, BIT_CONTAINER_BYTES>(output, input, table, table_log)
+}
+
+/// Like `fse_decompress`, but reads the bitstream with a bit container of `CONTAINER_BYTES`.
+///
+/// The compressed format does not depend on the container size, the smaller container only
+/// requires more frequent reloads. With `bitstream::BIT_CONTAINER_BYTES_32` this is the 32 bit
+/// decompression, which can also be used on 64 bit targets.
+#[inline]
+pub fn fse_decompress_with_container<S: Symbol, const CONTAINER_BYTES: usize>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<(), DecodeError> {
+    let decoded = decode_stream::<S, CONTAINER_BYTES>(output, input, table, table_log)?;
+    // the stream ended before the output was filled
+    if decoded != output.len() {
+        return Err(DecodeError::OutputSizeMismatch);
+    }
+    Ok(())
+}
+
+/// Decompresses until the end of the stream, for streams without known decompressed size.
+///
+/// output may be larger than the decompressed data.
 ///
+/// Returns the number of decoded symbols.
+#[inline]
+pub fn fse_decompress_stream<S: Symbol>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<usize, DecodeError> {
+    decode_stream::<S, BIT_CONTAINER_BYTES>(output, input, table, table_log)
+}
+
 #[inline]
-pub fn fse_decompress(output: &mut [u8], input: &[u8], table: &DecompressionTable, table_log: u32) {
-    let mut bit_stream = BitDStreamReverse::new(input);
+fn decode_stream<S: Symbol, const CONTAINER_BYTES: usize>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<usize, DecodeError> {
+    let container_bits = CONTAINER_BYTES as u32 * 8;
+    // The states are read with table_log bits and the table guarantees new_state + low_bits < table size,
+    // so with this check the states can't point outside the table.
+    if table.table.len() != 1 << table_log {
+        return Err(DecodeError::TableLogMismatch);
+    }
+    let mut bit_stream = BitDStreamReverse::<CONTAINER_BYTES>::with_container_bytes(input)?;
 
     let mut state1 = FseDState::new(&mut bit_stream, table_log, input);
     let mut state2 = FseDState::new(&mut bit_stream, table_log, input);
 
-    // 64bit version
+    // The conditions on container_bits are static, a 64 bit container fits 4 symbols of u8 per
+    // reload, a 32 bit container only 2.
     // let out_len = output.len();
     // let mut iter = output[..out_len.saturating_sub(80)].chunks_exact_mut(4);
     let mut iter = output.chunks_exact_mut(4);
@@ -41,8 +106,21 @@ pub fn fse_decompress(output: &mut [u8], input: &[u8], table: &DecompressionTabl
             break;
         }
         out_chunk[0] = fse_decode_symbol(table, &mut state1, &mut bit_stream, table.fast);
+        if S::MAX_TABLE_LOG * 2 + 7 > container_bits {
+            bit_stream.reload_stream(input);
+        }
         out_chunk[1] = fse_decode_symbol(table, &mut state2, &mut bit_stream, table.fast);
+        if S::MAX_TABLE_LOG * 4 + 7 > container_bits
+            && bit_stream.reload_stream(input) != BitDstreamStatus::Unfinished
+        {
+            // continue in the remainder loop with state1
+            consumed += 2;
+            break;
+        }
         out_chunk[2] = fse_decode_symbol(table, &mut state1, &mut bit_stream, table.fast);
+        if S::MAX_TABLE_LOG * 2 + 7 > container_bits {
+            bit_stream.reload_stream(input);
+        }
         out_chunk[3] = fse_decode_symbol(table, &mut state2, &mut bit_stream, table.fast);
         // consumed += 4;
         // let status = bit_stream.reload_stream(input);
@@ -56,41 +134,338 @@ pub fn fse_decompress(output: &mut [u8], input: &[u8], table: &DecompressionTabl
         consumed += 4;
     }
 
-    #[cfg(target_pointer_width = "32")]
-    {
-        panic!("32bit decompression not yet implemented");
-    }
     // let remainder_chunk = iter.into_remainder();
-    let remainder_chunk = &mut output[consumed..];
+    let remainder = decode_remainder(
+        &mut output[consumed..],
+        input,
+        table,
+        &mut bit_stream,
+        &mut state1,
+        &mut state2,
+    )?;
+    Ok(consumed + remainder)
+}
+
+/// Decodes the end of the stream, starting with state1, until the stream overflows.
+///
+/// Returns the number of decoded symbols.
+#[inline]
+fn decode_remainder<S: Symbol, const CONTAINER_BYTES: usize>(
+    remainder_chunk: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    bit_stream: &mut BitDStreamReverse<CONTAINER_BYTES>,
+    state1: &mut FseDState,
+    state2: &mut FseDState,
+) -> Result<usize, DecodeError> {
     let mut remainder_pos = 0;
     loop {
-        remainder_chunk[remainder_pos] =
-            fse_decode_symbol(table, &mut state1, &mut bit_stream, table.fast);
+        // every step may write two symbols, the second one when the end of the stream is reached
+        if remainder_pos + 2 > remainder_chunk.len() {
+            return Err(DecodeError::OutputSizeMismatch);
+        }
+        remainder_chunk[remainder_pos] = fse_decode_symbol(table, state1, bit_stream, table.fast);
         remainder_pos += 1;
         if bit_stream.reload_stream(input) == BitDstreamStatus::Overflow {
             remainder_chunk[remainder_pos] =
-                fse_decode_symbol(table, &mut state2, &mut bit_stream, table.fast);
+                fse_decode_symbol(table, state2, bit_stream, table.fast);
+            remainder_pos += 1;
             break;
         }
 
-        remainder_chunk[remainder_pos] =
-            fse_decode_symbol(table, &mut state2, &mut bit_stream, table.fast);
+        if remainder_pos + 2 > remainder_chunk.len() {
+            return Err(DecodeError::OutputSizeMismatch);
+        }
+        remainder_chunk[remainder_pos] = fse_decode_symbol(table, state2, bit_stream, table.fast);
         remainder_pos += 1;
         if bit_stream.reload_stream(input) == BitDstreamStatus::Overflow {
             remainder_chunk[remainder_pos] =
-                fse_decode_symbol(table, &mut state1, &mut bit_stream, table.fast);
+                fse_decode_symbol(table, state1, bit_stream, table.fast);
+            remainder_pos += 1;
             break;
         }
     }
+
+    Ok(remainder_pos)
+}
+
+/// Decompresses the 4 state bitstream of `fse_compress_4_states` into output.
+///
+/// output needs to be preallocated to the decompressed size, which is at least 4.
+///
+/// Returns an error if the input is malformed or does not decode to exactly `output.len()` symbols.
+#[inline]
+pub fn fse_decompress_4_states<S: Symbol>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<(), DecodeError> {
+    fse_decompress_4_states_with_container::<S, BIT_CONTAINER_BYTES>(
+        output, input, table, table_log,
+    )
+}
+
+/// Like `fse_decompress_4_states`, but reads the bitstream with a bit container of
+/// `CONTAINER_BYTES`, see `fse_decompress_with_container`.
+#[inline]
+pub fn fse_decompress_4_states_with_container<S: Symbol, const CONTAINER_BYTES: usize>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<(), DecodeError> {
+    let container_bits = CONTAINER_BYTES as u32 * 8;
+    if table.table.len() != 1 << table_log {
+        return Err(DecodeError::TableLogMismatch);
+    }
+    if output.len() < 4 {
+        return Err(DecodeError::OutputSizeMismatch);
+    }
+    let mut bit_stream = BitDStreamReverse::<CONTAINER_BYTES>::with_container_bytes(input)?;
+
+    let mut state0 = FseDState::new(&mut bit_stream, table_log, input);
+    let mut state1 = FseDState::new(&mut bit_stream, table_log, input);
+    let mut state2 = FseDState::new(&mut bit_stream, table_log, input);
+    let mut state3 = FseDState::new(&mut bit_stream, table_log, input);
+
+    // the last 4 symbols are the final states, which don't consume bits
+    let body_len = output.len() - 4;
+    let mut consumed = 0;
+    for out_chunk in output[..body_len].chunks_exact_mut(4) {
+        if bit_stream.reload_stream(input) != BitDstreamStatus::Unfinished {
+            break;
+        }
+        out_chunk[0] = fse_decode_symbol(table, &mut state0, &mut bit_stream, table.fast);
+        if S::MAX_TABLE_LOG * 2 + 7 > container_bits {
+            bit_stream.reload_stream(input);
+        }
+        out_chunk[1] = fse_decode_symbol(table, &mut state1, &mut bit_stream, table.fast);
+        if S::MAX_TABLE_LOG * 4 + 7 > container_bits {
+            bit_stream.reload_stream(input);
+        }
+        out_chunk[2] = fse_decode_symbol(table, &mut state2, &mut bit_stream, table.fast);
+        if S::MAX_TABLE_LOG * 2 + 7 > container_bits {
+            bit_stream.reload_stream(input);
+        }
+        out_chunk[3] = fse_decode_symbol(table, &mut state3, &mut bit_stream, table.fast);
+        consumed += 4;
+    }
+
+    // near the end of the stream, reload after every symbol and check for overflows
+    for (index, out) in output[..body_len].iter_mut().enumerate().skip(consumed) {
+        let state = match index % 4 {
+            0 => &mut state0,
+            1 => &mut state1,
+            2 => &mut state2,
+            _ => &mut state3,
+        };
+        *out = fse_decode_symbol(table, state, &mut bit_stream, table.fast);
+        if bit_stream.reload_stream(input) == BitDstreamStatus::Overflow {
+            return Err(DecodeError::OutputSizeMismatch);
+        }
+    }
+
+    bit_stream.reload_stream(input);
+    if !bit_stream.is_end_of_stream() {
+        return Err(DecodeError::OutputSizeMismatch);
+    }
+    let states = [state0, state1, state2, state3];
+    for (index, out) in output[body_len..].iter_mut().enumerate() {
+        *out = table.table[states[(body_len + index) % 4].state].symbol;
+    }
+    Ok(())
+}
+
+/// Bitstream and states of one of the streams of `fse_decompress_4_streams`
+struct StreamDecoder<'a, const CONTAINER_BYTES: usize> {
+    input: &'a [u8],
+    bit_stream: BitDStreamReverse<CONTAINER_BYTES>,
+    state1: FseDState,
+    state2: FseDState,
+}
+
+impl<'a, const CONTAINER_BYTES: usize> StreamDecoder<'a, CONTAINER_BYTES> {
+    fn new(input: &'a [u8], table_log: u32) -> Result<Self, DecodeError> {
+        let mut bit_stream = BitDStreamReverse::<CONTAINER_BYTES>::with_container_bytes(input)?;
+        let state1 = FseDState::new(&mut bit_stream, table_log, input);
+        let state2 = FseDState::new(&mut bit_stream, table_log, input);
+        Ok(StreamDecoder {
+            input,
+            bit_stream,
+            state1,
+            state2,
+        })
+    }
+
+    #[inline]
+    fn reload(&mut self) -> BitDstreamStatus {
+        self.bit_stream.reload_stream(self.input)
+    }
+
+    #[inline]
+    fn decode_symbol1<S: Symbol>(&mut self, table: &DecompressionTable<S>) -> S {
+        fse_decode_symbol(table, &mut self.state1, &mut self.bit_stream, table.fast)
+    }
+
+    #[inline]
+    fn decode_symbol2<S: Symbol>(&mut self, table: &DecompressionTable<S>) -> S {
+        fse_decode_symbol(table, &mut self.state2, &mut self.bit_stream, table.fast)
+    }
+}
+
+/// splits the input of `fse_compress_4_streams` into its four streams, using the jump table
+fn split_streams(input: &[u8]) -> Result<[&[u8]; 4], DecodeError> {
+    let jump_table = input
+        .get(..FSE_JUMP_TABLE_SIZE)
+        .ok_or(DecodeError::InvalidJumpTable)?;
+    let length = |stream: usize| {
+        u32::from_le_bytes(jump_table[stream * 4..stream * 4 + 4].try_into().unwrap()) as usize
+    };
+    let (stream1, rest) = input[FSE_JUMP_TABLE_SIZE..]
+        .split_at_checked(length(0))
+        .ok_or(DecodeError::InvalidJumpTable)?;
+    let (stream2, rest) = rest
+        .split_at_checked(length(1))
+        .ok_or(DecodeError::InvalidJumpTable)?;
+    let (stream3, stream4) = rest
+        .split_at_checked(length(2))
+        .ok_or(DecodeError::InvalidJumpTable)?;
+    Ok([stream1, stream2, stream3, stream4])
+}
+
+/// splits the output into the four segments of the streams, the last one may be larger
+fn split_segments<S>(output: &mut [S]) -> Result<[&mut [S]; 4], DecodeError> {
+    // every stream contains at least the two states
+    if output.len() < 8 {
+        return Err(DecodeError::OutputSizeMismatch);
+    }
+    let segment_size = output.len() / 4;
+    let (segment1, rest) = output.split_at_mut(segment_size);
+    let (segment2, rest) = rest.split_at_mut(segment_size);
+    let (segment3, segment4) = rest.split_at_mut(segment_size);
+    Ok([segment1, segment2, segment3, segment4])
+}
+
+/// Decodes two streams interleaved, 4 symbols per stream and loop, until a stream is near its
+/// end. The remainder needs at least 2 symbols per stream.
+///
+/// Returns the number of symbols decoded per stream.
+#[inline]
+fn decode_stream_pair<S: Symbol, const CONTAINER_BYTES: usize>(
+    segment1: &mut [S],
+    segment2: &mut [S],
+    decoder1: &mut StreamDecoder<CONTAINER_BYTES>,
+    decoder2: &mut StreamDecoder<CONTAINER_BYTES>,
+    table: &DecompressionTable<S>,
+) -> usize {
+    let container_bits = CONTAINER_BYTES as u32 * 8;
+    let num_chunks = (segment1.len().min(segment2.len()) - 2) / 4;
+    let chunks = segment1[..num_chunks * 4]
+        .chunks_exact_mut(4)
+        .zip(segment2.chunks_exact_mut(4));
+    let mut pos = 0;
+    for (chunk1, chunk2) in chunks {
+        let unfinished = (decoder1.reload() == BitDstreamStatus::Unfinished)
+            & (decoder2.reload() == BitDstreamStatus::Unfinished);
+        if !unfinished {
+            break;
+        }
+        chunk1[0] = decoder1.decode_symbol1(table);
+        chunk2[0] = decoder2.decode_symbol1(table);
+        if S::MAX_TABLE_LOG * 2 + 7 > container_bits {
+            decoder1.reload();
+            decoder2.reload();
+        }
+        chunk1[1] = decoder1.decode_symbol2(table);
+        chunk2[1] = decoder2.decode_symbol2(table);
+        if S::MAX_TABLE_LOG * 4 + 7 > container_bits {
+            decoder1.reload();
+            decoder2.reload();
+        }
+        chunk1[2] = decoder1.decode_symbol1(table);
+        chunk2[2] = decoder2.decode_symbol1(table);
+        if S::MAX_TABLE_LOG * 2 + 7 > container_bits {
+            decoder1.reload();
+            decoder2.reload();
+        }
+        chunk1[3] = decoder1.decode_symbol2(table);
+        chunk2[3] = decoder2.decode_symbol2(table);
+        pos += 4;
+    }
+    pos
+}
+
+/// Decompresses the four streams of `fse_compress_4_streams` into output. The streams are decoded
+/// interleaved in pairs, without dependencies between them.
+///
+/// output needs to be preallocated to the decompressed size, which is at least 8.
+///
+/// Returns an error if the input is malformed or does not decode to exactly `output.len()` symbols.
+#[inline]
+pub fn fse_decompress_4_streams<S: Symbol>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<(), DecodeError> {
+    fse_decompress_4_streams_with_container::<S, BIT_CONTAINER_BYTES>(
+        output, input, table, table_log,
+    )
+}
+
+/// Like `fse_decompress_4_streams`, but reads the bitstreams with a bit container of
+/// `CONTAINER_BYTES`, see `fse_decompress_with_container`.
+#[inline]
+pub fn fse_decompress_4_streams_with_container<S: Symbol, const CONTAINER_BYTES: usize>(
+    output: &mut [S],
+    input: &[u8],
+    table: &DecompressionTable<S>,
+    table_log: u32,
+) -> Result<(), DecodeError> {
+    if table.table.len() != 1 << table_log {
+        return Err(DecodeError::TableLogMismatch);
+    }
+    let streams = split_streams(input)?;
+    let mut segments = split_segments(output)?;
+    let mut decoders = [
+        StreamDecoder::<CONTAINER_BYTES>::new(streams[0], table_log)?,
+        StreamDecoder::new(streams[1], table_log)?,
+        StreamDecoder::new(streams[2], table_log)?,
+        StreamDecoder::new(streams[3], table_log)?,
+    ];
+
+    // two interleaved streams with their states fit into the registers, 4 would spill
+    let [segment1, segment2, segment3, segment4] = &mut segments;
+    let [decoder1, decoder2, decoder3, decoder4] = &mut decoders;
+    let pos12 = decode_stream_pair(segment1, segment2, decoder1, decoder2, table);
+    let pos34 = decode_stream_pair(segment3, segment4, decoder3, decoder4, table);
+
+    // finish the streams one by one
+    let positions = [pos12, pos12, pos34, pos34];
+    for ((segment, decoder), pos) in segments.iter_mut().zip(decoders.iter_mut()).zip(positions) {
+        let decoded = decode_remainder(
+            &mut segment[pos..],
+            decoder.input,
+            table,
+            &mut decoder.bit_stream,
+            &mut decoder.state1,
+            &mut decoder.state2,
+        )?;
+        if pos + decoded != segment.len() {
+            return Err(DecodeError::OutputSizeMismatch);
+        }
+    }
+    Ok(())
 }
 
 #[inline]
-fn fse_decode_symbol(
-    table: &DecompressionTable,
+fn fse_decode_symbol<S: Symbol, const CONTAINER_BYTES: usize>(
+    table: &DecompressionTable<S>,
     d_state: &mut FseDState,
-    bit_d: &mut BitDStreamReverse,
+    bit_d: &mut BitDStreamReverse<CONTAINER_BYTES>,
     fast: bool,
-) -> u8 {
+) -> S {
     if fast {
         internal_fse_decode_symbol_fast(table, d_state, bit_d)
     } else {
@@ -100,11 +475,14 @@ fn fse_decode_symbol(
 
 /// unsafe, only works if no symbol has a probability > 50%
 #[inline]
-fn internal_fse_decode_symbol_fast(
-    table: &DecompressionTable,
+fn internal_fse_decode_symbol_fast<S: Symbol, const CONTAINER_BYTES: usize>(
+    table: &DecompressionTable<S>,
     d_state: &mut FseDState,
-    bit_d: &mut BitDStreamReverse,
-) -> u8 {
+    bit_d: &mut BitDStreamReverse<CONTAINER_BYTES>,
+) -> S {
+    // Safety: the decompression functions check the table size against table_log, which bounds
+    // the initial states. Every transition of a `DecompressionTable` stays in the table, since it
+    // can only be built from valid normalized counts or loaded with validation.
     let d_info = unsafe { table.table.get_unchecked(d_state.state) };
 
     let low_bits = bit_d.read_bits_fast(d_info.nb_bits as u32);
@@ -116,11 +494,14 @@ fn internal_fse_decode_symbol_fast(
 }
 
 #[inline]
-fn internal_fse_decode_symbol(
-    table: &DecompressionTable,
+fn internal_fse_decode_symbol<S: Symbol, const CONTAINER_BYTES: usize>(
+    table: &DecompressionTable<S>,
     d_state: &mut FseDState,
-    bit_d: &mut BitDStreamReverse,
-) -> u8 {
+    bit_d: &mut BitDStreamReverse<CONTAINER_BYTES>,
+) -> S {
+    // Safety: the decompression functions check the table size against table_log, which bounds
+    // the initial states. Every transition of a `DecompressionTable` stays in the table, since it
+    // can only be built from valid normalized counts or loaded with validation.
     let d_info = unsafe { table.table.get_unchecked(d_state.state) };
     // let d_info = table.table[d_state.state];
 
@@ -0,0 +1,53 @@
+use bitstream::BitstreamError;
+use common::HistError;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum DecodeError {
+    #[error("input is too small to contain a frame header")]
+    FrameTooShort,
+    #[error("invalid normalized counts header: {0}")]
+    InvalidHeader(#[from] HistError),
+    #[error("unknown block type `{0}`")]
+    UnknownBlockType(u8),
+    #[error("payload size does not match the block")]
+    InvalidPayloadSize,
+    #[error("invalid bitstream: {0}")]
+    InvalidBitstream(#[from] BitstreamError),
+    #[error("decoded stream does not match the output size")]
+    OutputSizeMismatch,
+    #[error("decompression table does not match table_log")]
+    TableLogMismatch,
+    #[error("repeat block without the table of a previous block")]
+    MissingRepeatTable,
+    #[error("unsupported table format version `{0}`")]
+    UnsupportedTableVersion(u8),
+    #[error("invalid serialized table: {0}")]
+    InvalidTable(&'static str),
+    #[error("jump table of the four streams does not match the input")]
+    InvalidJumpTable,
+    #[error("index of the frame sizes does not match the input")]
+    InvalidBlockIndex,
+    #[error("decompressed size `{0}` of the frame exceeds the max block size")]
+    BlockTooLarge(usize),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CompressError {
+    #[error("input consists of a single symbol, use rle")]
+    Rle,
+    #[error("input is not compressible, each symbol is present at most once")]
+    NotCompressible,
+    #[error("input is not compressible enough")]
+    NotCompressibleEnough,
+    #[error("input contains a symbol larger than the max symbol value of the alphabet")]
+    SymbolTooLarge,
+    #[error("output buffer is too small for the compressed data")]
+    OutputTooSmall,
+    #[error("invalid normalized counts: {0}")]
+    InvalidNormCounts(#[from] HistError),
+    #[error("symbol `{0}` has a zero probability in the normalized counts")]
+    ZeroProbabilitySymbol(u32),
+    #[error("input of `{0}` bytes is larger than the max frame size of u32::MAX bytes")]
+    InputTooLarge(usize),
+}
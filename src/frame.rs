@@ -0,0 +1,258 @@
+use crate::block::{compress_or_fallback_with_options, decompress_block, Block, BlockType};
+use crate::error::{CompressError, DecodeError};
+use crate::options::CompressOptions;
+use std::convert::TryInto;
+
+/// Size of the block type tag and the original size at the start of a frame
+pub const FRAME_HEADER_SIZE: usize = 5;
+
+/// Compresses input into a self-describing frame.
+///
+/// In contrast to `compress`, the output contains everything needed to decompress it again.
+/// Input which is not suited for FSE is stored as raw or rle block, see `compress_or_fallback`.
+///
+/// Layout of a frame:
+///
+/// | Field         | Size     | Description                                           |
+/// |---------------|----------|-------------------------------------------------------|
+/// | block_type    | 1 byte   | `BlockType` tag                                       |
+/// | orig_size     | 4 bytes  | size of the uncompressed input, little endian         |
+/// | payload       | variable | depends on the block type, until the end of the frame |
+///
+/// The payload of a `BlockType::Fse` block consists of the normalized counts, written with
+/// `fse_write_n_count`, followed by the fse compressed bitstream.
+/// A `BlockType::Rle` block stores the symbol and a `BlockType::Raw` block the input as is.
+///
+/// A `BlockType::Repeat` block contains only the bitstream, compressed with the table of the
+/// previous `BlockType::Fse` block. It's never written by `compress_frame`, only by
+/// `CompressionContext::compress_frame` and `FseEncoder`, and can only be decompressed with the
+/// previous frames by `DecompressionContext::decompress_frame` or `FseDecoder`.
+///
+/// With `CompressOptions::four_states`, the bitstream interleaves 4 states and the blocks are
+/// written as `BlockType::Fse4States` and `BlockType::Repeat4States` instead. With
+/// `CompressOptions::four_streams`, the bitstream is split into 4 streams with a jump table, see
+/// `fse_compress_4_streams`, as `BlockType::Fse4Streams` and `BlockType::Repeat4Streams`.
+///
+/// Returns `CompressError::InputTooLarge` for inputs larger than `u32::MAX` bytes, which don't fit
+/// into the size field. Use `FseEncoder` to compress them in blocks.
+pub fn compress_frame(input: &[u8]) -> Result<Vec<u8>, CompressError> {
+    compress_frame_with_options(input, &CompressOptions::default())
+}
+
+/// Compresses input into a frame, with the table_log and incompressibility threshold of
+/// `options`.
+pub fn compress_frame_with_options(
+    input: &[u8],
+    options: &CompressOptions,
+) -> Result<Vec<u8>, CompressError> {
+    check_frame_input_size(input.len())?;
+    write_frame(&compress_or_fallback_with_options(input, options))
+}
+
+/// Writes the frame header and payload of a block.
+pub fn write_frame(block: &Block) -> Result<Vec<u8>, CompressError> {
+    let size = block.decompressed_size();
+    check_frame_input_size(size)?;
+    let payload = block.payload();
+
+    let mut out = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    out.push(block.block_type() as u8);
+    out.extend_from_slice(&(size as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    Ok(out)
+}
+
+/// Fails, if the size doesn't fit into the size field of the frame header.
+pub(crate) fn check_frame_input_size(size: usize) -> Result<(), CompressError> {
+    if size > u32::MAX as usize {
+        return Err(CompressError::InputTooLarge(size));
+    }
+    Ok(())
+}
+
+/// Decompresses a frame created by `compress_frame`.
+///
+/// The output is allocated with the decompressed size in the frame header, which can be up to
+/// 4 GiB. Untrusted input should be decompressed with `decompress_frame_with_max_size`.
+pub fn decompress_frame(input: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    decompress_frame_with_max_size(input, u32::MAX as usize)
+}
+
+/// Like `decompress_frame`, but accepts frames with a decompressed size up to `max_block_size`.
+///
+/// Larger frames are rejected with `DecodeError::BlockTooLarge`, before the output is allocated.
+pub fn decompress_frame_with_max_size(
+    input: &[u8],
+    max_block_size: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    let (block_type, orig_size) = read_frame_header(input, max_block_size)?;
+    decompress_block(block_type, &input[FRAME_HEADER_SIZE..], orig_size)
+}
+
+/// Returns the block type and the original size of a frame.
+///
+/// Fails, if the original size is larger than `max_block_size`.
+pub(crate) fn read_frame_header(
+    input: &[u8],
+    max_block_size: usize,
+) -> Result<(BlockType, usize), DecodeError> {
+    if input.len() < FRAME_HEADER_SIZE {
+        return Err(DecodeError::FrameTooShort);
+    }
+    let block_type = BlockType::from_tag(input[0])?;
+    let orig_size = u32::from_le_bytes(input[1..FRAME_HEADER_SIZE].try_into().unwrap()) as usize;
+    if orig_size > max_block_size {
+        return Err(DecodeError::BlockTooLarge(orig_size));
+    }
+    Ok((block_type, orig_size))
+}
+
+/// Max size of a frame with a decompressed size up to `max_block_size`.
+///
+/// The payload is never larger than the input, since blocks which don't compress are stored raw.
+/// Only the rle block of an empty input stores a symbol.
+pub(crate) fn max_frame_len(max_block_size: usize) -> usize {
+    FRAME_HEADER_SIZE + max_block_size.max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inverse(test_data: &[u8]) -> Vec<u8> {
+        let frame = compress_frame(test_data).unwrap();
+        let decompressed = decompress_frame(&frame).unwrap();
+        assert_eq!(decompressed, test_data);
+        frame
+    }
+
+    #[test]
+    fn test_frame_roundtrip_test_data() {
+        inverse(include_bytes!("../test_data/compression_66k_JSON.txt"));
+        inverse(include_bytes!("../test_data/compression_65k.txt"));
+        inverse(include_bytes!("../test_data/compression_34k.txt"));
+        inverse(include_bytes!("../test_data/compression_1k.txt"));
+        inverse(include_bytes!("../test_data/v4_uuids_19k.txt"));
+        inverse(include_bytes!("../test_data/v4_uuids_93k.txt"));
+    }
+
+    #[test]
+    fn test_frame_roundtrip_multi_sizes() {
+        for num_elems in 0..1000 {
+            let test_data: Vec<u8> = (0..num_elems).map(|i| b"aaaabbbcc"[i % 9]).collect();
+            inverse(&test_data);
+        }
+    }
+
+    #[test]
+    fn test_frame_block_types() {
+        let frame = inverse(&[7; 100]);
+        assert_eq!(frame, vec![BlockType::Rle as u8, 100, 0, 0, 0, 7]);
+
+        let frame = inverse(include_bytes!("../test_data/logo.jpg"));
+        assert_eq!(frame[0], BlockType::Raw as u8);
+
+        let frame = inverse(include_bytes!("../test_data/compression_1k.txt"));
+        assert_eq!(frame[0], BlockType::Fse as u8);
+    }
+
+    #[test]
+    fn test_frame_wrong_size() {
+        let mut frame = compress_frame(include_bytes!("../test_data/compression_1k.txt")).unwrap();
+        frame[1] += 1;
+        assert!(matches!(
+            decompress_frame(&frame),
+            Err(DecodeError::OutputSizeMismatch)
+        ));
+        frame[1] -= 2;
+        assert!(matches!(
+            decompress_frame(&frame),
+            Err(DecodeError::OutputSizeMismatch)
+        ));
+    }
+
+    #[test]
+    fn test_frame_corrupted_no_panic() {
+        let frame = compress_frame(include_bytes!("../test_data/compression_1k.txt")).unwrap();
+        for len in 0..frame.len() {
+            let _ = decompress_frame(&frame[..len]);
+        }
+
+        // xorshift, flips random bits outside of the size field
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..10_000 {
+            let mut corrupted = frame.clone();
+            for _ in 0..1 + seed % 3 {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let pos =
+                    FRAME_HEADER_SIZE + (seed >> 8) as usize % (frame.len() - FRAME_HEADER_SIZE);
+                corrupted[pos] ^= 1 << (seed % 8);
+            }
+            let _ = decompress_frame(&corrupted);
+        }
+    }
+
+    #[test]
+    fn test_frame_invalid() {
+        assert!(matches!(
+            decompress_frame(&[1, 2]),
+            Err(DecodeError::FrameTooShort)
+        ));
+        assert!(matches!(
+            decompress_frame(&[9, 1, 0, 0, 0]),
+            Err(DecodeError::UnknownBlockType(9))
+        ));
+        assert!(matches!(
+            decompress_frame(&[BlockType::Raw as u8, 5, 0, 0, 0, 1]),
+            Err(DecodeError::InvalidPayloadSize)
+        ));
+        assert!(decompress_frame(&[BlockType::Fse as u8, 5, 0, 0, 0]).is_err());
+        assert!(decompress_frame(&[BlockType::Fse as u8, 5, 0, 0, 0, 0x30, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_frame_input_too_large() {
+        let len = u32::MAX as usize + 1;
+        assert_eq!(
+            write_frame(&Block::Rle { symbol: 7, len }),
+            Err(CompressError::InputTooLarge(len))
+        );
+        assert_eq!(
+            write_frame(&Block::Rle {
+                symbol: 7,
+                len: u32::MAX as usize
+            })
+            .unwrap(),
+            vec![BlockType::Rle as u8, 0xFF, 0xFF, 0xFF, 0xFF, 7]
+        );
+    }
+
+    #[test]
+    fn test_frame_max_block_size() {
+        // rle frame claiming 1 GiB, rejected before allocating
+        let frame = [BlockType::Rle as u8, 0, 0, 0, 0x40, 7];
+        assert!(matches!(
+            decompress_frame_with_max_size(&frame, 1 << 20),
+            Err(DecodeError::BlockTooLarge(0x4000_0000))
+        ));
+
+        let test_data = vec![7; (2 << 20) + 1];
+        let frame = compress_frame(&test_data).unwrap();
+        assert_eq!(decompress_frame(&frame).unwrap(), test_data);
+        assert_eq!(
+            decompress_frame_with_max_size(&frame, test_data.len()).unwrap(),
+            test_data
+        );
+        assert!(matches!(
+            decompress_frame_with_max_size(&frame, 1000),
+            Err(DecodeError::BlockTooLarge(_))
+        ));
+
+        let frame = compress_frame(&[]).unwrap();
+        assert!(frame.len() <= max_frame_len(0));
+        let frame = compress_frame(include_bytes!("../test_data/logo.jpg")).unwrap();
+        assert!(frame.len() <= max_frame_len(include_bytes!("../test_data/logo.jpg").len()));
+    }
+}
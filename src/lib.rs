@@ -18,22 +18,47 @@ foundation in math and compression it will be difficult to follow.
 
 use crate::decompress::fse_decompress as other_fse_decompress;
 use crate::table::build_decompression_table;
+use crate::table::build_decompression_table_generic;
 use crate::table::DecompressionTable;
+use crate::table::{check_norm_counts, check_norm_counts_generic};
 
-use crate::compress::fse_compress;
-use crate::table::build_compression_table;
+use crate::compress::{fse_compress, fse_compress_into};
+use crate::table::{build_compression_table, estimate_stream_bits_from_norm_counts};
+use bitstream::highbit_pos;
 use bitstream::BitCstreamOwned;
 use common::get_max_symbol_value;
 use common::get_normalized_counts;
 use common::CountsTable;
 use common::NormCountsTable;
-use common::{count_simple, fse_optimal_table_log};
+use common::{count, fse_optimal_table_log};
+use common::{
+    count_symbols, fse_ncount_write_bound, fse_read_n_count, fse_write_n_count,
+    get_normalized_counts_generic, Symbol,
+};
 
+pub mod block;
 pub mod compress;
+pub mod context;
 pub mod decompress;
+pub mod error;
+pub mod frame;
 // pub mod hist;
+pub mod options;
+pub mod parallel;
+pub mod serialize;
+pub mod stream;
 pub mod table;
 
+pub use block::{compress_or_fallback, compress_or_fallback_with_options, Block, BlockType};
+pub use context::{CompressionContext, DecompressionContext};
+pub use error::{CompressError, DecodeError};
+pub use frame::{
+    compress_frame, compress_frame_with_options, decompress_frame, decompress_frame_with_max_size,
+};
+pub use options::CompressOptions;
+pub use parallel::{compress_parallel, decompress_parallel};
+pub use stream::{FseDecoder, FseEncoder};
+
 pub const FSE_DEFAULT_MEMORY_USAGE: u32 = 13;
 pub const FSE_MAX_MEMORY_USAGE: u32 = 14; // 16kb
 pub const FSE_DEFAULT_TABLELOG: u32 = FSE_DEFAULT_MEMORY_USAGE - 2;
@@ -59,9 +84,6 @@ struct Counts {
     total: usize,
 }
 
-// should be a generic eventually
-// type FseFunctionType = u8;
-
 pub fn get_ans_table_size(mut table_log: u32, max_symbol_value: u32) -> u32 {
     table_log = table_log.min(FSE_TABLELOG_ABSOLUTE_MAX);
 
@@ -75,53 +97,245 @@ fn test_get_ans_table_size() {
     assert_eq!(get_ans_table_size(FSE_DEFAULT_TABLELOG, 255), 1537);
 }
 
-pub fn compress(input: &[u8]) -> BitCstreamOwned {
-    let counts = count_simple(input);
+/// Compresses input with FSE.
+///
+/// Returns an error if the input is not suited for FSE, see `compress_or_fallback` for an
+/// alternative which falls back to a raw or rle block.
+pub fn compress(input: &[u8]) -> Result<BitCstreamOwned, CompressError> {
+    compress_with_options(input, &CompressOptions::default())
+}
+
+/// Compresses input with FSE, with the table_log and incompressibility threshold of `options`.
+pub fn compress_with_options(
+    input: &[u8],
+    options: &CompressOptions,
+) -> Result<BitCstreamOwned, CompressError> {
+    let (norm_counts, max_symbol_value, table_log) = normalize(input, options)?;
+    let comp_tables = build_compression_table(&norm_counts, table_log, max_symbol_value);
+
+    Ok(fse_compress(input, &comp_tables, table_log))
+}
+
+/// Compresses input with FSE into `dst`, like `compress`.
+///
+/// Returns the compressed size, or `CompressError::OutputTooSmall` if the compressed data does
+/// not fit into `dst`. Nothing is written past the end of `dst`.
+/// `compress::fse_compressbound(input.len())` is always large enough.
+pub fn compress_into(input: &[u8], dst: &mut [u8]) -> Result<usize, CompressError> {
+    let (norm_counts, max_symbol_value, table_log) = normalize(input, &CompressOptions::default())?;
+    let comp_tables = build_compression_table(&norm_counts, table_log, max_symbol_value);
+
+    fse_compress_into(input, &comp_tables, table_log, dst)
+}
+
+/// Compresses input with predefined normalized counts, e.g. a distribution shared by many small
+/// messages, so the counts don't need to be stored with each message.
+///
+/// Every symbol of input needs a non-zero normalized count, otherwise
+/// `CompressError::ZeroProbabilitySymbol` names the first symbol which can't be encoded.
+/// Input with less than 2 symbols can't be encoded in the bitstream and returns
+/// `CompressError::Rle`.
+///
+/// The output can be decompressed with `decompress` and the same normalized counts.
+pub fn compress_with_norm_counts(
+    input: &[u8],
+    norm_counts: &NormCountsTable,
+    table_log: u32,
+    max_symbol_value: u32,
+) -> Result<BitCstreamOwned, CompressError> {
+    check_norm_counts(norm_counts, table_log, max_symbol_value)?;
+    if let Some(symbol) = input
+        .iter()
+        .find(|symbol| **symbol as u32 > max_symbol_value || norm_counts[**symbol as usize] == 0)
+    {
+        return Err(CompressError::ZeroProbabilitySymbol(*symbol as u32));
+    }
+    if input.len() < 2 {
+        return Err(CompressError::Rle);
+    }
+    let comp_tables = build_compression_table(norm_counts, table_log, max_symbol_value);
+
+    Ok(fse_compress(input, &comp_tables, table_log))
+}
+
+/// Estimates the size in bytes of the FSE compressed symbols with `counts`, without compressing
+/// them, e.g. to decide if data is worth to be compressed.
+///
+/// The estimation consists of the size of the normalized counts header, as written by
+/// `fse_write_n_count`, and the bitstream, based on `fse_bit_cost`. In contrast to
+/// `hufflpuff::Tree::estimate_compressed_size`, which doesn't include the huffman table, the
+/// header is included.
+///
+/// The costs of `fse_bit_cost` are interpolated and slightly too high, the estimation is usually
+/// 1-2% larger than the compressed size.
+///
+/// The normalized counts may cover more symbols than `counts`, e.g. a predefined distribution,
+/// see `compress_with_norm_counts`. Returns `CompressError::ZeroProbabilitySymbol` for a symbol
+/// with a count but without a normalized count.
+pub fn estimate_compressed_size(
+    counts: &[u32],
+    norm_counts: &[i16],
+    table_log: u32,
+) -> Result<usize, CompressError> {
+    let max_symbol_value = norm_counts
+        .iter()
+        .rposition(|norm_count| *norm_count != 0)
+        .unwrap_or(0) as u32;
+    check_norm_counts(norm_counts, table_log, max_symbol_value)?;
+    if let Some(symbol) = counts.iter().enumerate().position(|(symbol, count)| {
+        *count != 0
+            && norm_counts
+                .get(symbol)
+                .is_none_or(|norm_count| *norm_count == 0)
+    }) {
+        return Err(CompressError::ZeroProbabilitySymbol(symbol as u32));
+    }
+
+    let mut header = vec![0; fse_ncount_write_bound(max_symbol_value, table_log) as usize];
+    let header_size = fse_write_n_count(&mut header, norm_counts, max_symbol_value, table_log)?;
+
+    Ok(header_size
+        + estimate_stream_bits_from_norm_counts(norm_counts, counts, table_log).div_ceil(8))
+}
+
+/// Counts the symbols in input and normalizes them.
+///
+/// Returns the normalized counts, the max symbol value and the table_log.
+pub(crate) fn normalize(
+    input: &[u8],
+    options: &CompressOptions,
+) -> Result<(NormCountsTable, u32, u32), CompressError> {
+    let counts = count(input);
+    let (max_symbol_value, table_log) = select_table_log::<u8>(&counts, input.len(), options)?;
+
+    let norm_counts = get_normalized_counts(&counts, table_log, input.len(), max_symbol_value)?;
+    Ok((norm_counts, max_symbol_value, table_log))
+}
+
+/// Checks if the counts are compressible with FSE.
+///
+/// Returns the max symbol value and the table_log.
+fn select_table_log<S: Symbol>(
+    counts: &[u32],
+    src_size: usize,
+    options: &CompressOptions,
+) -> Result<(u32, u32), CompressError> {
     let max_count = *counts.iter().max().unwrap() as usize;
-    if max_count == input.len() {
-        panic!("use rle");
+    if max_count == src_size {
+        return Err(CompressError::Rle);
     }; // only a single symbol in src : rle
     if max_count == 1 {
-        panic!("not compressible");
+        return Err(CompressError::NotCompressible);
     }; // each symbol present maximum once => not compressible
-    if max_count < (input.len() >> 7) {
-        panic!("not compressible enough");
+    if max_count < (src_size >> options.incompressible_shift) {
+        return Err(CompressError::NotCompressibleEnough);
     }; // Heuristic : not compressible enough
 
-    let max_symbol_value = get_max_symbol_value(&counts);
+    let max_symbol_value = get_max_symbol_value(counts);
 
-    let table_log = fse_optimal_table_log(FSE_DEFAULT_TABLELOG, input.len(), max_symbol_value);
+    let mut table_log =
+        fse_optimal_table_log(options.table_log_limit(), src_size, max_symbol_value);
 
-    let norm_counts = get_normalized_counts(&counts, table_log, input.len(), max_symbol_value);
-    let comp_tables = build_compression_table(&norm_counts, table_log, max_symbol_value);
+    // only possible for alphabets larger than u8, which may have more symbols than states:
+    // every symbol needs at least one state, the normalization needs some more to distribute.
+    // The table_log is raised beyond the limit of the options for them.
+    let num_symbols = counts.iter().filter(|count| **count != 0).count();
+    if num_symbols > 1 << (table_log - 1) {
+        table_log = highbit_pos(num_symbols as u32 - 1) + 2;
+        if table_log > S::MAX_TABLE_LOG {
+            return Err(CompressError::NotCompressible);
+        }
+    }
+    Ok((max_symbol_value, table_log))
+}
+
+/// Compresses symbols of an alphabet larger than `u8`, e.g. `u16` with up to
+/// `MAX_SYMBOL_VALUE_U16 + 1` symbols.
+///
+/// At most half of the states of the table can be used by distinct symbols, for input with more
+/// distinct symbols than the table_log of the options allows, the table_log is raised up to
+/// `Symbol::MAX_TABLE_LOG`, e.g. 13 for all 4096 `u16` symbols.
+///
+/// In contrast to `compress`, the output starts with the normalized counts, written with
+/// `fse_write_n_count`, followed by the bitstream. Only the number of symbols is needed to
+/// decompress it with `decompress_symbols`.
+pub fn compress_symbols<S: Symbol>(
+    input: &[S],
+    options: &CompressOptions,
+) -> Result<Vec<u8>, CompressError> {
+    let counts = count_symbols(input).map_err(|_| CompressError::SymbolTooLarge)?;
+    let (max_symbol_value, table_log) = select_table_log::<S>(&counts, input.len(), options)?;
+    let norm_counts =
+        get_normalized_counts_generic(&counts, table_log, input.len(), max_symbol_value)?;
+
+    let comp_table = build_compression_table(&norm_counts, table_log, max_symbol_value);
+    let stream = fse_compress(input, &comp_table, table_log);
+
+    let mut out = vec![0; fse_ncount_write_bound(max_symbol_value, table_log) as usize];
+    let header_size = fse_write_n_count(&mut out, &norm_counts, max_symbol_value, table_log)
+        .expect("normalized counts should be valid");
+    out.truncate(header_size);
+    out.extend_from_slice(stream.get_compressed_data());
+    Ok(out)
+}
+
+/// Decompresses the output of `compress_symbols` into `num_symbols` symbols.
+pub fn decompress_symbols<S: Symbol>(
+    input: &[u8],
+    num_symbols: usize,
+) -> Result<Vec<S>, DecodeError> {
+    let mut norm_counts = vec![0_i16; S::MAX_SYMBOL_VALUE as usize + 1];
+    let mut max_symbol_value = S::MAX_SYMBOL_VALUE;
+    let mut table_log = 0;
+    let header_size = fse_read_n_count(
+        input,
+        &mut norm_counts,
+        &mut max_symbol_value,
+        &mut table_log,
+    )?;
+    check_norm_counts_generic::<S>(&norm_counts, table_log, max_symbol_value)?;
 
-    fse_compress(input, &comp_tables, table_log)
+    let table = build_decompression_table_generic(&norm_counts, table_log, max_symbol_value);
+    let mut output = vec![S::default(); num_symbols];
+    other_fse_decompress(&mut output, &input[header_size..], &table, table_log)?;
+    Ok(output)
 }
 
+/// Decompresses the output of `compress` or `compress_with_norm_counts` into `orig_size` bytes,
+/// with the normalized counts used for the compression.
 pub fn decompress(
     compressed: &[u8],
     norm_counts: &NormCountsTable,
     table_log: u32,
     orig_size: usize,
     max_symbol_value: u32,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, DecodeError> {
+    check_norm_counts(norm_counts, table_log, max_symbol_value)?;
     let mut output = vec![0_u8, 0];
     output.resize(orig_size, 0);
 
     let decomp_table = build_decompression_table(norm_counts, table_log, max_symbol_value);
 
-    fse_decompress(&mut output, compressed, &decomp_table, table_log);
-    output
+    fse_decompress(&mut output, compressed, &decomp_table, table_log)?;
+    Ok(output)
 }
 
-pub fn fse_decompress(output: &mut [u8], input: &[u8], table: &DecompressionTable, table_log: u32) {
+pub fn fse_decompress(
+    output: &mut [u8],
+    input: &[u8],
+    table: &DecompressionTable,
+    table_log: u32,
+) -> Result<(), DecodeError> {
     other_fse_decompress(output, input, table, table_log)
 }
 
 #[cfg(test)]
 mod tests {
 
-    use common::count_simple;
+    use crate::decompress::fse_decompress_with_container;
+    use crate::table::CompressionTable;
+    use bitstream::BIT_CONTAINER_BYTES_32;
+    use common::{count_simple, get_normalized_counts_from_data, HistError};
     use std::sync::Once;
 
     static INIT: Once = Once::new();
@@ -186,7 +400,7 @@ mod tests {
         assert_eq!(counts[B_BYTE as usize], 35);
         assert_eq!(counts[C_BYTE as usize], 20);
 
-        let out = compress(&test_data);
+        let out = compress(&test_data).unwrap();
         dbg!(out.data_pos);
         // dbg!(out.bit_pos);
         // dbg!(out.bit_container);
@@ -215,6 +429,212 @@ mod tests {
         }
     }
 
+    fn build_tables<S: Symbol>(test_data: &[S]) -> (CompressionTable, DecompressionTable<S>, u32) {
+        let counts = count_symbols(test_data).unwrap();
+        let (max_symbol_value, table_log) =
+            select_table_log::<S>(&counts, test_data.len(), &CompressOptions::default()).unwrap();
+        let norm_counts =
+            get_normalized_counts_generic(&counts, table_log, test_data.len(), max_symbol_value)
+                .unwrap();
+        (
+            build_compression_table(&norm_counts, table_log, max_symbol_value),
+            build_decompression_table_generic(&norm_counts, table_log, max_symbol_value),
+            table_log,
+        )
+    }
+
+    fn inverse_4_states<S: Symbol + PartialEq>(test_data: &[S]) {
+        let (comp_table, table, table_log) = build_tables(test_data);
+        let compressed = compress::fse_compress_4_states(test_data, &comp_table, table_log);
+        let compressed = compressed.get_compressed_data();
+
+        let mut output = vec![S::default(); test_data.len()];
+        decompress::fse_decompress_4_states(&mut output, compressed, &table, table_log).unwrap();
+        assert!(output == test_data);
+        let mut output = vec![S::default(); test_data.len()];
+        decompress::fse_decompress_4_states_with_container::<S, BIT_CONTAINER_BYTES_32>(
+            &mut output,
+            compressed,
+            &table,
+            table_log,
+        )
+        .unwrap();
+        assert!(output == test_data);
+
+        let mut dst = vec![0; compressed.len()];
+        let size =
+            compress::fse_compress_4_states_into(test_data, &comp_table, table_log, &mut dst)
+                .unwrap();
+        assert_eq!(&dst[..size], compressed);
+        assert_eq!(
+            compress::fse_compress_4_states_into(
+                test_data,
+                &comp_table,
+                table_log,
+                &mut dst[..size - 1]
+            ),
+            Err(CompressError::OutputTooSmall)
+        );
+
+        // a wrong size is detected
+        for len in [0, 4, test_data.len() - 1, test_data.len() + 1] {
+            let mut output = vec![S::default(); len];
+            assert!(decompress::fse_decompress_4_states(
+                &mut output,
+                compressed,
+                &table,
+                table_log
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_4_states() {
+        for num_elems in 15..1000 {
+            inverse_4_states(&get_test_data_flexible(num_elems));
+        }
+        for test_data in [
+            &include_bytes!("../test_data/compression_66k_JSON.txt")[..],
+            include_bytes!("../test_data/compression_65k.txt"),
+            include_bytes!("../test_data/compression_34k.txt"),
+            include_bytes!("../test_data/compression_1k.txt"),
+            include_bytes!("../test_data/v4_uuids_19k.txt"),
+            include_bytes!("../test_data/v4_uuids_93k.txt"),
+        ] {
+            inverse_4_states(&test_data[..100]);
+            inverse_4_states(test_data);
+        }
+        let test_data: Vec<u16> = (0..5000_u16).map(|i| (i % 7) * (i % 13) * 40).collect();
+        inverse_4_states(&test_data);
+    }
+
+    fn inverse_4_streams<S: Symbol + PartialEq>(test_data: &[S]) {
+        let (comp_table, table, table_log) = build_tables(test_data);
+        let compressed = compress::fse_compress_4_streams(test_data, &comp_table, table_log);
+
+        let mut output = vec![S::default(); test_data.len()];
+        decompress::fse_decompress_4_streams(&mut output, &compressed, &table, table_log).unwrap();
+        assert!(output == test_data);
+        let mut output = vec![S::default(); test_data.len()];
+        decompress::fse_decompress_4_streams_with_container::<S, BIT_CONTAINER_BYTES_32>(
+            &mut output,
+            &compressed,
+            &table,
+            table_log,
+        )
+        .unwrap();
+        assert!(output == test_data);
+
+        let mut dst = vec![0; compressed.len()];
+        let size =
+            compress::fse_compress_4_streams_into(test_data, &comp_table, table_log, &mut dst)
+                .unwrap();
+        assert_eq!(dst[..size], compressed[..]);
+        assert_eq!(
+            compress::fse_compress_4_streams_into(
+                test_data,
+                &comp_table,
+                table_log,
+                &mut dst[..size - 1]
+            ),
+            Err(CompressError::OutputTooSmall)
+        );
+
+        for len in [0, 7, test_data.len() - 1, test_data.len() + 1] {
+            let mut output = vec![S::default(); len];
+            assert!(decompress::fse_decompress_4_streams(
+                &mut output,
+                &compressed,
+                &table,
+                table_log
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_roundtrip_4_streams() {
+        // the smallest inputs with 2 symbols per stream
+        for len in 8..15 {
+            inverse_4_streams(&b"aabaabcaabacaab"[..len]);
+        }
+        for num_elems in 15..1000 {
+            inverse_4_streams(&get_test_data_flexible(num_elems));
+        }
+        for test_data in [
+            &include_bytes!("../test_data/compression_66k_JSON.txt")[..],
+            include_bytes!("../test_data/compression_65k.txt"),
+            include_bytes!("../test_data/compression_34k.txt"),
+            include_bytes!("../test_data/compression_1k.txt"),
+            include_bytes!("../test_data/v4_uuids_19k.txt"),
+            include_bytes!("../test_data/v4_uuids_93k.txt"),
+        ] {
+            inverse_4_streams(&test_data[..100]);
+            inverse_4_streams(test_data);
+        }
+        let test_data: Vec<u16> = (0..5000_u16).map(|i| (i % 7) * (i % 13) * 40).collect();
+        inverse_4_streams(&test_data);
+    }
+
+    #[test]
+    fn test_4_streams_corrupted() {
+        let test_data = include_bytes!("../test_data/compression_1k.txt");
+        let (comp_table, table, table_log) = build_tables(&test_data[..]);
+        let compressed = compress::fse_compress_4_streams(test_data, &comp_table, table_log);
+
+        let mut output = vec![0; test_data.len()];
+        for len in 0..compressed.len() {
+            assert!(decompress::fse_decompress_4_streams(
+                &mut output,
+                &compressed[..len],
+                &table,
+                table_log
+            )
+            .is_err());
+        }
+        for pos in 0..compressed.len() {
+            let mut corrupted = compressed.clone();
+            corrupted[pos] ^= 0x55;
+            let _ =
+                decompress::fse_decompress_4_streams(&mut output, &corrupted, &table, table_log);
+        }
+        // a jump table pointing past the end
+        let mut corrupted = compressed.clone();
+        corrupted[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            decompress::fse_decompress_4_streams(&mut output, &corrupted, &table, table_log),
+            Err(DecodeError::InvalidJumpTable)
+        ));
+    }
+
+    #[test]
+    fn test_4_states_corrupted() {
+        let test_data = include_bytes!("../test_data/compression_1k.txt");
+        let (norm_counts, max_symbol_value, table_log) =
+            get_normalized_counts_from_data(test_data).unwrap();
+        let comp_table = build_compression_table(&norm_counts, table_log, max_symbol_value);
+        let compressed = compress::fse_compress_4_states(test_data, &comp_table, table_log);
+        let compressed = compressed.get_compressed_data();
+        let table = build_decompression_table(&norm_counts, table_log, max_symbol_value);
+
+        let mut output = vec![0; test_data.len()];
+        for len in 0..compressed.len() {
+            assert!(decompress::fse_decompress_4_states(
+                &mut output,
+                &compressed[..len],
+                &table,
+                table_log
+            )
+            .is_err());
+        }
+        for pos in 0..compressed.len() {
+            let mut corrupted = compressed.to_vec();
+            corrupted[pos] ^= 0x55;
+            let _ = decompress::fse_decompress_4_states(&mut output, &corrupted, &table, table_log);
+        }
+    }
+
     #[test]
     fn test_66k_json() {
         setup();
@@ -253,9 +673,337 @@ mod tests {
         inverse(TEST_DATA);
     }
 
+    #[test]
+    fn test_many_low_proba_symbols() {
+        // 30 frequent symbols and 100 symbols occurring once, which triggers the secondary
+        // normalization method
+        let mut test_data: Vec<u8> = (0..30 * 300).map(|i| (i % 30) as u8).collect();
+        for symbol in 30..130 {
+            test_data.insert(symbol as usize * 71 % test_data.len(), symbol);
+        }
+        inverse(&test_data);
+
+        // mostly zero, with some sparse values
+        let test_data: Vec<u8> = (0..20_000_u32)
+            .map(|i| {
+                if i % 97 == 0 {
+                    (i / 97 % 200) as u8 + 1
+                } else {
+                    0
+                }
+            })
+            .collect();
+        inverse(&test_data);
+    }
+
+    #[test]
+    fn test_compress_with_options() {
+        setup();
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_66k_JSON.txt");
+        let counts = count_simple(TEST_DATA);
+        let max_symbol_value = get_max_symbol_value(&counts);
+        for level in 1..=options::MAX_COMPRESSION_LEVEL {
+            let options = CompressOptions::from_level(level);
+            let out = compress_with_options(TEST_DATA, &options).unwrap();
+
+            let table_log =
+                fse_optimal_table_log(options.table_log_limit(), TEST_DATA.len(), max_symbol_value);
+            let norm_counts =
+                get_normalized_counts(&counts, table_log, TEST_DATA.len(), max_symbol_value)
+                    .unwrap();
+            let decompressed = decompress(
+                out.get_compressed_data(),
+                &norm_counts,
+                table_log,
+                TEST_DATA.len(),
+                max_symbol_value,
+            )
+            .unwrap();
+            assert_eq!(decompressed, TEST_DATA);
+        }
+
+        // the most frequent symbol is 1/256 of the input
+        let test_data: Vec<u8> = (0..=u8::MAX).cycle().take(256 * 10).collect();
+        assert_eq!(
+            compress(&test_data).unwrap_err(),
+            CompressError::NotCompressibleEnough
+        );
+        let options = CompressOptions {
+            incompressible_shift: 8,
+            ..Default::default()
+        };
+        assert!(compress_with_options(&test_data, &options).is_ok());
+    }
+
+    fn inverse_symbols<S: Symbol + PartialEq>(test_data: &[S]) -> Vec<u8> {
+        let compressed = compress_symbols(test_data, &CompressOptions::default()).unwrap();
+        let decompressed: Vec<S> = decompress_symbols(&compressed, test_data.len()).unwrap();
+        assert!(decompressed == test_data);
+        compressed
+    }
+
+    #[test]
+    fn test_roundtrip_u16_symbols() {
+        setup();
+        // geometric like distribution over every 3rd symbol of 4096 symbols
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let test_data: Vec<u16> = (0..100_000)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let bits = (seed >> 60) as u32 % 12; // 0..12
+                ((seed >> 8) as u32 & ((1 << bits) - 1)) as u16 % 1366 * 3
+            })
+            .collect();
+        let max_symbol = *test_data.iter().max().unwrap();
+        assert!(max_symbol > 4000);
+        let compressed = inverse_symbols(&test_data);
+        assert!(compressed.len() < test_data.len() * 2);
+
+        for num_elems in 3..300 {
+            let test_data: Vec<u16> = (0..num_elems).map(|i| [300, 300, 2000, 7][i % 4]).collect();
+            inverse_symbols(&test_data);
+        }
+
+        // u8 works as well
+        inverse_symbols(include_bytes!("../test_data/compression_34k.txt"));
+    }
+
+    #[test]
+    fn test_roundtrip_4096_distinct_u16_symbols() {
+        // all 4096 symbols, more distinct symbols than half of the states of FSE_MAX_TABLELOG
+        let mut test_data: Vec<u16> = (0..4096 * 3).map(|i| (i * 7 % 4096) as u16).collect();
+        test_data.extend_from_slice(&[0; 1000]);
+        let counts = count_symbols(&test_data).unwrap();
+        let (_, table_log) =
+            select_table_log::<u16>(&counts, test_data.len(), &CompressOptions::default()).unwrap();
+        assert_eq!(table_log, 13);
+        inverse_symbols(&test_data);
+        inverse_4_states(&test_data);
+        inverse_4_streams(&test_data);
+
+        // the largest table_log, which needs the most flushes and reloads
+        let table_log = FSE_TABLELOG_ABSOLUTE_MAX;
+        let norm_counts =
+            get_normalized_counts_generic(&counts, table_log, test_data.len(), 4095).unwrap();
+        let comp_table = build_compression_table(&norm_counts, table_log, 4095);
+        let table = build_decompression_table_generic::<u16>(&norm_counts, table_log, 4095);
+        // both parities of the input length, for the join to mod 4
+        for test_data in [&test_data[..], &test_data[1..]] {
+            let compressed = compress::fse_compress(test_data, &comp_table, table_log);
+            let mut output = vec![0_u16; test_data.len()];
+            decompress::fse_decompress_with_container::<u16, BIT_CONTAINER_BYTES_32>(
+                &mut output,
+                compressed.get_compressed_data(),
+                &table,
+                table_log,
+            )
+            .unwrap();
+            assert!(output == test_data);
+
+            let compressed = compress::fse_compress_4_states(test_data, &comp_table, table_log);
+            let mut output = vec![0_u16; test_data.len()];
+            decompress::fse_decompress_4_states_with_container::<u16, BIT_CONTAINER_BYTES_32>(
+                &mut output,
+                compressed.get_compressed_data(),
+                &table,
+                table_log,
+            )
+            .unwrap();
+            assert!(output == test_data);
+        }
+    }
+
+    #[test]
+    fn test_compress_symbols_errors() {
+        let options = CompressOptions::default();
+        assert_eq!(
+            compress_symbols(&[1_u16, 1, 5000], &options),
+            Err(CompressError::SymbolTooLarge)
+        );
+        assert_eq!(
+            compress_symbols(&[3000_u16; 10], &options),
+            Err(CompressError::Rle)
+        );
+        let compressed = inverse_symbols(&[1_u16, 1, 1, 2, 2, 3000]);
+        assert!(decompress_symbols::<u8>(&compressed, 6).is_err());
+        assert!(decompress_symbols::<u16>(&compressed, 7).is_err());
+    }
+
+    #[test]
+    fn test_compress_into() {
+        for test_data in [
+            &include_bytes!("../test_data/compression_1k.txt")[..],
+            &include_bytes!("../test_data/compression_66k_JSON.txt")[..],
+            &get_test_data()[..],
+        ] {
+            let expected = compress(test_data).unwrap();
+            let expected = expected.get_compressed_data();
+
+            let mut dst = vec![0; compress::fse_compressbound(test_data.len())];
+            let compressed_size = compress_into(test_data, &mut dst).unwrap();
+            assert_eq!(&dst[..compressed_size], expected);
+
+            // exact size fits, nothing is written past the end of dst
+            let mut dst = vec![0xAA; expected.len() + 16];
+            let (exact, rest) = dst.split_at_mut(expected.len());
+            assert_eq!(compress_into(test_data, exact), Ok(expected.len()));
+            assert_eq!(exact, expected);
+            assert!(rest.iter().all(|byte| *byte == 0xAA));
+
+            for size in [0, 1, expected.len() / 2, expected.len() - 1] {
+                let (too_small, rest) = dst.split_at_mut(size);
+                rest.iter_mut().for_each(|byte| *byte = 0xAA);
+                assert_eq!(
+                    compress_into(test_data, too_small),
+                    Err(CompressError::OutputTooSmall)
+                );
+                assert!(rest.iter().all(|byte| *byte == 0xAA));
+            }
+        }
+        assert_eq!(
+            compress_into(&[5; 100], &mut [0; 100]),
+            Err(CompressError::Rle)
+        );
+    }
+
+    #[test]
+    fn test_estimate_compressed_size() {
+        for test_data in [
+            &include_bytes!("../test_data/compression_66k_JSON.txt")[..],
+            include_bytes!("../test_data/compression_65k.txt"),
+            include_bytes!("../test_data/compression_34k.txt"),
+            include_bytes!("../test_data/compression_1k.txt"),
+            include_bytes!("../test_data/v4_uuids_93k.txt"),
+            &get_test_data(),
+        ] {
+            let counts = count_simple(test_data);
+            let (norm_counts, _, table_log) = get_normalized_counts_from_data(test_data).unwrap();
+            let estimate = estimate_compressed_size(&counts, &norm_counts, table_log).unwrap();
+
+            let compressed = compress_frame(test_data).unwrap();
+            let actual = compressed.len() - frame::FRAME_HEADER_SIZE;
+            // the interpolated costs of fse_bit_cost are slightly too high, within 2%
+            let tolerance = actual / 50 + 4;
+            assert!(
+                estimate.abs_diff(actual) <= tolerance,
+                "estimate {} actual {}",
+                estimate,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn test_estimate_compressed_size_norm_counts() {
+        // a predefined distribution covering more symbols than the data
+        let sample = include_bytes!("../test_data/compression_66k_JSON.txt");
+        let (norm_counts, max_symbol_value, table_log) =
+            get_normalized_counts_from_data(sample).unwrap();
+        let table = build_compression_table(&norm_counts, table_log, max_symbol_value);
+        for message in [&b"{\"a\": 1}"[..], &sample[..1000], sample] {
+            let counts = count_simple(message);
+            let estimate = estimate_compressed_size(&counts, &norm_counts, table_log).unwrap();
+            let stream_bits = table::estimate_stream_bits(&table, &counts, table_log);
+            assert_eq!(
+                stream_bits,
+                table::estimate_stream_bits_from_norm_counts(&norm_counts, &counts, table_log)
+            );
+            assert!(estimate > stream_bits.div_ceil(8));
+        }
+
+        let counts = count_simple(b"{\"a\": 1}\x00");
+        assert_eq!(
+            estimate_compressed_size(&counts, &norm_counts, table_log),
+            Err(CompressError::ZeroProbabilitySymbol(0))
+        );
+        assert_eq!(
+            estimate_compressed_size(&counts, &norm_counts[..10], table_log),
+            Err(CompressError::InvalidNormCounts(
+                HistError::IncorrectNormalizedDistribution
+            ))
+        );
+        let counts = count_simple(b"abc");
+        assert_eq!(
+            estimate_compressed_size(&counts, &norm_counts, table_log + 1),
+            Err(CompressError::InvalidNormCounts(
+                HistError::IncorrectNormalizedDistribution
+            ))
+        );
+        assert_eq!(
+            estimate_compressed_size(&counts, &[0; 256], table_log),
+            Err(CompressError::InvalidNormCounts(
+                HistError::IncorrectNormalizedDistribution
+            ))
+        );
+    }
+
+    #[test]
+    fn test_compress_with_norm_counts() {
+        // a distribution trained on a sample, shared by many small messages
+        let sample = include_bytes!("../test_data/compression_66k_JSON.txt");
+        let (norm_counts, max_symbol_value, table_log) =
+            get_normalized_counts_from_data(sample).unwrap();
+
+        for (i, message) in sample.chunks(37).take(100).enumerate() {
+            let message = &message[..2 + i % 36];
+            let compressed =
+                compress_with_norm_counts(message, &norm_counts, table_log, max_symbol_value)
+                    .unwrap();
+            let compressed = compressed.get_compressed_data();
+            assert!(compressed.len() < message.len() + 3);
+            let decompressed = decompress(
+                compressed,
+                &norm_counts,
+                table_log,
+                message.len(),
+                max_symbol_value,
+            )
+            .unwrap();
+            assert_eq!(decompressed, message);
+        }
+
+        for message in [&b""[..], b"{"] {
+            assert_eq!(
+                compress_with_norm_counts(message, &norm_counts, table_log, max_symbol_value)
+                    .unwrap_err(),
+                CompressError::Rle
+            );
+        }
+    }
+
+    #[test]
+    fn test_compress_with_norm_counts_errors() {
+        let (norm_counts, max_symbol_value, table_log) =
+            get_normalized_counts_from_data(b"aaaabbbbccccdddd").unwrap();
+        assert_eq!(
+            compress_with_norm_counts(b"abcxd", &norm_counts, table_log, max_symbol_value)
+                .unwrap_err(),
+            CompressError::ZeroProbabilitySymbol(b'x' as u32)
+        );
+        // symbols above max_symbol_value
+        assert_eq!(
+            compress_with_norm_counts(&[b'a', 255], &norm_counts, table_log, max_symbol_value)
+                .unwrap_err(),
+            CompressError::ZeroProbabilitySymbol(255)
+        );
+        let mut invalid = norm_counts;
+        invalid[b'a' as usize] += 1;
+        assert_eq!(
+            compress_with_norm_counts(b"abcd", &invalid, table_log, max_symbol_value).unwrap_err(),
+            CompressError::InvalidNormCounts(HistError::IncorrectNormalizedDistribution)
+        );
+        assert_eq!(
+            compress_with_norm_counts(b"abcd", &norm_counts, table_log + 1, max_symbol_value)
+                .unwrap_err(),
+            CompressError::InvalidNormCounts(HistError::IncorrectNormalizedDistribution)
+        );
+    }
+
     fn inverse(test_data: &[u8]) {
         setup();
-        let out = compress(test_data);
+        let out = compress(test_data).unwrap();
         // dbg!(&out.get_compressed_data().len());
         // dbg!(out.bit_pos);
         // dbg!(out.bit_container);
@@ -265,7 +1013,7 @@ mod tests {
         let table_log =
             fse_optimal_table_log(FSE_DEFAULT_TABLELOG, test_data.len(), max_symbol_value);
         let norm_counts =
-            get_normalized_counts(&counts, table_log, test_data.len(), max_symbol_value);
+            get_normalized_counts(&counts, table_log, test_data.len(), max_symbol_value).unwrap();
 
         let decompressed = decompress(
             out.get_compressed_data(),
@@ -273,7 +1021,20 @@ mod tests {
             table_log,
             test_data.len(),
             max_symbol_value,
-        );
+        )
+        .unwrap();
+        assert_eq!(decompressed, test_data);
+
+        // 32 bit decompression, with the same compressed data
+        let decomp_table = build_decompression_table(&norm_counts, table_log, max_symbol_value);
+        let mut decompressed = vec![0; test_data.len()];
+        fse_decompress_with_container::<_, BIT_CONTAINER_BYTES_32>(
+            &mut decompressed,
+            out.get_compressed_data(),
+            &decomp_table,
+            table_log,
+        )
+        .unwrap();
         assert_eq!(decompressed, test_data);
     }
 }
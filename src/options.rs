@@ -0,0 +1,137 @@
+use crate::stream::STREAM_BLOCK_SIZE;
+use crate::{FSE_DEFAULT_MEMORY_USAGE, FSE_DEFAULT_TABLELOG, FSE_MAX_TABLELOG, FSE_MIN_TABLELOG};
+
+/// Compression level used by `CompressOptions::default`
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 3;
+/// Highest compression level, higher levels are treated as this level
+pub const MAX_COMPRESSION_LEVEL: u32 = 5;
+
+/// Parameters of the compression, trading speed against ratio.
+///
+/// Use `CompressOptions::from_level` for a preset, or set the fields directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressOptions {
+    /// Upper limit of the table_log, values above `FSE_MAX_TABLELOG` are clamped.
+    ///
+    /// The actual table_log may be smaller for small inputs, see `fse_optimal_table_log`.
+    pub max_table_log: u32,
+    /// Memory budget of the compression table, as log2 of bytes. The table_log is limited to
+    /// `memory_usage - 2`.
+    pub memory_usage: u32,
+    /// The input is not compressed, if the most frequent symbol occurs less than
+    /// `input.len() >> incompressible_shift` times. Higher values try to compress more inputs.
+    pub incompressible_shift: u32,
+    /// Size of the blocks of `FseEncoder`
+    pub block_size: usize,
+    /// Blocks of frames and `FseEncoder` interleave 4 instead of 2 states in the bitstream, see
+    /// `BlockType::Fse4States`. Faster to decode on out of order cores, but slightly larger.
+    /// Disabled by all levels.
+    pub four_states: bool,
+    /// Blocks of frames and `FseEncoder` are split into 4 independent bitstreams, see
+    /// `BlockType::Fse4Streams`. Takes precedence over `four_states`. Disabled by all levels.
+    pub four_streams: bool,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions::from_level(DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+impl CompressOptions {
+    /// Returns the preset for a compression level, from 1 (fastest) to `MAX_COMPRESSION_LEVEL`
+    /// (best ratio).
+    ///
+    /// Level 0 is treated as level 1 and levels above `MAX_COMPRESSION_LEVEL` as the max level.
+    ///
+    /// | Level | max_table_log | memory_usage | incompressible_shift | block_size |
+    /// |-------|---------------|--------------|----------------------|------------|
+    /// | 1     | 9             | 11           | 5                    | 32 KiB     |
+    /// | 2     | 10            | 12           | 6                    | 64 KiB     |
+    /// | 3     | 11            | 13           | 7                    | 128 KiB    |
+    /// | 4     | 12            | 14           | 7                    | 256 KiB    |
+    /// | 5     | 12            | 14           | 8                    | 1 MiB      |
+    pub fn from_level(level: u32) -> Self {
+        let (max_table_log, memory_usage, incompressible_shift, block_size) =
+            match level.clamp(1, MAX_COMPRESSION_LEVEL) {
+                1 => (9, 11, 5, 32 * 1024),
+                2 => (10, 12, 6, 64 * 1024),
+                3 => (
+                    FSE_DEFAULT_TABLELOG,
+                    FSE_DEFAULT_MEMORY_USAGE,
+                    7,
+                    STREAM_BLOCK_SIZE,
+                ),
+                4 => (12, 14, 7, 256 * 1024),
+                _ => (12, 14, 8, 1024 * 1024),
+            };
+        CompressOptions {
+            max_table_log,
+            memory_usage,
+            incompressible_shift,
+            block_size,
+            four_states: false,
+            four_streams: false,
+        }
+    }
+
+    /// The max table_log, limited by `max_table_log` and `memory_usage`.
+    pub fn table_log_limit(&self) -> u32 {
+        self.max_table_log
+            .min(self.memory_usage.saturating_sub(2))
+            .clamp(FSE_MIN_TABLELOG, FSE_MAX_TABLELOG)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_matches_previous_constants() {
+        let options = CompressOptions::default();
+        assert_eq!(options.table_log_limit(), FSE_DEFAULT_TABLELOG);
+        assert_eq!(options.incompressible_shift, 7);
+        assert_eq!(options.block_size, STREAM_BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(
+            CompressOptions::from_level(0),
+            CompressOptions::from_level(1)
+        );
+        assert_eq!(
+            CompressOptions::from_level(100),
+            CompressOptions::from_level(MAX_COMPRESSION_LEVEL)
+        );
+        for level in 1..MAX_COMPRESSION_LEVEL {
+            let options = CompressOptions::from_level(level);
+            let next = CompressOptions::from_level(level + 1);
+            assert!(options.table_log_limit() <= next.table_log_limit());
+            assert!(options.block_size < next.block_size);
+        }
+    }
+
+    #[test]
+    fn test_table_log_limit() {
+        let options = CompressOptions {
+            max_table_log: 20,
+            memory_usage: 20,
+            ..Default::default()
+        };
+        assert_eq!(options.table_log_limit(), FSE_MAX_TABLELOG);
+        let options = CompressOptions {
+            max_table_log: 12,
+            memory_usage: 10,
+            ..Default::default()
+        };
+        assert_eq!(options.table_log_limit(), 8);
+        let options = CompressOptions {
+            max_table_log: 0,
+            memory_usage: 0,
+            ..Default::default()
+        };
+        assert_eq!(options.table_log_limit(), FSE_MIN_TABLELOG);
+    }
+}
@@ -0,0 +1,433 @@
+/*!
+Block-parallel compression and decompression of large inputs, with `std::thread::scope`.
+
+The input is split into blocks of `block_size`, each block is compressed into an independent
+frame, see `compress_frame`. In contrast to the stream, no block reuses the table of a previous
+block, so the blocks can be counted, normalized and encoded on different threads. An index of the
+frame sizes is written in front of the frames, so the decoder can find the frames without
+decoding them and decompress them in parallel too.
+
+| Field      | Size                 | Description                                  |
+|------------|----------------------|----------------------------------------------|
+| num_blocks | 4 bytes              | number of frames, little endian              |
+| block_size | 4 bytes              | size of all blocks but the last one          |
+| frame_lens | 4 bytes * num_blocks | size of each frame, little endian            |
+| frames     | sum of frame_lens    | frames of the blocks, see `compress_frame`   |
+
+*/
+
+use crate::block::{decompress_block_into, BlockType};
+use crate::error::DecodeError;
+use crate::frame::{
+    compress_frame_with_options, max_frame_len, read_frame_header, FRAME_HEADER_SIZE,
+};
+use crate::options::CompressOptions;
+use crate::table::DecompressionTable;
+use std::convert::TryInto;
+use std::thread;
+
+/// Size of the number of blocks, the block size and each entry in the index of the frame sizes
+pub const PARALLEL_INDEX_ENTRY_SIZE: usize = 4;
+
+/// Splits input into blocks of `block_size` and compresses them into independent frames on
+/// `threads` threads.
+///
+/// Each thread compresses a contiguous range of blocks. `threads` of 0 is treated as 1.
+///
+/// The `block_size` is written into the index, `decompress_parallel` reads the max size of the
+/// blocks from there.
+pub fn compress_parallel(input: &[u8], block_size: usize, threads: usize) -> Vec<u8> {
+    compress_parallel_with_options(input, block_size, threads, &CompressOptions::default())
+}
+
+/// Like `compress_parallel`, with the table_log, bitstream layout and incompressibility
+/// threshold of `options`.
+pub fn compress_parallel_with_options(
+    input: &[u8],
+    block_size: usize,
+    threads: usize,
+    options: &CompressOptions,
+) -> Vec<u8> {
+    assert!(block_size > 0 && block_size <= u32::MAX as usize);
+    let blocks: Vec<&[u8]> = input.chunks(block_size).collect();
+    assert!(blocks.len() <= u32::MAX as usize);
+
+    let mut frames = vec![Vec::new(); blocks.len()];
+    let blocks_per_thread = blocks_per_thread(blocks.len(), threads);
+    thread::scope(|scope| {
+        for (blocks, frames) in blocks
+            .chunks(blocks_per_thread)
+            .zip(frames.chunks_mut(blocks_per_thread))
+        {
+            scope.spawn(move || {
+                for (block, frame) in blocks.iter().zip(frames.iter_mut()) {
+                    // the block size is at most u32::MAX
+                    *frame = compress_frame_with_options(block, options)
+                        .expect("block fits into a frame");
+                }
+            });
+        }
+    });
+
+    write_block_index(block_size, &frames)
+}
+
+/// Writes the index of the frame sizes, followed by the frames.
+fn write_block_index(block_size: usize, frames: &[Vec<u8>]) -> Vec<u8> {
+    let index_size = PARALLEL_INDEX_ENTRY_SIZE * (2 + frames.len());
+    let frames_size: usize = frames.iter().map(|frame| frame.len()).sum();
+    let mut out = Vec::with_capacity(index_size + frames_size);
+    out.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(block_size as u32).to_le_bytes());
+    for frame in frames {
+        assert!(frame.len() <= u32::MAX as usize);
+        out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    }
+    for frame in frames {
+        out.extend_from_slice(frame);
+    }
+    out
+}
+
+/// Decompresses the output of `compress_parallel` on `threads` threads.
+///
+/// The original sizes in the frame headers determine where each block is written, so the
+/// threads decompress into disjoint parts of the output. `threads` of 0 is treated as 1.
+///
+/// The frames need to be independent, `BlockType::Repeat*` frames are rejected with
+/// `DecodeError::MissingRepeatTable`. Each block is checked against the block size in the index,
+/// so any `block_size` of `compress_parallel` is accepted. Untrusted input should be decompressed
+/// with `decompress_parallel_with_max_block_size`, which also limits the block size in the index.
+pub fn decompress_parallel(input: &[u8], threads: usize) -> Result<Vec<u8>, DecodeError> {
+    decompress_parallel_with_max_block_size(input, threads, u32::MAX as usize)
+}
+
+/// Like `decompress_parallel`, but accepts a block size up to `max_block_size`.
+///
+/// The size of each block is checked against the block size in the index, before the output is
+/// allocated.
+pub fn decompress_parallel_with_max_block_size(
+    input: &[u8],
+    threads: usize,
+    max_block_size: usize,
+) -> Result<Vec<u8>, DecodeError> {
+    let (block_size, frames) = read_block_index(input, max_block_size)?;
+    let mut orig_sizes = Vec::with_capacity(frames.len());
+    let mut total_size = 0_usize;
+    for (block_index, frame) in frames.iter().enumerate() {
+        let (_, orig_size) = read_independent_frame_header(frame, block_size)?;
+        // only the last block may be smaller
+        if orig_size != block_size && block_index + 1 != frames.len() {
+            return Err(DecodeError::InvalidBlockIndex);
+        }
+        total_size = total_size
+            .checked_add(orig_size)
+            .ok_or(DecodeError::InvalidBlockIndex)?;
+        orig_sizes.push(orig_size);
+    }
+
+    let mut output = vec![0; total_size];
+    let mut outputs = Vec::with_capacity(frames.len());
+    let mut rest = &mut output[..];
+    for orig_size in orig_sizes {
+        let (block_output, tail) = std::mem::take(&mut rest).split_at_mut(orig_size);
+        outputs.push(block_output);
+        rest = tail;
+    }
+
+    let blocks_per_thread = blocks_per_thread(frames.len(), threads);
+    thread::scope(|scope| {
+        let handles: Vec<_> = frames
+            .chunks(blocks_per_thread)
+            .zip(outputs.chunks_mut(blocks_per_thread))
+            .map(|(frames, outputs)| {
+                scope.spawn(move || decompress_frames(frames, outputs, block_size))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .try_for_each(|handle| handle.join().unwrap())
+    })?;
+    Ok(output)
+}
+
+/// Decompresses each frame into its output, reusing the table and buffers between the frames.
+fn decompress_frames(
+    frames: &[&[u8]],
+    outputs: &mut [&mut [u8]],
+    block_size: usize,
+) -> Result<(), DecodeError> {
+    let mut table = DecompressionTable::default();
+    let mut next_symbol_table = vec![];
+    let mut buffer = vec![];
+    for (frame, output) in frames.iter().zip(outputs.iter_mut()) {
+        let (block_type, orig_size) = read_independent_frame_header(frame, block_size)?;
+        decompress_block_into(
+            block_type,
+            &frame[FRAME_HEADER_SIZE..],
+            orig_size,
+            &mut table,
+            &mut next_symbol_table,
+            &mut buffer,
+        )?;
+        if buffer.len() != output.len() {
+            return Err(DecodeError::OutputSizeMismatch);
+        }
+        output.copy_from_slice(&buffer);
+    }
+    Ok(())
+}
+
+/// Returns the block type and the original size of a frame, which doesn't depend on the table of
+/// a previous frame.
+fn read_independent_frame_header(
+    frame: &[u8],
+    block_size: usize,
+) -> Result<(BlockType, usize), DecodeError> {
+    let (block_type, orig_size) = read_frame_header(frame, block_size)?;
+    match block_type {
+        BlockType::Repeat | BlockType::Repeat4States | BlockType::Repeat4Streams => {
+            Err(DecodeError::MissingRepeatTable)
+        }
+        _ => Ok((block_type, orig_size)),
+    }
+}
+
+/// Returns the block size and the frames listed in the index at the start of input.
+fn read_block_index(
+    input: &[u8],
+    max_block_size: usize,
+) -> Result<(usize, Vec<&[u8]>), DecodeError> {
+    const INDEX_START: usize = 2 * PARALLEL_INDEX_ENTRY_SIZE;
+    if input.len() < INDEX_START {
+        return Err(DecodeError::InvalidBlockIndex);
+    }
+    let num_blocks =
+        u32::from_le_bytes(input[..PARALLEL_INDEX_ENTRY_SIZE].try_into().unwrap()) as usize;
+    let block_size = u32::from_le_bytes(
+        input[PARALLEL_INDEX_ENTRY_SIZE..INDEX_START]
+            .try_into()
+            .unwrap(),
+    ) as usize;
+    if block_size > max_block_size {
+        return Err(DecodeError::BlockTooLarge(block_size));
+    }
+    let index_end = num_blocks
+        .checked_add(2)
+        .and_then(|entries| entries.checked_mul(PARALLEL_INDEX_ENTRY_SIZE))
+        .filter(|index_end| *index_end <= input.len())
+        .ok_or(DecodeError::InvalidBlockIndex)?;
+
+    let mut data = &input[index_end..];
+    let mut frames = Vec::with_capacity(num_blocks);
+    for entry in input[INDEX_START..index_end].chunks_exact(PARALLEL_INDEX_ENTRY_SIZE) {
+        let frame_len = u32::from_le_bytes(entry.try_into().unwrap()) as usize;
+        if frame_len > data.len() || frame_len > max_frame_len(block_size) {
+            return Err(DecodeError::InvalidBlockIndex);
+        }
+        let (frame, rest) = data.split_at(frame_len);
+        frames.push(frame);
+        data = rest;
+    }
+    if !data.is_empty() {
+        return Err(DecodeError::InvalidBlockIndex);
+    }
+    Ok((block_size, frames))
+}
+
+/// Number of blocks in the contiguous range of each thread, at least 1.
+fn blocks_per_thread(num_blocks: usize, threads: usize) -> usize {
+    num_blocks.div_ceil(threads.max(1)).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::compress_frame;
+    use crate::CompressionContext;
+
+    fn inverse(test_data: &[u8], block_size: usize, threads: usize) -> Vec<u8> {
+        let compressed = compress_parallel(test_data, block_size, threads);
+        let decompressed = decompress_parallel(&compressed, threads).unwrap();
+        assert_eq!(decompressed, test_data);
+        compressed
+    }
+
+    #[test]
+    fn test_parallel_roundtrip() {
+        let test_data = include_bytes!("../test_data/compression_66k_JSON.txt");
+        for block_size in [1, 100, 1000, 4096, 1 << 20] {
+            for threads in [0, 1, 3, 8] {
+                inverse(test_data, block_size, threads);
+            }
+        }
+        inverse(
+            include_bytes!("../test_data/v4_uuids_93k.txt"),
+            16 * 1024,
+            4,
+        );
+        inverse(include_bytes!("../test_data/logo.jpg"), 16 * 1024, 4);
+        inverse(&[7; 10_000], 1000, 4);
+        inverse(&[], 1000, 4);
+    }
+
+    #[test]
+    fn test_parallel_layouts() {
+        let test_data = include_bytes!("../test_data/compression_65k.txt");
+        for options in [
+            CompressOptions::default(),
+            CompressOptions {
+                four_states: true,
+                ..CompressOptions::default()
+            },
+            CompressOptions {
+                four_streams: true,
+                ..CompressOptions::default()
+            },
+        ] {
+            let compressed = compress_parallel_with_options(test_data, 8 * 1024, 3, &options);
+            assert_eq!(decompress_parallel(&compressed, 2).unwrap(), test_data);
+        }
+    }
+
+    #[test]
+    fn test_parallel_independent_frames() {
+        let test_data = include_bytes!("../test_data/compression_66k_JSON.txt");
+        let block_size = 16 * 1024;
+        let compressed = inverse(test_data, block_size, 3);
+
+        let frames: Vec<Vec<u8>> = test_data
+            .chunks(block_size)
+            .map(|chunk| compress_frame(chunk).unwrap())
+            .collect();
+        let (index_block_size, frames_ref) = read_block_index(&compressed, usize::MAX).unwrap();
+        assert_eq!(index_block_size, block_size);
+        assert_eq!(frames_ref.len(), frames.len());
+        for (frame, expected) in frames_ref.iter().zip(frames.iter()) {
+            assert_eq!(frame, expected);
+            assert_ne!(frame[0], BlockType::Repeat as u8);
+        }
+
+        assert_eq!(
+            compress_parallel(&[], 1000, 4),
+            vec![0, 0, 0, 0, 0xE8, 3, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_parallel_repeat_frames() {
+        // frames of a context reuse the table of the previous frame
+        let test_data = include_bytes!("../test_data/v4_uuids_93k.txt");
+        let mut comp_ctx = CompressionContext::new();
+        let frames: Vec<Vec<u8>> = test_data
+            .chunks(10_000)
+            .map(|chunk| comp_ctx.compress_frame(chunk).unwrap().to_vec())
+            .collect();
+        assert!(frames
+            .iter()
+            .any(|frame| frame[0] == BlockType::Repeat as u8));
+        let compressed = write_block_index(10_000, &frames);
+        for threads in [1, 2, 8, 100] {
+            assert!(matches!(
+                decompress_parallel(&compressed, threads),
+                Err(DecodeError::MissingRepeatTable)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_parallel_invalid_index() {
+        let compressed =
+            compress_parallel(include_bytes!("../test_data/compression_34k.txt"), 4096, 2);
+        let invalid_index = |input: &[u8]| {
+            matches!(
+                decompress_parallel(input, 2),
+                Err(DecodeError::InvalidBlockIndex)
+            )
+        };
+        assert!(invalid_index(&[0, 0]));
+        assert!(invalid_index(&[0, 0, 0, 0, 0]));
+        assert!(invalid_index(&[1, 0, 0, 0, 0, 1, 0, 0]));
+        assert!(invalid_index(&[0xFF, 0xFF, 0xFF, 0xFF, 0, 1, 0, 0]));
+        assert!(invalid_index(&compressed[..compressed.len() - 1]));
+
+        let mut trailing = compressed.clone();
+        trailing.push(0);
+        assert!(invalid_index(&trailing));
+
+        let mut wrong_len = compressed.clone();
+        wrong_len[2 * PARALLEL_INDEX_ENTRY_SIZE] += 1;
+        assert!(decompress_parallel(&wrong_len, 2).is_err());
+    }
+
+    #[test]
+    fn test_parallel_corrupted_no_panic() {
+        let compressed =
+            compress_parallel(include_bytes!("../test_data/compression_1k.txt"), 256, 3);
+        for len in 0..compressed.len() {
+            assert!(decompress_parallel(&compressed[..len], 3).is_err());
+        }
+
+        // xorshift, flips random bits in the payload of the first frame
+        let (_, frames) = read_block_index(&compressed, usize::MAX).unwrap();
+        let payload_start = PARALLEL_INDEX_ENTRY_SIZE * (2 + frames.len()) + FRAME_HEADER_SIZE;
+        let payload_len = frames[0].len() - FRAME_HEADER_SIZE;
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        for _ in 0..2_000 {
+            let mut corrupted = compressed.clone();
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let pos = payload_start + (seed >> 8) as usize % payload_len;
+            corrupted[pos] ^= 1 << (seed % 8);
+            let _ = decompress_parallel(&corrupted, 3);
+        }
+    }
+
+    #[test]
+    fn test_parallel_max_block_size() {
+        let test_data = include_bytes!("../test_data/compression_34k.txt");
+        let compressed = compress_parallel(test_data, 8 * 1024, 2);
+        assert!(matches!(
+            decompress_parallel_with_max_block_size(&compressed, 2, 8 * 1024 - 1),
+            Err(DecodeError::BlockTooLarge(8192))
+        ));
+        assert_eq!(
+            decompress_parallel_with_max_block_size(&compressed, 2, 8 * 1024).unwrap(),
+            &test_data[..]
+        );
+
+        // any block size of compress_parallel round-trips
+        let test_data = vec![7; 9 << 20];
+        let compressed = compress_parallel(&test_data, 4 << 20, 2);
+        assert_eq!(decompress_parallel(&compressed, 2).unwrap(), test_data);
+        assert!(matches!(
+            decompress_parallel_with_max_block_size(&compressed, 2, 1 << 20),
+            Err(DecodeError::BlockTooLarge(_))
+        ));
+
+        // rle frames claiming 1 GiB each, rejected before allocating
+        let rle_frame = vec![BlockType::Rle as u8, 0, 0, 0, 0x40, 7];
+        let compressed = write_block_index(0x4000_0000, &vec![rle_frame.clone(); 1000]);
+        assert!(matches!(
+            decompress_parallel_with_max_block_size(&compressed, 2, 1 << 20),
+            Err(DecodeError::BlockTooLarge(0x4000_0000))
+        ));
+        let compressed = write_block_index(1000, &vec![rle_frame; 1000]);
+        assert!(matches!(
+            decompress_parallel(&compressed, 2),
+            Err(DecodeError::BlockTooLarge(0x4000_0000))
+        ));
+
+        // a block in the middle, which is smaller than the block size
+        let frames: Vec<Vec<u8>> = [&[1; 100][..], &[2; 50], &[3; 100]]
+            .iter()
+            .map(|block| compress_frame(block).unwrap())
+            .collect();
+        let compressed = write_block_index(100, &frames);
+        assert!(matches!(
+            decompress_parallel(&compressed, 2),
+            Err(DecodeError::InvalidBlockIndex)
+        ));
+    }
+}
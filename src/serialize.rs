@@ -0,0 +1,490 @@
+/*!
+Binary format of prebuilt `CompressionTable` and `DecompressionTable`, so tables of fixed
+distributions can be cached on disk or embedded with `include_bytes!` instead of being built at
+startup.
+
+All values are little endian. Both formats start with the same header:
+
+| Field     | Size   | Description                                      |
+|-----------|--------|--------------------------------------------------|
+| version   | 1 byte | `TABLE_FORMAT_VERSION`                           |
+| kind      | 1 byte | 0 for a compression, 1 for a decompression table |
+| table_log | 1 byte | `FSE_MIN_TABLELOG..=Symbol::MAX_TABLE_LOG`       |
+
+`CompressionTable`:
+
+| Field       | Size                 | Description                                  |
+|-------------|----------------------|----------------------------------------------|
+| num_states  | 4 bytes              | number of entries of `state_table`           |
+| num_symbols | 4 bytes              | number of entries of `symbol_tt`             |
+| state_table | 2 bytes * num_states | next state values                            |
+| symbol_tt   | 8 bytes * num_symbols| `delta_find_state` i32, `delta_nb_bits` u32  |
+
+`DecompressionTable`:
+
+| Field        | Size                                    | Description                         |
+|--------------|-----------------------------------------|-------------------------------------|
+| symbol_bytes | 1 byte                                  | size of a symbol, 1 (u8) or 2 (u16) |
+| fast         | 1 byte                                  | 0 or 1, see `DecompressionTable`    |
+| table        | (3 + symbol_bytes) * (1 << table_log)   | `new_state` u16, symbol, `nb_bits`  |
+
+Loading validates the tables, so a loaded table is as safe to use as a built one. The compression
+functions panic on input symbols beyond the symbols of the table, and input symbols with a zero
+count produce a corrupted bitstream, but they never access the tables out of bounds.
+
+*/
+
+use crate::error::DecodeError;
+use crate::table::{
+    CompressionTable, DecompressionTable, FseDecode, FseSymbolCompressionTransform,
+};
+use crate::{FSE_MIN_TABLELOG, FSE_TABLELOG_ABSOLUTE_MAX};
+use bitstream::highbit_pos;
+use common::{Symbol, MAX_SYMBOL_VALUE_U16};
+use std::convert::TryInto;
+
+/// Version of the binary table format, tables of other versions are rejected when loading
+pub const TABLE_FORMAT_VERSION: u8 = 1;
+
+const KIND_COMPRESSION: u8 = 0;
+const KIND_DECOMPRESSION: u8 = 1;
+
+impl CompressionTable {
+    /// The table_log the table was built with.
+    pub fn table_log(&self) -> u32 {
+        // the state table has 1 << table_log entries and a trailing unused one
+        highbit_pos(self.state_table.len() as u32)
+    }
+
+    /// Serializes the table, see the module documentation for the format.
+    ///
+    /// Panics if the table is empty, like `CompressionTable::default()`, which has no table_log.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!(
+            !self.state_table.is_empty() && !self.symbol_tt.is_empty(),
+            "can't serialize an empty compression table, build it first"
+        );
+        let mut out =
+            Vec::with_capacity(11 + self.state_table.len() * 2 + self.symbol_tt.len() * 8);
+        out.extend_from_slice(&[
+            TABLE_FORMAT_VERSION,
+            KIND_COMPRESSION,
+            self.table_log() as u8,
+        ]);
+        out.extend_from_slice(&(self.state_table.len() as u32).to_le_bytes());
+        out.extend_from_slice(&(self.symbol_tt.len() as u32).to_le_bytes());
+        for state in &self.state_table {
+            out.extend_from_slice(&state.to_le_bytes());
+        }
+        for symbol_tt in &self.symbol_tt {
+            out.extend_from_slice(&symbol_tt.delta_find_state.to_le_bytes());
+            out.extend_from_slice(&symbol_tt.delta_nb_bits.to_le_bytes());
+        }
+        out
+    }
+
+    /// Loads a table written by `to_bytes`.
+    ///
+    /// Returns an error if the table is truncated, has an other version, or the transforms
+    /// don't describe a valid distribution.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { data };
+        // the symbol type is only known when compressing, see `check_symbols`
+        let table_log = read_header(&mut reader, KIND_COMPRESSION, FSE_TABLELOG_ABSOLUTE_MAX)?;
+        let table_size = 1_u32 << table_log;
+
+        let num_states = reader.read_u32()? as usize;
+        let num_symbols = reader.read_u32()? as usize;
+        if num_states < table_size as usize || highbit_pos(num_states as u32) != table_log {
+            return Err(DecodeError::InvalidTable(
+                "number of states does not match table_log",
+            ));
+        }
+        if num_symbols == 0 || num_symbols > MAX_SYMBOL_VALUE_U16 as usize + 1 {
+            return Err(DecodeError::InvalidTable("invalid number of symbols"));
+        }
+
+        let state_table = (0..num_states)
+            .map(|_| reader.read_u16())
+            .collect::<Result<Vec<_>, _>>()?;
+        // the states are in table_size..2 * table_size, the trailing states are never used
+        if state_table[..table_size as usize]
+            .iter()
+            .any(|&state| (state as u32) < table_size || state as u32 >= 2 * table_size)
+        {
+            return Err(DecodeError::InvalidTable("state out of range"));
+        }
+
+        let mut symbol_tt = Vec::with_capacity(num_symbols);
+        let mut total = 0_u32;
+        for _ in 0..num_symbols {
+            let mut transform = FseSymbolCompressionTransform {
+                delta_find_state: reader.read_u32()? as i32,
+                delta_nb_bits: reader.read_u32()?,
+            };
+            // recover the normalized count and check the transform against it, this
+            // guarantees the encoder only accesses the states of the symbol
+            let norm_count = transform_norm_count(&transform, table_log)
+                .ok_or(DecodeError::InvalidTable("invalid symbol transform"))?;
+            if norm_count == 0 {
+                // with the fake cost, an unused symbol in the input moves to the first state
+                transform.delta_nb_bits =
+                    FseSymbolCompressionTransform::delta_nb_bits(0, table_log);
+            } else {
+                total += norm_count;
+                if total > table_size
+                    || transform.delta_find_state != total as i32 - 2 * norm_count as i32
+                {
+                    return Err(DecodeError::InvalidTable("invalid symbol transform"));
+                }
+            }
+            symbol_tt.push(transform);
+        }
+        if total != table_size {
+            return Err(DecodeError::InvalidTable(
+                "normalized counts don't sum up to the table size",
+            ));
+        }
+        reader.finish()?;
+
+        Ok(CompressionTable {
+            state_table,
+            symbol_tt,
+        })
+    }
+}
+
+/// Returns the normalized count of the symbol of a transform, 0 for unused symbols, or None if
+/// the transform is not one built by `build_compression_table`.
+fn transform_norm_count(transform: &FseSymbolCompressionTransform, table_log: u32) -> Option<u32> {
+    let table_size = 1 << table_log;
+    // unused symbols, with or without the fake cost of table_log + 1 bits
+    if transform.delta_find_state == 0
+        && (transform.delta_nb_bits == 0
+            || transform.delta_nb_bits == ((table_log + 1) << 16) - table_size)
+    {
+        return Some(0);
+    }
+    let max_bits_out = transform.fse_get_max_nb_bits();
+    if max_bits_out == 0 || max_bits_out > table_log {
+        return None;
+    }
+    let min_state_plus = (max_bits_out << 16).checked_sub(transform.delta_nb_bits)?;
+    let norm_count = min_state_plus >> max_bits_out;
+    if norm_count == 0 || norm_count > table_size {
+        return None;
+    }
+    let expected_max_bits_out = table_log - highbit_pos(norm_count.max(2) - 1);
+    if norm_count << max_bits_out == min_state_plus && max_bits_out == expected_max_bits_out {
+        Some(norm_count)
+    } else {
+        None
+    }
+}
+
+impl<S: Symbol> DecompressionTable<S> {
+    /// The table_log the table was built with.
+    pub fn table_log(&self) -> u32 {
+        highbit_pos(self.table.len() as u32)
+    }
+
+    /// Serializes the table, see the module documentation for the format.
+    ///
+    /// Panics if the table is empty, like `DecompressionTable::default()`, which has no
+    /// table_log.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!(
+            !self.table.is_empty(),
+            "can't serialize an empty decompression table, build it first"
+        );
+        let symbol_bytes = std::mem::size_of::<S>();
+        let mut out = Vec::with_capacity(5 + self.table.len() * (3 + symbol_bytes));
+        out.extend_from_slice(&[
+            TABLE_FORMAT_VERSION,
+            KIND_DECOMPRESSION,
+            self.table_log() as u8,
+            symbol_bytes as u8,
+            self.fast as u8,
+        ]);
+        for decode in &self.table {
+            out.extend_from_slice(&decode.new_state.to_le_bytes());
+            out.extend_from_slice(&(decode.symbol.to_usize() as u16).to_le_bytes()[..symbol_bytes]);
+            out.push(decode.nb_bits);
+        }
+        out
+    }
+
+    /// Loads a table written by `to_bytes`, for the same symbol type.
+    ///
+    /// Returns an error if the table is truncated, has an other version or symbol type, or the
+    /// states would point outside of the table.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { data };
+        let table_log = read_header(&mut reader, KIND_DECOMPRESSION, S::MAX_TABLE_LOG)?;
+        let table_size = 1_usize << table_log;
+
+        let symbol_bytes = reader.read_u8()? as usize;
+        if symbol_bytes != std::mem::size_of::<S>() {
+            return Err(DecodeError::InvalidTable("symbol size does not match"));
+        }
+        let fast = match reader.read_u8()? {
+            0 => false,
+            1 => true,
+            _ => return Err(DecodeError::InvalidTable("invalid fast flag")),
+        };
+
+        let mut table = Vec::with_capacity(table_size);
+        for _ in 0..table_size {
+            let new_state = reader.read_u16()?;
+            let symbol = if symbol_bytes == 1 {
+                reader.read_u8()? as u32
+            } else {
+                reader.read_u16()? as u32
+            };
+            let nb_bits = reader.read_u8()?;
+            if symbol > S::MAX_SYMBOL_VALUE {
+                return Err(DecodeError::InvalidTable("symbol too large"));
+            }
+            // the decoder reads nb_bits into the next state, which has to stay in the table
+            if nb_bits as u32 > table_log || new_state as usize + (1 << nb_bits) > table_size {
+                return Err(DecodeError::InvalidTable("state out of range"));
+            }
+            // the fast decoder can't read 0 bits
+            if fast && nb_bits == 0 {
+                return Err(DecodeError::InvalidTable(
+                    "fast flag set with a 0 bit state",
+                ));
+            }
+            table.push(FseDecode {
+                new_state,
+                symbol: S::from_usize(symbol as usize),
+                nb_bits,
+            });
+        }
+        reader.finish()?;
+
+        Ok(DecompressionTable { table, fast })
+    }
+}
+
+/// Reads the common header, returns the table_log.
+fn read_header(reader: &mut Reader, kind: u8, max_table_log: u32) -> Result<u32, DecodeError> {
+    let version = reader.read_u8()?;
+    if version != TABLE_FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedTableVersion(version));
+    }
+    if reader.read_u8()? != kind {
+        return Err(DecodeError::InvalidTable("wrong kind of table"));
+    }
+    let table_log = reader.read_u8()? as u32;
+    if !(FSE_MIN_TABLELOG..=max_table_log).contains(&table_log) {
+        return Err(DecodeError::InvalidTable("table_log out of range"));
+    }
+    Ok(table_log)
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, num_bytes: usize) -> Result<&'a [u8], DecodeError> {
+        if self.data.len() < num_bytes {
+            return Err(DecodeError::InvalidTable("table is truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(num_bytes);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    fn finish(&self) -> Result<(), DecodeError> {
+        if !self.data.is_empty() {
+            return Err(DecodeError::InvalidTable("trailing bytes after the table"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress::fse_compress;
+    use crate::decompress::fse_decompress;
+    use crate::table::{build_compression_table, build_decompression_table_generic};
+    use common::{count_symbols, get_normalized_counts_from_data, get_normalized_counts_generic};
+
+    const TEST_DATA: &[&[u8]] = &[
+        include_bytes!("../test_data/compression_66k_JSON.txt"),
+        include_bytes!("../test_data/compression_1k.txt"),
+        include_bytes!("../test_data/v4_uuids_19k.txt"),
+        include_bytes!("../test_data/logo.jpg"),
+        b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaab",
+    ];
+
+    #[test]
+    fn test_table_roundtrip() {
+        for test_data in TEST_DATA {
+            let (norm_counts, max_symbol_value, table_log) =
+                get_normalized_counts_from_data(test_data).unwrap();
+            let comp = build_compression_table(&norm_counts, table_log, max_symbol_value);
+            let decomp: DecompressionTable =
+                build_decompression_table_generic(&norm_counts, table_log, max_symbol_value);
+
+            let comp_bytes = comp.to_bytes();
+            let loaded_comp = CompressionTable::from_bytes(&comp_bytes).unwrap();
+            assert_eq!(loaded_comp.table_log(), table_log);
+            assert_eq!(loaded_comp.to_bytes(), comp_bytes);
+
+            let decomp_bytes = decomp.to_bytes();
+            let loaded_decomp = DecompressionTable::<u8>::from_bytes(&decomp_bytes).unwrap();
+            assert_eq!(loaded_decomp.table_log(), table_log);
+            assert_eq!(loaded_decomp.fast, decomp.fast);
+            assert_eq!(loaded_decomp.to_bytes(), decomp_bytes);
+
+            // the loaded tables compress and decompress like the built ones
+            let compressed = fse_compress(test_data, &loaded_comp, table_log);
+            assert_eq!(
+                compressed.get_compressed_data(),
+                fse_compress(test_data, &comp, table_log).get_compressed_data()
+            );
+            let mut decompressed = vec![0; test_data.len()];
+            fse_decompress(
+                &mut decompressed,
+                compressed.get_compressed_data(),
+                &loaded_decomp,
+                table_log,
+            )
+            .unwrap();
+            assert_eq!(&decompressed, test_data);
+        }
+    }
+
+    #[test]
+    fn test_table_roundtrip_u16() {
+        let test_data: Vec<u16> = (0..10_000_u32).map(|i| ((i * i) % 1000) as u16).collect();
+        let counts = count_symbols(&test_data).unwrap();
+        let norm_counts = get_normalized_counts_generic(&counts, 12, test_data.len(), 999).unwrap();
+        let decomp: DecompressionTable<u16> =
+            build_decompression_table_generic(&norm_counts, 12, 999);
+        let bytes = decomp.to_bytes();
+        let loaded = DecompressionTable::<u16>::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert!(matches!(
+            DecompressionTable::<u8>::from_bytes(&bytes),
+            Err(DecodeError::InvalidTable(_))
+        ));
+    }
+
+    #[test]
+    fn test_table_invalid() {
+        let (norm_counts, max_symbol_value, table_log) =
+            get_normalized_counts_from_data(TEST_DATA[0]).unwrap();
+        let comp_bytes =
+            build_compression_table(&norm_counts, table_log, max_symbol_value).to_bytes();
+        let decomp: DecompressionTable =
+            build_decompression_table_generic(&norm_counts, table_log, max_symbol_value);
+        let decomp_bytes = decomp.to_bytes();
+
+        let mut other_version = comp_bytes.clone();
+        other_version[0] = 2;
+        assert!(matches!(
+            CompressionTable::from_bytes(&other_version),
+            Err(DecodeError::UnsupportedTableVersion(2))
+        ));
+        assert!(DecompressionTable::<u8>::from_bytes(&comp_bytes).is_err());
+        assert!(CompressionTable::from_bytes(&decomp_bytes).is_err());
+
+        for len in 0..comp_bytes.len() {
+            assert!(CompressionTable::from_bytes(&comp_bytes[..len]).is_err());
+        }
+        for len in 0..decomp_bytes.len() {
+            assert!(DecompressionTable::<u8>::from_bytes(&decomp_bytes[..len]).is_err());
+        }
+
+        // loaded tables are either rejected or usable without out of bounds accesses, also for
+        // symbols which are not in the table
+        let test_data = &TEST_DATA[0][..300];
+        for pos in 0..comp_bytes.len() {
+            let mut corrupted = comp_bytes.clone();
+            corrupted[pos] ^= 1 << (pos % 8);
+            if let Ok(table) = CompressionTable::from_bytes(&corrupted) {
+                fse_compress(test_data, &table, table.table_log());
+            }
+        }
+        let compressed = fse_compress(
+            test_data,
+            &CompressionTable::from_bytes(&comp_bytes).unwrap(),
+            table_log,
+        );
+        for pos in 0..decomp_bytes.len() {
+            let mut corrupted = decomp_bytes.clone();
+            corrupted[pos] ^= 1 << (pos % 8);
+            if let Ok(table) = DecompressionTable::<u8>::from_bytes(&corrupted) {
+                let mut out = vec![0; test_data.len()];
+                let _ = fse_decompress(
+                    &mut out,
+                    compressed.get_compressed_data(),
+                    &table,
+                    table.table_log(),
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compression_table_unused_symbols() {
+        let test_data = b"aabac".repeat(100);
+        let (norm_counts, max_symbol_value, table_log) =
+            get_normalized_counts_from_data(&test_data).unwrap();
+        let comp = build_compression_table(&norm_counts, table_log, max_symbol_value);
+        let comp_bytes = comp.to_bytes();
+        // the transform of '0' without the fake cost
+        let mut zero_cost_bytes = comp_bytes.clone();
+        let pos = 3 + 8 + 2 * comp.state_table.len() + 8 * b'0' as usize + 4;
+        zero_cost_bytes[pos..pos + 4].copy_from_slice(&[0; 4]);
+
+        // symbols with a zero count give a corrupted bitstream, but no out of bounds access
+        let input = b"0abc0ab0";
+        for table in [
+            comp,
+            CompressionTable::from_bytes(&comp_bytes).unwrap(),
+            CompressionTable::from_bytes(&zero_cost_bytes).unwrap(),
+        ] {
+            fse_compress(&input[..], &table, table_log);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not in the compression table")]
+    fn test_compression_table_missing_symbol() {
+        let test_data = b"aabac".repeat(100);
+        let (norm_counts, max_symbol_value, table_log) =
+            get_normalized_counts_from_data(&test_data).unwrap();
+        let comp = build_compression_table(&norm_counts, table_log, max_symbol_value);
+        fse_compress(&b"abcd"[..], &comp, table_log);
+    }
+
+    #[test]
+    #[should_panic(expected = "empty compression table")]
+    fn test_empty_compression_table_to_bytes() {
+        CompressionTable::default().to_bytes();
+    }
+
+    #[test]
+    #[should_panic(expected = "empty decompression table")]
+    fn test_empty_decompression_table_to_bytes() {
+        DecompressionTable::<u8>::default().to_bytes();
+    }
+}
@@ -0,0 +1,457 @@
+/*!
+Streaming compression and decompression of input, which doesn't fit into memory at once.
+
+The input is split into blocks of `block_size`, each block is compressed into a frame, see
+`compress_frame`. The stream is a sequence of frames, each prefixed by its length. Blocks with
+similar statistics as the previous block reuse its table, see `BlockType::Repeat`, so the frames
+need to be decompressed in order.
+
+| Field     | Size      | Description                                |
+|-----------|-----------|--------------------------------------------|
+| frame_len | 4 bytes   | size of the following frame, little endian |
+| frame     | frame_len | frame of a block, see `compress_frame`     |
+
+*/
+
+use crate::context::{CompressionContext, DecompressionContext};
+use crate::frame::max_frame_len;
+use crate::options::CompressOptions;
+use std::io::{self, BufRead, Read, Write};
+
+/// Size of the blocks, the input is split into, with the default `CompressOptions`
+pub const STREAM_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Size of the length prefix of each frame in the stream
+pub const STREAM_FRAME_LEN_SIZE: usize = 4;
+
+/// Compresses everything written into it into the inner writer.
+///
+/// Input is buffered until a block is full. `finish` writes the last partial block at the end.
+/// Like the writers of `flate2` and `zstd`, dropping the encoder writes it as well, but ignores
+/// errors, so `finish` should be called to handle them.
+#[derive(Debug)]
+pub struct FseEncoder<W: Write> {
+    /// None after `finish`, so the encoder is not flushed again on drop
+    inner: Option<W>,
+    buffer: Vec<u8>,
+    options: CompressOptions,
+    context: CompressionContext,
+}
+
+impl<W: Write> FseEncoder<W> {
+    pub fn new(inner: W) -> Self {
+        Self::with_options(inner, CompressOptions::default())
+    }
+
+    /// Creates an encoder which compresses the input in blocks of `block_size`.
+    ///
+    /// Larger blocks compress better, smaller blocks adapt faster to changing statistics.
+    pub fn with_block_size(inner: W, block_size: usize) -> Self {
+        Self::with_options(
+            inner,
+            CompressOptions {
+                block_size,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Creates an encoder which compresses the input in blocks of `options.block_size`.
+    pub fn with_options(inner: W, options: CompressOptions) -> Self {
+        assert!(options.block_size > 0 && options.block_size <= u32::MAX as usize);
+        FseEncoder {
+            inner: Some(inner),
+            buffer: Vec::with_capacity(options.block_size),
+            options,
+            context: CompressionContext::with_options(options),
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner
+            .as_ref()
+            .expect("inner writer is only taken by finish")
+    }
+
+    /// Writes the last partial block and returns the inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let result = self.flush();
+        // also after an error, a partially written block should not be written again on drop
+        let inner = self
+            .inner
+            .take()
+            .expect("inner writer is only taken by finish");
+        result.map(|()| inner)
+    }
+
+    /// Compresses the buffered input into a frame and writes it to the inner writer.
+    fn write_block(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let inner = self
+            .inner
+            .as_mut()
+            .expect("inner writer is only taken by finish");
+        // the block size is at most u32::MAX, see `with_options`
+        let frame = self
+            .context
+            .compress_frame(&self.buffer)
+            .expect("block fits into a frame");
+        inner.write_all(&(frame.len() as u32).to_le_bytes())?;
+        inner.write_all(frame)?;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for FseEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let num_bytes = buf.len().min(self.options.block_size - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..num_bytes]);
+        if self.buffer.len() == self.options.block_size {
+            self.write_block()?;
+        }
+        Ok(num_bytes)
+    }
+
+    /// Writes the buffered input as a block, which may be smaller than `block_size`.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_block()?;
+        self.inner
+            .as_mut()
+            .expect("inner writer is only taken by finish")
+            .flush()
+    }
+}
+
+impl<W: Write> Drop for FseEncoder<W> {
+    /// Writes the last partial block like `finish`, errors are ignored.
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Decompresses a stream written by `FseEncoder`.
+///
+/// Frames are read and decompressed one at a time, so only a single block is held in memory.
+/// The decoder is created with the block size of the encoder. Frames with a larger decompressed
+/// size are rejected, before the frame or the block is allocated.
+#[derive(Debug)]
+pub struct FseDecoder<R: Read> {
+    inner: R,
+    context: DecompressionContext,
+    max_block_size: usize,
+    /// Size of the current block in the output of the context
+    block_len: usize,
+    pos: usize,
+}
+
+impl<R: Read> FseDecoder<R> {
+    /// Creates a decoder for streams written by `FseEncoder::new`.
+    pub fn new(inner: R) -> Self {
+        Self::with_options(inner, CompressOptions::default())
+    }
+
+    /// Creates a decoder for streams written by `FseEncoder::with_block_size` with a block size
+    /// up to `block_size`.
+    pub fn with_block_size(inner: R, block_size: usize) -> Self {
+        Self::with_options(
+            inner,
+            CompressOptions {
+                block_size,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Creates a decoder for streams written by `FseEncoder::with_options`, only
+    /// `options.block_size` is used.
+    pub fn with_options(inner: R, options: CompressOptions) -> Self {
+        FseDecoder {
+            inner,
+            context: DecompressionContext::with_max_block_size(options.block_size),
+            max_block_size: options.block_size,
+            block_len: 0,
+            pos: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Reads and decompresses the next frame, returns false at the end of the stream.
+    fn read_block(&mut self) -> io::Result<bool> {
+        let mut len = [0_u8; STREAM_FRAME_LEN_SIZE];
+        let mut read = 0;
+        while read < len.len() {
+            match self.inner.read(&mut len[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(num_bytes) => read += num_bytes,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let frame_len = u32::from_le_bytes(len) as u64;
+        if frame_len > max_frame_len(self.max_block_size) as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame is larger than the max block size",
+            ));
+        }
+        let mut frame = vec![];
+        (&mut self.inner).take(frame_len).read_to_end(&mut frame)?;
+        if frame.len() as u64 != frame_len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // the block stays empty, if the frame is corrupted
+        self.pos = 0;
+        self.block_len = 0;
+        self.block_len = self
+            .context
+            .decompress_frame(&frame)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            .len();
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for FseDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let num_bytes = available.len().min(buf.len());
+        buf[..num_bytes].copy_from_slice(&available[..num_bytes]);
+        self.consume(num_bytes);
+        Ok(num_bytes)
+    }
+}
+
+impl<R: Read> BufRead for FseDecoder<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // loop, since blocks may be empty
+        while self.pos == self.block_len {
+            if !self.read_block()? {
+                break;
+            }
+        }
+        Ok(&self.context.output()[self.pos..self.block_len])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.block_len);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::MAX_COMPRESSION_LEVEL;
+    use crate::BlockType;
+    use std::convert::TryInto;
+
+    /// Decompresses the frames of a stream, returns the decompressed data and the number of frames
+    fn decompress_frames(mut stream: &[u8]) -> (Vec<u8>, usize) {
+        let mut context = DecompressionContext::new();
+        let mut out = vec![];
+        let mut num_frames = 0;
+        while !stream.is_empty() {
+            let (len, rest) = stream.split_at(STREAM_FRAME_LEN_SIZE);
+            let (frame, rest) = rest.split_at(u32::from_le_bytes(len.try_into().unwrap()) as usize);
+            out.extend_from_slice(context.decompress_frame(frame).unwrap());
+            num_frames += 1;
+            stream = rest;
+        }
+        (out, num_frames)
+    }
+
+    #[test]
+    fn test_encoder_blocks() {
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_66k_JSON.txt");
+        let mut encoder = FseEncoder::with_block_size(vec![], 16 * 1024);
+        encoder.write_all(TEST_DATA).unwrap();
+        let stream = encoder.finish().unwrap();
+        assert!(stream.len() < TEST_DATA.len());
+
+        let (decompressed, num_frames) = decompress_frames(&stream);
+        assert_eq!(decompressed, TEST_DATA);
+        assert_eq!(num_frames, TEST_DATA.len().div_ceil(16 * 1024));
+    }
+
+    #[test]
+    fn test_encoder_small_writes() {
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_34k.txt");
+        let mut encoder = FseEncoder::with_block_size(vec![], 1000);
+        for chunk in TEST_DATA.chunks(7) {
+            encoder.write_all(chunk).unwrap();
+        }
+        let stream = encoder.finish().unwrap();
+        assert_eq!(decompress_frames(&stream).0, TEST_DATA);
+    }
+
+    #[test]
+    fn test_encoder_flush_and_empty() {
+        let encoder = FseEncoder::new(vec![]);
+        assert!(encoder.finish().unwrap().is_empty());
+
+        let mut encoder = FseEncoder::new(vec![]);
+        encoder.write_all(b"aaabbc").unwrap();
+        encoder.flush().unwrap();
+        encoder.flush().unwrap();
+        encoder.write_all(&[7; 100]).unwrap();
+        let stream = encoder.finish().unwrap();
+        let (decompressed, num_frames) = decompress_frames(&stream);
+        assert_eq!(&decompressed[..6], b"aaabbc");
+        assert_eq!(&decompressed[6..], &[7; 100][..]);
+        assert_eq!(num_frames, 2);
+    }
+
+    #[test]
+    fn test_encoder_drop() {
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_34k.txt");
+        let mut stream = vec![];
+        {
+            let mut encoder = FseEncoder::with_block_size(&mut stream, 10_000);
+            encoder.write_all(TEST_DATA).unwrap();
+        }
+        let (decompressed, num_frames) = decompress_frames(&stream);
+        assert_eq!(decompressed, TEST_DATA);
+        assert_eq!(num_frames, TEST_DATA.len().div_ceil(10_000));
+
+        // finish leaves nothing to write on drop
+        let mut stream = vec![];
+        let mut encoder = FseEncoder::new(&mut stream);
+        encoder.write_all(b"aaabbc").unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(decompress_frames(&stream), (b"aaabbc".to_vec(), 1));
+    }
+
+    fn inverse(test_data: &[u8], block_size: usize) -> Vec<u8> {
+        let mut encoder = FseEncoder::with_block_size(vec![], block_size);
+        encoder.write_all(test_data).unwrap();
+        let stream = encoder.finish().unwrap();
+
+        let mut decompressed = vec![];
+        FseDecoder::with_block_size(&stream[..], block_size)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, test_data);
+        stream
+    }
+
+    #[test]
+    fn test_encoder_levels() {
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_66k_JSON.txt");
+        for level in 1..=MAX_COMPRESSION_LEVEL {
+            let mut encoder = FseEncoder::with_options(vec![], CompressOptions::from_level(level));
+            encoder.write_all(TEST_DATA).unwrap();
+            let stream = encoder.finish().unwrap();
+
+            let mut decompressed = vec![];
+            FseDecoder::with_options(&stream[..], CompressOptions::from_level(level))
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, TEST_DATA);
+        }
+    }
+
+    #[test]
+    fn test_decoder_roundtrip() {
+        inverse(
+            include_bytes!("../test_data/compression_66k_JSON.txt"),
+            1000,
+        );
+        inverse(
+            include_bytes!("../test_data/compression_65k.txt"),
+            64 * 1024,
+        );
+        inverse(
+            include_bytes!("../test_data/v4_uuids_93k.txt"),
+            STREAM_BLOCK_SIZE,
+        );
+        inverse(include_bytes!("../test_data/logo.jpg"), 4096);
+        inverse(&[7; 10_000], 1000);
+        inverse(&[], 1000);
+    }
+
+    #[test]
+    fn test_decoder_small_reads() {
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_34k.txt");
+        let stream = inverse(TEST_DATA, 1000);
+
+        let mut decoder = FseDecoder::new(&stream[..]);
+        let mut decompressed = vec![];
+        let mut buf = [0; 13];
+        loop {
+            let num_bytes = decoder.read(&mut buf).unwrap();
+            if num_bytes == 0 {
+                break;
+            }
+            decompressed.extend_from_slice(&buf[..num_bytes]);
+        }
+        assert_eq!(decompressed, TEST_DATA);
+
+        let decoder = FseDecoder::new(&stream[..]);
+        let num_lines = decoder.lines().count();
+        assert_eq!(num_lines, TEST_DATA.split(|b| *b == b'\n').count());
+    }
+
+    #[test]
+    fn test_decoder_truncated() {
+        let stream = inverse(include_bytes!("../test_data/compression_1k.txt"), 400);
+        for len in 1..stream.len() {
+            let mut decompressed = vec![];
+            let res = FseDecoder::new(&stream[..len]).read_to_end(&mut decompressed);
+            // truncation between two frames can't be detected
+            if res.is_ok() {
+                let (_, num_frames) = decompress_frames(&stream[..len]);
+                assert!(num_frames > 0);
+            }
+        }
+
+        let mut corrupted = stream.clone();
+        corrupted[STREAM_FRAME_LEN_SIZE] = 9;
+        let err = FseDecoder::new(&corrupted[..])
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_decoder_max_block_size() {
+        const TEST_DATA: &[u8] = include_bytes!("../test_data/compression_66k_JSON.txt");
+        let stream = inverse(TEST_DATA, 16 * 1024);
+        let mut decompressed = vec![];
+        FseDecoder::with_block_size(&stream[..], 16 * 1024)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, TEST_DATA);
+        let err = FseDecoder::with_block_size(&stream[..], 16 * 1024 - 1)
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // rle frame claiming 1 GiB
+        let mut stream = 6_u32.to_le_bytes().to_vec();
+        stream.extend_from_slice(&[BlockType::Rle as u8, 0, 0, 0, 0x40, 7]);
+        let err = FseDecoder::new(&stream[..])
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // frame length which no block of the max block size produces
+        let stream = u32::MAX.to_le_bytes();
+        let err = FseDecoder::new(&stream[..])
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,14 +1,19 @@
 use crate::*;
 use bitstream::highbit_pos;
-use common::NormCountsTable;
+use common::HistError;
+use common::Symbol;
 use log::log_enabled;
 use log::Level::{Debug, Trace};
 use log::*;
 
-#[derive(Debug, Clone)]
+/// Table to encode symbols with, see `fse_compress`.
+///
+/// Only built by `build_compression_table` or loaded by `CompressionTable::from_bytes`, so the
+/// states of the encoder stay in the table without bounds checks.
+#[derive(Debug, Clone, Default)]
 pub struct CompressionTable {
-    pub state_table: Vec<u16>,
-    pub symbol_tt: Vec<FseSymbolCompressionTransform>,
+    pub(crate) state_table: Vec<u16>,
+    pub(crate) symbol_tt: Vec<FseSymbolCompressionTransform>,
 }
 
 #[derive(Debug, Clone, Copy, Default)]
@@ -18,6 +23,22 @@ pub struct FseSymbolCompressionTransform {
 }
 
 impl FseSymbolCompressionTransform {
+    /// The `delta_nb_bits` of a symbol with `norm_count`, which doesn't depend on the other
+    /// symbols.
+    ///
+    /// A symbol with a zero count gets a fake cost of tableLog+1 bits, see `fse_bit_cost`.
+    pub fn delta_nb_bits(norm_count: i16, table_log: u32) -> u32 {
+        match norm_count {
+            0 => ((table_log + 1) << 16) - (1 << table_log),
+            -1 | 1 => (table_log << 16) - (1 << table_log),
+            _ => {
+                let max_bits_out: u32 = table_log - highbit_pos(norm_count as u32 - 1);
+                let min_state_plus: u32 = (norm_count as u32) << max_bits_out;
+                (max_bits_out << 16) - min_state_plus
+            }
+        }
+    }
+
     /// Approximate maximum cost of a symbol, in bits.
     ///
     /// Fractional get rounded up (i.e : a symbol with a normalized frequency of 3 gives the same result as a frequency of 2)
@@ -49,6 +70,49 @@ impl FseSymbolCompressionTransform {
     }
 }
 
+/// Fractional bits of the costs of `fse_bit_cost` used for the estimations
+pub const FSE_BIT_COST_ACCURACY_LOG: u32 = 8;
+
+/// Approximate size in bits of the bitstream of symbols with `counts` encoded with `table`,
+/// including the final states and the end mark, based on `fse_bit_cost`.
+///
+/// Every symbol with a count needs a non-zero normalized count in the table.
+pub fn estimate_stream_bits(table: &CompressionTable, counts: &[u32], table_log: u32) -> usize {
+    sum_stream_bits(counts, table.symbol_tt.iter().copied(), table_log)
+}
+
+/// Like `estimate_stream_bits`, but computes the costs from the normalized counts, without
+/// building a `CompressionTable`.
+pub fn estimate_stream_bits_from_norm_counts(
+    norm_counts: &[i16],
+    counts: &[u32],
+    table_log: u32,
+) -> usize {
+    let symbol_tts = norm_counts
+        .iter()
+        .map(|norm_count| FseSymbolCompressionTransform {
+            delta_find_state: 0,
+            delta_nb_bits: FseSymbolCompressionTransform::delta_nb_bits(*norm_count, table_log),
+        });
+    sum_stream_bits(counts, symbol_tts, table_log)
+}
+
+fn sum_stream_bits(
+    counts: &[u32],
+    symbol_tts: impl Iterator<Item = FseSymbolCompressionTransform>,
+    table_log: u32,
+) -> usize {
+    let symbol_bits: u64 = counts
+        .iter()
+        .zip(symbol_tts)
+        .filter(|(count, _)| **count != 0)
+        .map(|(count, symbol_tt)| {
+            *count as u64 * symbol_tt.fse_bit_cost(table_log, FSE_BIT_COST_ACCURACY_LOG) as u64
+        })
+        .sum();
+    (symbol_bits >> FSE_BIT_COST_ACCURACY_LOG) as usize + 2 * table_log as usize + 1
+}
+
 #[test]
 fn test_table_log_limit() {
     // Max value of min bits required imposed by FSE_MAX_SYMBOL_VALUE (too many min max :)
@@ -59,6 +123,19 @@ fn test_table_log_limit() {
     assert!(min_bits_symbols < FSE_MAX_TABLELOG);
 }
 
+#[test]
+#[should_panic(expected = "invalid normalized counts")]
+fn test_build_compression_table_invalid_counts() {
+    // sums up to 48 instead of 1 << 5
+    build_compression_table(&[16, 16, 16], 5, 2);
+}
+
+#[test]
+#[should_panic(expected = "invalid normalized counts")]
+fn test_build_decompression_table_invalid_counts() {
+    build_decompression_table(&[16, 16, 16], 5, 2);
+}
+
 /// Creating an ANSTable consists of following steps
 ///
 /// 1. count symbol occurrence from input[] into table count[]
@@ -67,19 +144,53 @@ fn test_table_log_limit() {
 ///
 /// build_table is step 3
 ///
+/// The table is not specific to the symbol type, `norm_counts` may have more than 256 entries.
+/// Panics on invalid normalized counts, see `check_norm_counts`.
 pub fn build_compression_table(
-    norm_counts: &NormCountsTable,
+    norm_counts: &[i16],
     table_log: u32,
-    mut max_symbol_value: u32,
+    max_symbol_value: u32,
 ) -> CompressionTable {
+    let mut table = CompressionTable::default();
+    build_compression_table_into(
+        &mut table,
+        &mut CompressionWorkspace::default(),
+        norm_counts,
+        table_log,
+        max_symbol_value,
+    );
+    table
+}
+
+/// Temporary tables of `build_compression_table_into`, which can be reused between calls
+#[derive(Debug, Clone, Default)]
+pub struct CompressionWorkspace {
+    cumul: Vec<u32>,
+    table_symbol: Vec<u16>,
+}
+
+/// Like `build_compression_table`, but reuses the allocations of `table` and `workspace`.
+pub fn build_compression_table_into(
+    table: &mut CompressionTable,
+    workspace: &mut CompressionWorkspace,
+    norm_counts: &[i16],
+    table_log: u32,
+    mut max_symbol_value: u32,
+) {
     let table_size = 1 << table_log;
     debug!("table_size {:?}", table_size);
     let table_mask = table_size - 1;
     let mut high_threshold = table_size - 1;
-    max_symbol_value = max_symbol_value.min(FSE_MAX_SYMBOL_VALUE);
-    let mut cumul = vec![0_u32; max_symbol_value as usize + 2];
-
-    // tmp table - TODO Could be externally allocated and reused
+    max_symbol_value = max_symbol_value.min(norm_counts.len() as u32 - 1);
+    // the encoder relies on a valid table, see `CompressionTable`. The table is not specific to
+    // the symbol type, the encoder checks the table_log against the symbols.
+    check_norm_counts_generic::<u16>(norm_counts, table_log, max_symbol_value)
+        .expect("invalid normalized counts");
+    let cumul = &mut workspace.cumul;
+    cumul.clear();
+    cumul.resize(max_symbol_value as usize + 2, 0);
+
+    // tmp table
     // This is the classical table symbol table, where the state equals its position in the table
     // In the classical approach, they are illustrated like this
     // State    A    B    C
@@ -87,7 +198,9 @@ pub fn build_compression_table(
     // 2        4    6    10
     // 3        7    8    15
     //
-    let mut table_symbol = vec![0_u8; table_size];
+    let table_symbol = &mut workspace.table_symbol;
+    table_symbol.clear();
+    table_symbol.resize(table_size, 0);
 
     // out table
     // get_ans_table_size will return usually a smaller value that table_size
@@ -100,8 +213,8 @@ pub fn build_compression_table(
         if norm_counts[u - 1] == -1 {
             // Low proba symbol
             cumul[u] = cumul[u - 1] + 1;
-            table_symbol[high_threshold] = (u - 1) as u8;
-            high_threshold -= 1;
+            table_symbol[high_threshold] = (u - 1) as u16;
+            high_threshold = high_threshold.wrapping_sub(1); // wraps if all symbols are low proba
         } else {
             cumul[u] = cumul[u - 1] + norm_counts[u - 1] as u32;
         }
@@ -119,7 +232,7 @@ pub fn build_compression_table(
         for symbol in 0..=max_symbol_value {
             let freq = norm_counts[symbol as usize];
             for _ in 0..freq {
-                table_symbol[position] = symbol as u8;
+                table_symbol[position] = symbol as u16;
                 position = (position + step) & table_mask;
                 while position > high_threshold {
                     position = (position + step) & table_mask; // Low proba area
@@ -128,19 +241,20 @@ pub fn build_compression_table(
         }
 
         if log_enabled!(Trace) {
-            for position in 0..table_size {
-                trace!("table_symbol[{:?}] {:?}", position, table_symbol[position]);
+            for (position, symbol) in table_symbol.iter().enumerate() {
+                trace!("table_symbol[{:?}] {:?}", position, symbol);
             }
         }
 
         assert!(position == 0);
     }
 
-    let mut state_table = vec![0_u16; cumul[max_symbol_value as usize + 1] as usize];
+    let state_table = &mut table.state_table;
+    state_table.clear();
+    state_table.resize(cumul[max_symbol_value as usize + 1] as usize, 0);
     // Build Table
     {
-        for u in 0..table_size {
-            let s = table_symbol[u];
+        for (u, &s) in table_symbol.iter().enumerate() {
             state_table[cumul[s as usize] as usize] = table_size as u16 + u as u16; // state_table : sorted by symbol order; gives next state value
             cumul[s as usize] += 1;
         }
@@ -154,8 +268,12 @@ pub fn build_compression_table(
     }
 
     // The symbol transformation table will help encoding input streams
-    let mut symbol_tt =
-        vec![FseSymbolCompressionTransform::default(); max_symbol_value as usize + 1];
+    let symbol_tt = &mut table.symbol_tt;
+    symbol_tt.clear();
+    symbol_tt.resize(
+        max_symbol_value as usize + 1,
+        FseSymbolCompressionTransform::default(),
+    );
 
     // Build Symbol Transformation Table
     {
@@ -164,20 +282,20 @@ pub fn build_compression_table(
             let norm_count = norm_counts[symbol];
             match norm_count {
                 0 => {
-                    if log_enabled!(Debug) {
-                        // For compatibility with fse_get_max_nb_bits()
-                        symbol_tt[symbol].delta_nb_bits = ((table_log + 1) << 16) - (1 << table_log)
-                    }
+                    // For compatibility with fse_get_max_nb_bits(), also moves a symbol which
+                    // is not in the input to the first state instead of out of the state table
+                    symbol_tt[symbol].delta_nb_bits =
+                        FseSymbolCompressionTransform::delta_nb_bits(0, table_log);
                 }
                 -1 | 1 => {
-                    symbol_tt[symbol].delta_nb_bits = (table_log << 16) - (1 << table_log);
+                    symbol_tt[symbol].delta_nb_bits =
+                        FseSymbolCompressionTransform::delta_nb_bits(norm_count, table_log);
                     symbol_tt[symbol].delta_find_state = total - 1;
                     total += 1;
                 }
                 _ => {
-                    let max_bits_out: u32 = table_log - highbit_pos(norm_counts[symbol] as u32 - 1);
-                    let min_state_plus: u32 = (norm_counts[symbol] as u32) << max_bits_out;
-                    symbol_tt[symbol].delta_nb_bits = (max_bits_out << 16) - min_state_plus;
+                    symbol_tt[symbol].delta_nb_bits =
+                        FseSymbolCompressionTransform::delta_nb_bits(norm_count, table_log);
                     symbol_tt[symbol].delta_find_state = total - norm_counts[symbol] as i32;
                     total += norm_counts[symbol] as i32;
                     // trace!(
@@ -209,34 +327,110 @@ pub fn build_compression_table(
             );
         }
     }
-
-    CompressionTable {
-        state_table,
-        symbol_tt,
-    }
 }
 
-#[derive(Debug)]
-pub struct DecompressionTable {
-    pub table: Vec<FseDecode>,
-    pub fast: bool,
+/// Table to decode symbols with, see `fse_decompress`.
+///
+/// Only built by `build_decompression_table` or loaded by `DecompressionTable::from_bytes`, so
+/// the states of the decoder stay in the table without bounds checks.
+#[derive(Debug, Default)]
+pub struct DecompressionTable<S = u8> {
+    pub(crate) table: Vec<FseDecode<S>>,
+    /// No symbol has a probability above 50%, which allows to read the bits without checks
+    pub(crate) fast: bool,
 }
 
 // pub type DecompressionTable = Vec<FseDecode>;
 
+/// Validates normalized counts, which may come from untrusted input, before building a table from them.
+///
+/// The sum of the counts has to match 1 << table_log.
+pub fn check_norm_counts(
+    norm_counts: &[i16],
+    table_log: u32,
+    max_symbol_value: u32,
+) -> Result<(), HistError> {
+    check_norm_counts_generic::<u8>(norm_counts, table_log, max_symbol_value)
+}
+
+/// Like `check_norm_counts`, for a table of symbols of type `S`, which allows table logs up to
+/// `S::MAX_TABLE_LOG`.
+pub fn check_norm_counts_generic<S: Symbol>(
+    norm_counts: &[i16],
+    table_log: u32,
+    max_symbol_value: u32,
+) -> Result<(), HistError> {
+    if table_log > S::MAX_TABLE_LOG {
+        return Err(HistError::TableLogTooLarge);
+    }
+    if table_log < FSE_MIN_TABLELOG {
+        return Err(HistError::TableLogTooSmall);
+    }
+    if max_symbol_value as usize >= norm_counts.len() {
+        return Err(HistError::MaxSymbolValueTooLarge);
+    }
+    let mut total = 0_i32;
+    for &norm_count in &norm_counts[..=max_symbol_value as usize] {
+        if norm_count < -1 {
+            return Err(HistError::IncorrectNormalizedDistribution);
+        }
+        total += norm_count.abs() as i32;
+    }
+    if total != 1 << table_log {
+        return Err(HistError::IncorrectNormalizedDistribution);
+    }
+    Ok(())
+}
+
 /// Build decoding table from normalized counters
+///
+/// Panics on invalid normalized counts, see `check_norm_counts`.
 pub fn build_decompression_table(
-    norm_counts: &NormCountsTable,
+    norm_counts: &[i16],
     table_log: u32, // can be u8
     max_symbol_value: u32,
 ) -> DecompressionTable {
-    let mut next_symbol_table = vec![0_u16; max_symbol_value as usize + 1];
+    build_decompression_table_generic(norm_counts, table_log, max_symbol_value)
+}
+
+/// Build decoding table from normalized counters, for symbols of type `S`
+pub fn build_decompression_table_generic<S: Symbol>(
+    norm_counts: &[i16],
+    table_log: u32, // can be u8
+    max_symbol_value: u32,
+) -> DecompressionTable<S> {
+    let mut table = DecompressionTable::default();
+    build_decompression_table_into(
+        &mut table,
+        &mut vec![],
+        norm_counts,
+        table_log,
+        max_symbol_value,
+    );
+    table
+}
+
+/// Like `build_decompression_table_generic`, but reuses the allocations of `table` and the
+/// temporary `next_symbol_table`.
+pub fn build_decompression_table_into<S: Symbol>(
+    table: &mut DecompressionTable<S>,
+    next_symbol_table: &mut Vec<u16>,
+    norm_counts: &[i16],
+    table_log: u32, // can be u8
+    max_symbol_value: u32,
+) {
+    // the decoder relies on a valid table, see `DecompressionTable`
+    check_norm_counts_generic::<S>(norm_counts, table_log, max_symbol_value)
+        .expect("invalid normalized counts");
+    next_symbol_table.clear();
+    next_symbol_table.resize(max_symbol_value as usize + 1, 0);
     let table_size = 1 << table_log;
     let mut high_threshold = table_size - 1;
-    let mut table_decode = vec![FseDecode::default(); table_size];
+    let table_decode = &mut table.table;
+    table_decode.clear();
+    table_decode.resize(table_size, FseDecode::default());
 
-    assert!(max_symbol_value <= FSE_MAX_SYMBOL_VALUE);
-    assert!(table_log <= FSE_MAX_TABLELOG);
+    assert!(max_symbol_value <= S::MAX_SYMBOL_VALUE);
 
     // build next_symbol_table
     let large_limit: i16 = (1 << (table_log - 1)) as i16;
@@ -244,8 +438,8 @@ pub fn build_decompression_table(
     for symbol in 0..=max_symbol_value as usize {
         let norm_count = norm_counts[symbol];
         if norm_count == -1 {
-            table_decode[high_threshold].symbol = symbol as u8;
-            high_threshold -= 1;
+            table_decode[high_threshold].symbol = S::from_usize(symbol);
+            high_threshold = high_threshold.wrapping_sub(1); // wraps if all symbols are low proba
             next_symbol_table[symbol] = 1;
         } else {
             if norm_count > large_limit {
@@ -263,7 +457,7 @@ pub fn build_decompression_table(
         for symbol in 0..=max_symbol_value {
             let freq = norm_counts[symbol as usize];
             for _ in 0..freq {
-                table_decode[position].symbol = symbol as u8;
+                table_decode[position].symbol = S::from_usize(symbol as usize);
                 position = (position + step) & table_mask;
                 while position > high_threshold {
                     position = (position + step) & table_mask; // Low proba area
@@ -272,21 +466,16 @@ pub fn build_decompression_table(
         }
 
         if log_enabled!(Trace) {
-            for position in 0..table_size {
-                trace!(
-                    "table_decode[{:?}] {:?}",
-                    position,
-                    table_decode[position].symbol
-                );
+            for (position, decode_state) in table_decode.iter().enumerate() {
+                trace!("table_decode[{:?}] {:?}", position, decode_state.symbol);
             }
         }
 
         assert!(position == 0);
     }
 
-    for u in 0..table_size {
-        let decode_state = &mut table_decode[u];
-        let symbol = decode_state.symbol as usize;
+    for decode_state in table_decode.iter_mut() {
+        let symbol = decode_state.symbol.to_usize();
         let next_state = next_symbol_table[symbol];
 
         // state used, increment state
@@ -295,15 +484,12 @@ pub fn build_decompression_table(
         decode_state.new_state = (next_state << decode_state.nb_bits) - table_size as u16;
     }
 
-    DecompressionTable {
-        table: table_decode,
-        fast: fast_mode,
-    }
+    table.fast = fast_mode;
 }
 
 #[derive(Debug, Clone, Copy, Default)]
-pub struct FseDecode {
+pub struct FseDecode<S = u8> {
     pub new_state: u16,
-    pub symbol: u8,
+    pub symbol: S,
     pub nb_bits: u8,
 }